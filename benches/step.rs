@@ -0,0 +1,170 @@
+//! Compares the chunked `SandWorld::step`, on one thread and on all of them, against the update it
+//! replaced: a single pass over the whole grid, kept below as it was before chunking. That update
+//! only moves particles, while `step` also tracks velocities and heat, so the comparison favors it.
+//! Run with `cargo bench --bench step`.
+
+use std::{cmp::Ordering, sync::Arc, time::{Duration, Instant}};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sand::{SandWorld, Particle, MaterialRegistry};
+
+const SIZE : usize = 512;
+const TICKS : usize = 100;
+
+const NEIGHBORS : [(i64, i64); 8] = [(-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0)];
+
+#[derive(Copy, Clone)]
+enum Action {
+    Swap,
+    React(Particle, Particle)
+}
+
+#[derive(Copy, Clone)]
+struct Move {
+    src : (usize, usize),
+    dst : (usize, usize),
+    action : Action
+}
+impl PartialEq for Move {
+    fn eq(&self, other : &Self) -> bool { self.dst == other.dst }
+}
+impl Eq for Move { }
+impl Ord for Move {
+    fn cmp(&self, other : &Self) -> Ordering { self.dst.cmp(&other.dst) }
+}
+impl PartialOrd for Move {
+    fn partial_cmp(&self, other : &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+// The grid of a world, updated the way `SandWorld::step` did before chunking
+struct Unchunked {
+    materials : Arc<MaterialRegistry>,
+    width : usize,
+    height : usize,
+    grid : Vec<Particle>,
+    moves : Vec<Move>,
+    touched : Vec<bool>
+}
+
+impl Unchunked {
+    fn new(world : &SandWorld) -> Unchunked {
+        let (width, height) = (world.width(), world.height());
+        Unchunked {
+            materials : world.materials().clone(), width, height, grid : world.cells().to_vec(),
+            moves : Vec::new(), touched : vec![false; width * height]
+        }
+    }
+
+    fn step<R : Rng>(&mut self, rng : &mut R) {
+        let (width, height) = (self.width, self.height);
+        let grid = |x : usize, y : usize| self.grid[y * width + x];
+        let materials = &*self.materials;
+        self.moves.clear();
+        for i in 0..width {
+            for j in 0..height {
+                let p = grid(i, j);
+                let m = &materials[p];
+                let neighbor = |(dx, dy) : (i64, i64)| {
+                    let (x, y) = (i as i64 + dx, j as i64 + dy);
+                    (x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height).then_some((x as usize, y as usize))
+                };
+                if let Some(decay) = m.decay.filter(|d| rng.gen::<f32>() < d.chance) {
+                    self.moves.push(Move { src : (i, j), dst : (i, j), action : Action::React(decay.into, decay.into) });
+                    continue;
+                }
+                if !m.reactions.is_empty() {
+                    let start = rng.gen_range(0..NEIGHBORS.len());
+                    let reaction = (0..NEIGHBORS.len()).filter_map(|k| neighbor(NEIGHBORS[(start + k) % NEIGHBORS.len()])).find_map(|(x, y)| {
+                        let q = grid(x, y);
+                        m.reactions.iter().find(|r| r.with == q && rng.gen::<f32>() < r.chance).map(|r| ((x, y), r))
+                    });
+                    if let Some((dst, r)) = reaction {
+                        self.moves.push(Move { src : (i, j), dst, action : Action::React(r.into, r.other_into) });
+                        continue;
+                    }
+                }
+                for group in &m.moves {
+                    let start = if group.len() > 1 { rng.gen_range(0..group.len()) } else { 0 };
+                    let dst = (0..group.len()).map(|k| group[(start + k) % group.len()]).find_map(|(dx, dy)| {
+                        neighbor((dx as i64, dy as i64)).filter(|&(x, y)| materials.can_displace(p, grid(x, y)))
+                    });
+                    if let Some(dst) = dst {
+                        self.moves.push(Move { src : (i, j), dst, action : Action::Swap });
+                        break;
+                    }
+                }
+            }
+        }
+        self.moves.sort_unstable();
+        self.touched.fill(false);
+        let mut dst_prev = 0;
+        self.moves.push(Move { src : (usize::MAX, usize::MAX), dst : (usize::MAX, usize::MAX), action : Action::Swap });
+        for i in 0..(self.moves.len() - 1) {
+            if self.moves[i + 1] != self.moves[i] {
+                let m = self.moves[rng.gen_range(dst_prev..(i + 1))];
+                dst_prev = i + 1;
+                let src = m.src.1 * width + m.src.0;
+                let dst = m.dst.1 * width + m.dst.0;
+                match m.action {
+                    Action::Swap => {
+                        if self.touched[src] || !materials.can_displace(self.grid[src], self.grid[dst]) { continue; }
+                        self.grid.swap(src, dst);
+                    }
+                    Action::React(into, other_into) => {
+                        if self.touched[src] || self.touched[dst] { continue; }
+                        self.grid[src] = into;
+                        self.grid[dst] = other_into;
+                    }
+                }
+                self.touched[src] = true;
+                self.touched[dst] = true;
+            }
+        }
+    }
+}
+
+// Fills a quarter of the cells with sand and another quarter with water
+fn random_world(seed : u64) -> SandWorld {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut world = SandWorld::new(SIZE, SIZE);
+    for x in 0..SIZE {
+        for y in 0..SIZE {
+            let p = match rng.gen_range(0..4) {
+                0 => Particle(1),
+                1 => Particle(2),
+                _ => Particle::AIR
+            };
+            world.set(x, y, p);
+        }
+    }
+    world
+}
+
+// A pile of sand at rest covering the bottom quarter, with a single grain falling
+fn settled_world() -> SandWorld {
+    let mut world = SandWorld::new(SIZE, SIZE);
+    for x in 0..SIZE {
+        for y in 0..SIZE / 4 { world.set(x, y, Particle(1)); }
+    }
+    world.set(SIZE / 2, SIZE - 1, Particle(1));
+    world
+}
+
+fn time<S>(mut state : S, step : fn(&mut S, &mut StdRng)) -> Duration {
+    let mut rng = StdRng::seed_from_u64(1);
+    let start = Instant::now();
+    for _ in 0..TICKS { step(&mut state, &mut rng); }
+    start.elapsed() / TICKS as u32
+}
+
+fn main() {
+    for (name, world) in [("random", random_world as fn(u64) -> SandWorld), ("settled", |_| settled_world())] {
+        let unchunked = time(Unchunked::new(&world(0)), |u, rng| u.step(rng));
+        let mut single = world(0);
+        single.set_threads(1);
+        let single = time(single, |w, rng| w.step(rng));
+        let parallel = time(world(0), |w, rng| w.step(rng));
+        let speedup = |d : Duration| unchunked.as_secs_f64() / d.as_secs_f64();
+        println!("{}x{} {:8} unchunked {:>10.2?}/tick   chunked, 1 thread {:>10.2?}/tick ({:.1}x)   chunked {:>10.2?}/tick ({:.1}x)",
+            SIZE, SIZE, name, unchunked, single, speedup(single), parallel, speedup(parallel));
+    }
+}
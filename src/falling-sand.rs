@@ -2,47 +2,514 @@ use glium::{
     glutin,
     Surface,
     glutin::event_loop::ControlFlow,
-    glutin::event::VirtualKeyCode,
+    glutin::event::{VirtualKeyCode, MouseButton, MouseScrollDelta},
 };
-use glam::{Mat4, Vec3, Quat};
-use std::cmp::Ordering;
+use glam::{Mat2, Mat4, Vec2, Vec3, Vec4};
+use imgui::{Context, Window, Ui, Slider, ColorButton, Condition};
+use imgui_glium_renderer::Renderer;
+use imgui_winit_support::{WinitPlatform, HiDpiMode};
 use rand::Rng;
+use std::{fs::File, io::{BufReader, BufWriter, Write}, path::{Path, PathBuf}, sync::Arc, time::Instant};
+use sand::{
+    SandWorld, Particle, MaterialRegistry, State, WorldFileError, Simulation, Replay, Command, Emitter, EmitterKind, Body, Brush,
+    Rule, Life, LangtonsAnt, History, StampLibrary, GravityWell, GRAVITY, MAX_SIZE,
+    render::{GridRenderer, Camera, Effects},
+    gpu::GpuStepper
+};
+
+// Maps a cursor position in physical pixels to normalized device coordinates
+fn cursor_to_ndc(cursor : (f64, f64), framebuffer : (u32, u32)) -> Vec2 {
+    Vec2::new(2.0 * (cursor.0 as f32 / framebuffer.0 as f32) - 1.0, 1.0 - 2.0 * (cursor.1 as f32 / framebuffer.1 as f32))
+}
+
+// Maps a cursor position in physical pixels back through the drawing transform to a grid cell
+fn cursor_to_cell(cursor : (f64, f64), framebuffer : (u32, u32), transform : Mat4) -> (i32, i32) {
+    let ndc = cursor_to_ndc(cursor, framebuffer);
+    let cell = transform.inverse() * Vec4::new(ndc.x, ndc.y, 0.0, 1.0);
+    (cell.x.round() as i32, cell.y.round() as i32)
+}
+
+// Viewport rectangle of the minimap inset in the top-right corner, a quarter of the window in
+// each direction, and the transform fitting a whole grid into it
+fn minimap(framebuffer : (u32, u32), width : f32, height : f32) -> (glium::Rect, Mat4) {
+    const MARGIN : u32 = 8;
+    let (w, h) = (framebuffer.0 / 4, framebuffer.1 / 4);
+    let rect = glium::Rect {
+        left : framebuffer.0.saturating_sub(w + MARGIN), bottom : framebuffer.1.saturating_sub(h + MARGIN), width : w, height : h
+    };
+    let ndc = |px : u32, size : u32| 2.0 * px as f32 / size as f32 - 1.0;
+    let (x0, x1) = (ndc(rect.left, framebuffer.0), ndc(rect.left + w, framebuffer.0));
+    let (y0, y1) = (ndc(rect.bottom, framebuffer.1), ndc(rect.bottom + h, framebuffer.1));
+    let transform = Mat4::from_translation(Vec3::new((x0 + x1) / 2.0, (y0 + y1) / 2.0, 0.0))
+        * Mat4::from_scale(Vec3::new((x1 - x0) / 2.0, (y1 - y0) / 2.0, 1.0))
+        * Camera { aspect : w.max(1) as f32 / h.max(1) as f32, ..Camera::fit(width, height) }.transform(width, height);
+    (rect, transform)
+}
+
+// Viewport rectangle covered by a grid drawn with `transform`, clipped to the framebuffer
+fn grid_rect(framebuffer : (u32, u32), transform : Mat4, width : f32, height : f32) -> glium::Rect {
+    let lo = transform.transform_point3(Vec3::ZERO).clamp(Vec3::splat(-1.0), Vec3::ONE);
+    let hi = transform.transform_point3(Vec3::new(width, height, 0.0)).clamp(Vec3::splat(-1.0), Vec3::ONE);
+    let px = |ndc : f32, size : u32| ((ndc + 1.0) / 2.0 * size as f32).round() as u32;
+    let (left, bottom) = (px(lo.x, framebuffer.0), px(lo.y, framebuffer.1));
+    glium::Rect { left, bottom, width : px(hi.x, framebuffer.0) - left, height : px(hi.y, framebuffer.1) - bottom }
+}
+
+// Inverse of `cursor_to_cell`, giving the center of a cell in physical pixels
+fn cell_to_cursor(cell : (f32, f32), framebuffer : (u32, u32), transform : Mat4) -> (f32, f32) {
+    let ndc = transform * Vec4::new(cell.0, cell.1, 0.0, 1.0);
+    ((ndc.x + 1.0) / 2.0 * framebuffer.0 as f32, (1.0 - ndc.y) / 2.0 * framebuffer.1 as f32)
+}
+
+// Lists every emitter with its settings; edits are returned as commands so they can be recorded
+fn show_emitters(opened : &mut bool, ui : &Ui, world : &SandWorld) -> Vec<Command> {
+    let mut commands = Vec::new();
+    if !*opened { return commands; }
+    let names : Vec<&str> = world.materials().iter().map(|(_, m)| m.name.as_str()).collect();
+    Window::new("Emitters").opened(opened).position([10.0, 420.0], Condition::FirstUseEver).build(ui, || {
+        for (i, emitter) in world.emitters().iter().enumerate() {
+            let _id = ui.push_id(i as i32);
+            let mut e = *emitter;
+            let mut kind = match e.kind { EmitterKind::Source => 0, EmitterKind::Drain => 1 };
+            let mut material = e.particle.index() as usize;
+            let mut changed = ui.combo_simple_string("Kind", &mut kind, &["Source", "Drain"]);
+            changed |= ui.combo_simple_string("Material", &mut material, &names);
+            changed |= ui.input_int("X", &mut e.x).build();
+            changed |= ui.input_int("Y", &mut e.y).build();
+            changed |= Slider::new("Rate", 0.0, 20.0).build(ui, &mut e.rate);
+            changed |= Slider::new("Spread", 0, world.width() as i32).build(ui, &mut e.spread);
+            if changed {
+                // Ctrl-clicking the slider allows typing in any value
+                e.spread = e.spread.clamp(0, MAX_SIZE as i32);
+                e.kind = if kind == 0 { EmitterKind::Source } else { EmitterKind::Drain };
+                e.particle = Particle(material as u8);
+                commands.push(Command::EditEmitter(i, e));
+            }
+            if ui.button("Remove") { commands.push(Command::RemoveEmitter(i)); }
+            ui.separator();
+        }
+        if ui.button("Add spawner") { commands.push(Command::AddEmitter(Emitter::spawner(world, Particle(1 % names.len() as u8)))); }
+        ui.text("Shift + left/right click places a source/drain");
+    });
+    commands
+}
+
+// Lists the stamps of `library` and saves the clipboard into it; returns a stamp to pick up
+fn show_stamps(opened : &mut bool, ui : &Ui, library : &mut StampLibrary, clipboard : Option<&SandWorld>, name : &mut String) -> Option<SandWorld> {
+    let mut picked = None;
+    if !*opened { return picked; }
+    Window::new("Stamps").opened(opened).position([400.0, 10.0], Condition::FirstUseEver).always_auto_resize(true).build(ui, || {
+        ui.input_text("Name", name).build();
+        ui.disabled(clipboard.is_none() || name.is_empty(), || if ui.button("Save clipboard") {
+            if let Some(stamp) = clipboard {
+                if let Err(e) = library.save(name, stamp) { eprintln!("Failed to save stamp {}: {}", name, e); }
+            }
+        });
+        ui.separator();
+        let mut removed = None;
+        for (i, (name, stamp)) in library.stamps().iter().enumerate() {
+            let _id = ui.push_id(i as i32);
+            ui.text(format!("{} ({}x{})", name, stamp.width(), stamp.height()));
+            ui.same_line();
+            if ui.button("Use") { picked = Some(stamp.clone()); }
+            ui.same_line();
+            if ui.button("Delete") { removed = Some(i); }
+        }
+        if let Some(i) = removed {
+            if let Err(e) = library.remove(i) { eprintln!("Failed to delete stamp: {}", e); }
+        }
+        ui.text(library.dir().display().to_string());
+    });
+    picked
+}
+
+// Bottom-left cell, width and height of the rectangle between two corner cells
+fn region(a : (i32, i32), b : (i32, i32)) -> (i32, i32, usize, usize) {
+    (a.0.min(b.0), a.1.min(b.1), a.0.abs_diff(b.0) as usize + 1, a.1.abs_diff(b.1) as usize + 1)
+}
+
+// Bottom-left cell of `stamp` when it's centered on `cell`
+fn stamp_origin(cell : (i32, i32), stamp : &SandWorld) -> (i32, i32) {
+    (cell.0 - stamp.width() as i32 / 2, cell.1 - stamp.height() as i32 / 2)
+}
+
+// Painting tool settings, changed in the tools panel and with keyboard shortcuts
+#[derive(Copy, Clone, PartialEq)]
+struct Tools {
+    material : Particle,
+    brush_radius : i32,
+    brush : Brush,
+    // Simulated seconds per real second
+    time_scale : f32,
+    // Settings of gravity wells placed with the mouse
+    well_radius : f32,
+    well_strength : f32
+}
+
+#[derive(Copy, Clone)]
+enum FileAction { Save, Load, Export }
+
+// Saves, reloads or exports the world next to `world_path`; a reloaded world is returned as a
+// command so it goes through the simulation
+fn file_action(action : FileAction, world : &SandWorld, world_path : &Path, materials : &Arc<MaterialRegistry>) -> Option<Command> {
+    match action {
+        FileAction::Save | FileAction::Export => {
+            let (path, done) = match action {
+                FileAction::Save => (world_path.with_extension("sand"), "Saved"),
+                _ => (world_path.with_extension("png"), "Exported")
+            };
+            match save_world(world, &path) {
+                Ok(()) => println!("{} {}", done, path.display()),
+                Err(e) => eprintln!("Failed to write {}: {}", path.display(), e)
+            }
+            None
+        }
+        FileAction::Load => match load_world(world_path, materials) {
+            Ok(w) => Some(Command::Load(Box::new(w))),
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", world_path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+// Material palette, brush, simulation controls and statistics, rendering effects, and file buttons.
+// Changes to the simulation are returned as commands so they can be recorded.
+fn show_tools(opened : &mut bool, ui : &Ui, simulation : &Simulation, tools : &mut Tools, effects : &mut Effects, tick_ms : f32, world_path : &Path) -> (Vec<Command>, Option<FileAction>) {
+    let (mut commands, mut action) = (Vec::new(), None);
+    if !*opened { return (commands, action); }
+    let world = simulation.world();
+    Window::new("Tools").opened(opened).position([10.0, 10.0], Condition::FirstUseEver).always_auto_resize(true).build(ui, || {
+        ui.text("Material");
+        for (n, (p, m)) in world.materials().iter().enumerate().skip(1) {
+            let _id = ui.push_id(n as i32);
+            if (n - 1) % 6 != 0 { ui.same_line(); }
+            let [r, g, b] = m.color;
+            if ColorButton::new(&m.name, [r, g, b, 1.0]).size([24.0, 24.0]).build(ui) { tools.material = p; }
+            if ui.is_item_hovered() { ui.tooltip_text(&m.name); }
+        }
+        ui.text(format!("Selected: {}", world.materials()[tools.material].name));
+        ui.separator();
+
+        Slider::new("Brush size", 0, 20).build(ui, &mut tools.brush_radius);
+        let mut shape = tools.brush as usize;
+        if ui.combo_simple_string("Brush shape", &mut shape, &["Circle", "Square"]) {
+            tools.brush = if shape == 0 { Brush::Circle } else { Brush::Square };
+        }
+        ui.separator();
+
+        let paused = simulation.paused();
+        if ui.button(if paused { "Resume" } else { "Pause" }) { commands.push(Command::Pause(!paused)); }
+        ui.same_line();
+        ui.disabled(!paused, || if ui.button("Step") { commands.push(Command::Step); });
+        Slider::new("Speed", 1.0 / 8.0, 8.0).build(ui, &mut tools.time_scale);
+        let mut gravity = world.gravity();
+        let mut changed = Slider::new("Gravity X", -1.0, 1.0).build(ui, &mut gravity.x);
+        changed |= Slider::new("Gravity Y", -1.0, 1.0).build(ui, &mut gravity.y);
+        if ui.button("Down") { (gravity, changed) = (Vec2::new(0.0, -GRAVITY), true); }
+        ui.same_line();
+        if ui.button("Zero-g") { (gravity, changed) = (Vec2::ZERO, true); }
+        if changed { commands.push(Command::SetGravity(gravity)); }
+        Slider::new("Well radius", 2.0, 64.0).build(ui, &mut tools.well_radius);
+        Slider::new("Well strength", 0.0, 1.0).build(ui, &mut tools.well_strength);
+        for (i, well) in world.wells().iter().enumerate() {
+            let _id = ui.push_id(i as i32);
+            ui.text(format!("Well at ({}, {}), radius {:.0}, strength {:.2}", well.x, well.y, well.radius, well.strength));
+            ui.same_line();
+            if ui.button("Remove") { commands.push(Command::RemoveWell(i)); }
+        }
+        ui.text(format!("{:.2} ms per tick, {} moves, {} awake chunks", tick_ms, world.last_moves(), world.awake_chunks()));
+        for (count, (_, m)) in world.counts().into_iter().zip(world.materials().iter()).skip(1) {
+            if count > 0 { ui.text(format!("{}: {}", m.name, count)); }
+        }
+        ui.separator();
+
+        ui.checkbox("Color jitter", &mut effects.jitter);
+        ui.same_line();
+        ui.checkbox("Shimmer", &mut effects.shimmer);
+        ui.checkbox("Glow", &mut effects.glow);
+        ui.same_line();
+        ui.checkbox("Lighting", &mut effects.lighting);
+        ui.text(format!("{:.2} ms per frame", 1000.0 / ui.io().framerate));
+        ui.separator();
 
-#[derive(Copy, Clone, PartialEq, Debug)]
-enum Particle {
-    AIR,
-    SAND,
-    WATER
+        if ui.button("Save") { action = Some(FileAction::Save); }
+        ui.same_line();
+        if ui.button("Load") { action = Some(FileAction::Load); }
+        ui.same_line();
+        if ui.button("Export PNG") { action = Some(FileAction::Export); }
+        ui.text(world_path.display().to_string());
+    });
+    (commands, action)
 }
 
-#[derive(Debug, Copy, Clone)]
-struct Move {
-    src : (usize, usize),
-    dst : (usize, usize)
+const USAGE : &str = "Usage: falling-sand [--size WIDTHxHEIGHT] [--load FILE.sand|FILE.png] [--materials FILE] [--seed N] [--record FILE | --replay FILE]
+                    [--rule life|ant] [--backend cells|margolus|gpu] [--gravity X,Y] [--stamps DIR] [--headless [--ticks N] [--snapshot-every N] [--out DIR]]";
+
+// How the world advances each tick: the particle rules of `SandWorld::step`, or the simpler
+// Margolus-neighborhood rules of `SandWorld::step_margolus`, computed on the CPU or the GPU
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Backend {
+    Cells,
+    Margolus,
+    Gpu
 }
-impl PartialEq for Move {
-    fn eq(&self, other: &Self) -> bool { self.dst.0 == other.dst.0 && self.dst.1 == other.dst.1 }
+
+// Command-line options
+struct Options {
+    width : usize,
+    height : usize,
+    load : Option<PathBuf>,
+    materials : Option<PathBuf>,
+    seed : Option<u64>,
+    record : Option<PathBuf>,
+    replay : Option<PathBuf>,
+    // Cellular-automaton preset, see `install_rule`
+    rule : Option<String>,
+    backend : Backend,
+    // Gravity of the starting world, in cells per tick squared
+    gravity : Option<Vec2>,
+    // Directory of the stamp library
+    stamps : PathBuf,
+    // Run without a window, see `run_headless`
+    headless : bool,
+    ticks : u64,
+    snapshot_every : u64,
+    out : PathBuf
+}
+
+fn parse_size(s : &str) -> Option<(usize, usize)> {
+    let (w, h) = s.split_once('x')?;
+    let (w, h) = (w.parse().ok()?, h.parse().ok()?);
+    if (1..=MAX_SIZE).contains(&w) && (1..=MAX_SIZE).contains(&h) { Some((w, h)) } else { None }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        width : 100, height : 100, load : None, materials : None, seed : None, record : None, replay : None, rule : None, backend : Backend::Cells, gravity : None,
+        stamps : PathBuf::from("stamps"),
+        headless : false, ticks : 1000, snapshot_every : 100, out : PathBuf::from(".")
+    };
+    let mut args = std::env::args().skip(1);
+    let mut headless_only = None;
+    while let Some(arg) = args.next() {
+        if matches!(arg.as_str(), "--ticks" | "--snapshot-every" | "--out") { headless_only = Some(arg.clone()); }
+        match arg.as_str() {
+            "--size" => {
+                let size = args.next().ok_or("--size requires a value like 200x150")?;
+                (options.width, options.height) = parse_size(&size).ok_or(format!("Invalid grid size '{}'", size))?;
+            }
+            "--load" => options.load = Some(args.next().ok_or("--load requires a file path")?.into()),
+            "--materials" => options.materials = Some(args.next().ok_or("--materials requires a file path")?.into()),
+            "--seed" => {
+                let seed = args.next().ok_or("--seed requires a number")?;
+                options.seed = Some(seed.parse().map_err(|_| format!("Invalid seed '{}'", seed))?);
+            }
+            "--record" => options.record = Some(args.next().ok_or("--record requires a file path")?.into()),
+            "--replay" => options.replay = Some(args.next().ok_or("--replay requires a file path")?.into()),
+            "--rule" => {
+                let rule = args.next().ok_or("--rule requires a name")?;
+                if !matches!(rule.as_str(), "life" | "ant") { return Err(format!("Unknown rule '{}', expected life or ant", rule)); }
+                options.rule = Some(rule);
+            }
+            "--backend" => options.backend = match args.next().ok_or("--backend requires a name")?.as_str() {
+                "cells" => Backend::Cells,
+                "margolus" => Backend::Margolus,
+                "gpu" => Backend::Gpu,
+                backend => return Err(format!("Unknown backend '{}', expected cells, margolus or gpu", backend))
+            },
+            "--gravity" => {
+                let gravity = args.next().ok_or("--gravity requires a value like 0,-0.25")?;
+                let parsed = gravity.split_once(',').and_then(|(x, y)| Some(Vec2::new(x.trim().parse().ok()?, y.trim().parse().ok()?)));
+                options.gravity = Some(parsed.filter(|g| g.is_finite()).ok_or(format!("Invalid gravity '{}'", gravity))?);
+            }
+            "--stamps" => options.stamps = args.next().ok_or("--stamps requires a directory")?.into(),
+            "--headless" => options.headless = true,
+            "--ticks" => {
+                let ticks = args.next().ok_or("--ticks requires a number")?;
+                options.ticks = ticks.parse().map_err(|_| format!("Invalid tick count '{}'", ticks))?;
+            }
+            "--snapshot-every" => {
+                let every = args.next().ok_or("--snapshot-every requires a number")?;
+                options.snapshot_every = every.parse().map_err(|_| format!("Invalid snapshot interval '{}'", every))?;
+            }
+            "--out" => options.out = args.next().ok_or("--out requires a directory")?.into(),
+            _ => return Err(format!("Unknown argument '{}'", arg))
+        }
+    }
+    if options.record.is_some() && options.replay.is_some() { return Err("--record and --replay can't be combined".into()); }
+    if let (false, Some(arg)) = (options.headless, headless_only) { return Err(format!("{} requires --headless", arg)); }
+    if options.headless && options.backend == Backend::Gpu { return Err("--backend gpu needs a window, use margolus with --headless".into()); }
+    Ok(options)
 }
-impl Eq for Move { }
-impl Ord for Move {
-    fn cmp(&self, other: &Self) -> Ordering {
-        if self.dst.0 == other.dst.0 { self.dst.1.cmp(&other.dst.1) } 
-        else { self.dst.0.cmp(&other.dst.0) }
+
+// Sets the rules of a --rule preset on `world`, finding the particles they act on by material name
+// (see assets/rules.txt)
+fn install_rule(name : &str, world : &mut SandWorld) -> Result<(), String> {
+    let materials = world.materials().clone();
+    match name {
+        "life" => {
+            let alive = materials.find("life").ok_or("--rule life needs a material named 'life'")?;
+            let life : Arc<dyn Rule> = Arc::new(Life { alive });
+            world.set_rule(alive, life.clone());
+            world.set_rule(Particle::AIR, life);
+        }
+        _ => {
+            let ant = LangtonsAnt::from_materials(&materials).ok_or("--rule ant needs the materials 'black' and 'ant-*'")?;
+            let particles = ant.ants.concat();
+            let ant : Arc<dyn Rule> = Arc::new(ant);
+            for p in particles { world.set_rule(p, ant.clone()); }
+        }
     }
+    Ok(())
 }
-impl PartialOrd for Move {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+
+// Loads a `.sand` world, or imports any other file as a PNG image
+fn load_world(path : &Path, materials : &Arc<MaterialRegistry>) -> Result<SandWorld, WorldFileError> {
+    let mut file = BufReader::new(File::open(path)?);
+    if path.extension() == Some("png".as_ref()) { SandWorld::import_png_with_materials(file, materials.clone()) }
+    else { SandWorld::load_with_materials(&mut file, materials.clone()) }
+}
+
+fn save_world(world : &SandWorld, path : &Path) -> Result<(), WorldFileError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if path.extension() == Some("png".as_ref()) { world.export_png(&mut file)?; }
+    else { world.save(&mut file)?; }
+    // Dropping the writer would ignore errors from the last write
+    file.flush()?;
+    Ok(())
+}
+
+// Writes the recording of `simulation`, if any, to the --record path
+fn save_recording(options : &Options, simulation : &Simulation) {
+    if let (Some(path), Some(replay)) = (&options.record, simulation.replay()) {
+        let saved = File::create(path).map_err(WorldFileError::from)
+            .and_then(|file| {
+                let mut w = BufWriter::new(file);
+                replay.save(&mut w)?;
+                Ok(w.flush()?)
+            });
+        match saved {
+            Ok(()) => println!("Recorded {} ticks to {}", replay.ticks.len(), path.display()),
+            Err(e) => eprintln!("Failed to save replay {}: {}", path.display(), e)
+        }
     }
 }
 
-#[derive(Copy, Clone)]
-struct Vertex {
-    position : [f32; 2]
+// Advances the simulation --ticks times without opening a window, applying the commands of a
+// replay while it lasts. Every --snapshot-every ticks (and at the start) the world is exported as
+// `frame-TICK.png` in the --out directory, and `stats.csv` there gets a row per tick with the
+// number of moves and the cell count of every material.
+fn run_headless(options : &Options, mut simulation : Simulation, replay : Option<&Replay>) -> Result<(), WorldFileError> {
+    std::fs::create_dir_all(&options.out)?;
+    let mut stats = BufWriter::new(File::create(options.out.join("stats.csv"))?);
+    let names : Vec<&str> = simulation.world().materials().iter().map(|(_, m)| m.name.as_str()).collect();
+    writeln!(stats, "tick,moves,{}", names.join(","))?;
+    let start = Instant::now();
+    for tick in 0..=options.ticks {
+        if tick > 0 {
+            if let Some(commands) = replay.and_then(|r| r.ticks.get(tick as usize - 1)) {
+                for command in commands { simulation.apply(command.clone()); }
+            }
+            simulation.tick();
+        }
+        let world = simulation.world();
+        let counts : Vec<String> = world.counts().iter().map(|c| c.to_string()).collect();
+        writeln!(stats, "{},{},{}", tick, world.last_moves(), counts.join(","))?;
+        if options.snapshot_every > 0 && tick % options.snapshot_every == 0 {
+            save_world(world, &options.out.join(format!("frame-{:06}.png", tick)))?;
+        }
+    }
+    stats.flush()?;
+    println!("Ran {} ticks in {:.2}s, output in {}", options.ticks, start.elapsed().as_secs_f32(), options.out.display());
+    save_recording(options, &simulation);
+    Ok(())
+}
+
+fn update_title(display : &glium::Display, simulation : &Simulation, tools : &Tools) {
+    let name = &simulation.world().materials()[tools.material].name;
+    let speed = if simulation.paused() { " [paused]".to_string() } else if tools.time_scale != 1.0 { format!(" [x{}]", tools.time_scale) } else { String::new() };
+    display.gl_window().window().set_title(&format!("Falling Sand - {} (brush {}){}", name, tools.brush_radius, speed));
 }
-glium::implement_vertex!(Vertex, position);
 
 fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(2);
+    });
+
+    // Worlds are saved (Ctrl+S), reloaded (Ctrl+O) and exported as PNG (Ctrl+E) next to this path
+    let materials = match &options.materials {
+        Some(path) => {
+            let src = std::fs::read_to_string(path).and_then(|src| MaterialRegistry::parse(&src)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)));
+            Arc::new(src.unwrap_or_else(|e| {
+                eprintln!("Failed to load materials from {}: {}", path.display(), e);
+                std::process::exit(1);
+            }))
+        }
+        None => Arc::new(MaterialRegistry::default())
+    };
+    let world_path = options.load.clone().unwrap_or_else(|| PathBuf::from("world.sand"));
+    let spawn = materials.find("sand").unwrap_or(Particle(1 % materials.len() as u8));
+    let mut world = match &options.load {
+        Some(path) => load_world(path, &materials).unwrap_or_else(|e| {
+            eprintln!("Failed to load {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => {
+            // New worlds start with a spawner dropping sand along the top row, unless they run rules
+            let mut world = SandWorld::with_materials(options.width, options.height, materials.clone());
+            if options.rule.is_none() {
+                let spawner = Emitter::spawner(&world, spawn);
+                world.emitters_mut().push(spawner);
+            }
+            world
+        }
+    };
+    let install = |world : &mut SandWorld| {
+        if let Err(e) = options.rule.as_ref().map_or(Ok(()), |rule| install_rule(rule, world)) {
+            eprintln!("{} (see assets/rules.txt)", e);
+            std::process::exit(1);
+        }
+    };
+    install(&mut world);
+    if let Some(gravity) = options.gravity { world.set_gravity(gravity); }
+
+    // All changes to the world go through `simulation`, so a run can be recorded (--record) and
+    // played back exactly (--replay); user input takes over once a replay has finished
+    let replay = options.replay.as_ref().map(|path| {
+        File::open(path).map_err(WorldFileError::from)
+            .and_then(|file| Replay::load_with_materials(&mut BufReader::new(file), materials.clone()))
+            .map(|mut replay| {
+                install(&mut replay.world);
+                replay
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load replay {}: {}", path.display(), e);
+                std::process::exit(1);
+            })
+    });
+    let mut simulation = match &replay {
+        Some(replay) => replay.start(),
+        None => {
+            let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen());
+            println!("Seed {}", seed);
+            if options.record.is_some() { Simulation::recorded(world, seed) } else { Simulation::new(world, seed) }
+        }
+    };
+    let mut replay_tick = 0;
+    if options.backend == Backend::Margolus { simulation.set_stepper(|world, rng| world.step_margolus(rng)); }
+
+    if options.headless {
+        if let Err(e) = run_headless(&options, simulation, replay.as_ref()) {
+            eprintln!("Headless run failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let event_loop = glutin::event_loop::EventLoop::new();
     let window_builder = glutin::window::WindowBuilder::new()
         .with_title("Falling Sand")
@@ -51,154 +518,443 @@ fn main() {
     let display = glium::Display::new(window_builder, context_builder, &event_loop)
         .expect("Error creating display");
 
-    let vertex_buffer = glium::VertexBuffer::new(&display, &[
-        Vertex { position : [-1.0, -1.0] },
-        Vertex { position : [1.0, -1.0] },
-        Vertex { position : [1.0, 1.0] },
-        Vertex { position : [1.0, 1.0] },
-        Vertex { position : [-1.0, 1.0] },
-        Vertex { position : [-1.0, -1.0] },
-    ]).expect("Error creating vertex buffer");
-    let indices = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
-
-    let vert_src = r#"
-        #version 140
-        in vec2 position;
-        uniform mat4 transform;
-        void main() {
-            gl_Position = transform * vec4(position, 0, 1);
-        }
-    "#;
-
-    let frag_src = r#"
-        #version 140
-        out vec4 color;
-        uniform vec3 pColor;
-        void main() {
-            color = vec4(pColor, 1);
-        }
-    "#;
-
-    let program = glium::Program::from_source(&display, vert_src, frag_src, None)
-        .expect("Error compiling shader program");
+    let mut renderer = GridRenderer::new(&display);
+    // Draws the stamp being placed over the world
+    let mut stamp_renderer = GridRenderer::new(&display);
+    if options.backend == Backend::Gpu {
+        let (mut gpu, facade) = (GpuStepper::new(&display), display.clone());
+        simulation.set_stepper(move |world, rng| gpu.step(&facade, world, rng));
+    }
 
-    let mut rng = rand::thread_rng();
+    // Create imgui context, platform renderer, and attach platform to window
+    let mut imgui = Context::create();
+    imgui.set_ini_filename(None);
+    let mut platform = WinitPlatform::init(&mut imgui);
+    platform.attach_window(imgui.io_mut(), display.gl_window().window(), HiDpiMode::Default);
+    let mut ui_renderer = Renderer::init(&mut imgui, &display).expect("Error initializing UI renderer");
+    let mut frame_timer = Instant::now();
 
-    const GRID_WIDTH : usize = 100;
-    const GRID_HEIGHT : usize = 100;
-    
-    let mut grid = [[Particle::AIR; GRID_HEIGHT]; GRID_WIDTH];
+    // Painting state: left button paints the selected material, right button erases; with Shift
+    // held they place a source of the selected material or a drain instead, and with Ctrl held they
+    // draw a rigid body of the selected material: a rectangle, or the convex hull of the stroke.
+    // With G held, left click places a gravity well and right click removes the wells under the
+    // cursor; Ctrl + left/right arrow turns gravity by 45 degrees.
+    let mut tools = Tools { material : spawn, brush_radius : 2, brush : Brush::Circle, time_scale : 1.0, well_radius : 16.0, well_strength : 0.5 };
+    let mut cursor = (0f64, 0f64);
+    let mut mouse = [false; 2];
+    let mut prev_mouse = mouse;
+    let mut emitters_open = true;
+    let mut tools_open = true;
+    // Average time spent in `Simulation::tick`, shown in the tools panel
+    let mut tick_ms = 0f32;
+    let mut ui_commands = Vec::new();
+    // Edits are undone with Ctrl+Z and redone with Ctrl+Y
+    const UNDO_BUDGET : usize = 64 << 20;
+    let mut history = History::new(UNDO_BUDGET);
+    let mut prev_cell : Option<(i32, i32)> = None;
+    // Cells of the body being drawn, and whether it's a rectangle
+    let mut stroke : Vec<(i32, i32)> = Vec::new();
+    let mut stroke_rectangle = false;
+    // Alt + left drag selects a rectangle, which Ctrl+C copies and Ctrl+X cuts to the clipboard.
+    // Ctrl+V picks up the clipboard as a stamp following the cursor: left click pastes it, the left
+    // and right arrows turn it, F mirrors it (Shift+F upside down), and right click or Escape drops it.
+    let mut selection : Option<((i32, i32), (i32, i32))> = None;
+    let mut clipboard : Option<SandWorld> = None;
+    let mut stamp : Option<SandWorld> = None;
+    // Stamps saved for later, listed in a window toggled with L
+    let mut library = StampLibrary::open(&options.stamps, materials.clone()).unwrap_or_else(|e| {
+        eprintln!("Failed to load stamps from {}: {}", options.stamps.display(), e);
+        std::process::exit(1);
+    });
+    let mut stamps_open = false;
+    let mut stamp_name = String::new();
+    // The mouse wheel zooms around the cursor and dragging with the middle button pans
+    let framebuffer = display.get_framebuffer_dimensions();
+    let mut camera = Camera {
+        aspect : framebuffer.0 as f32 / framebuffer.1 as f32,
+        ..Camera::fit(simulation.world().width() as f32, simulation.world().height() as f32)
+    };
+    let mut panning = false;
+    let material_keys = [
+        VirtualKeyCode::Key1, VirtualKeyCode::Key2, VirtualKeyCode::Key3,
+        VirtualKeyCode::Key4, VirtualKeyCode::Key5, VirtualKeyCode::Key6,
+        VirtualKeyCode::Key7, VirtualKeyCode::Key8, VirtualKeyCode::Key9,
+        VirtualKeyCode::Key0,
+    ];
+    update_title(&display, &simulation, &tools);
 
     let mut now_keys = [false; 255];
-    let mut prev_keys = now_keys.clone();
+    let mut prev_keys = now_keys;
 
     let mut acc = 0f32;
     let mut prev_t = std::time::Instant::now();
     const SIM_DT : f32 = 1.0 / 60.0;
+    // Most simulated time run in one frame, at any speed, so a stall doesn't cause a burst of steps
+    const MAX_FRAME_DT : f32 = 0.25;
 
     //let start_t = std::time::Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
+        platform.handle_event(imgui.io_mut(), display.gl_window().window(), &event);
+        let want_mouse = imgui.io().want_capture_mouse;
+        let want_keyboard = imgui.io().want_capture_keyboard;
+        let frame = matches!(event, glutin::event::Event::MainEventsCleared);
         *control_flow = match event {
             glutin::event::Event::WindowEvent { event, .. } => match event {
                 glutin::event::WindowEvent::CloseRequested => ControlFlow::Exit,
+                glutin::event::WindowEvent::Resized(size) => {
+                    // Keep cells square, showing more or less of the grid along the side that changed
+                    display.gl_window().resize(size);
+                    if size.width > 0 && size.height > 0 { camera.aspect = size.width as f32 / size.height as f32; }
+                    ControlFlow::Poll
+                },
                 glutin::event::WindowEvent::KeyboardInput {
                     input : glutin::event::KeyboardInput { virtual_keycode:Some(keycode), state, .. },
                     ..
                 } => {
                     match state {
-                        glutin::event::ElementState::Pressed => now_keys[keycode as usize] = true,
+                        glutin::event::ElementState::Pressed => if !want_keyboard { now_keys[keycode as usize] = true },
                         glutin::event::ElementState::Released => now_keys[keycode as usize] = false
                     };
                     ControlFlow::Poll
                 },
+                glutin::event::WindowEvent::CursorMoved { position, .. } => {
+                    if panning {
+                        let (world, framebuffer) = (simulation.world(), display.get_framebuffer_dimensions());
+                        let (width, height) = (world.width() as f32, world.height() as f32);
+                        let from = camera.to_grid(width, height, cursor_to_ndc(cursor, framebuffer));
+                        let to = camera.to_grid(width, height, cursor_to_ndc((position.x, position.y), framebuffer));
+                        camera.pan(from, to, width, height);
+                    }
+                    cursor = (position.x, position.y);
+                    ControlFlow::Poll
+                },
+                glutin::event::WindowEvent::MouseWheel { delta, .. } => {
+                    if !want_mouse {
+                        let lines = match delta {
+                            MouseScrollDelta::LineDelta(_, y) => y,
+                            MouseScrollDelta::PixelDelta(p) => p.y as f32 / 100.0
+                        };
+                        let world = simulation.world();
+                        let (width, height) = (world.width() as f32, world.height() as f32);
+                        let at = camera.to_grid(width, height, cursor_to_ndc(cursor, display.get_framebuffer_dimensions()));
+                        camera.zoom_at(at, 1.25f32.powf(lines), width, height);
+                    }
+                    ControlFlow::Poll
+                },
+                glutin::event::WindowEvent::MouseInput { state, button, .. } => {
+                    let pressed = state == glutin::event::ElementState::Pressed && !want_mouse;
+                    match button {
+                        MouseButton::Left => mouse[0] = pressed,
+                        MouseButton::Right => mouse[1] = pressed,
+                        MouseButton::Middle => panning = pressed,
+                        _ => ()
+                    };
+                    ControlFlow::Poll
+                },
                 _ => ControlFlow::Poll
             },
+            glutin::event::Event::LoopDestroyed => {
+                save_recording(&options, &simulation);
+                return;
+            },
+            glutin::event::Event::NewEvents(_) => {
+                // Update imgui with elapsed time
+                imgui.io_mut().update_delta_time(frame_timer.elapsed());
+                frame_timer = Instant::now();
+                ControlFlow::Poll
+            },
             _ => ControlFlow::Poll
         };
+        // Simulate and draw once all pending events are handled
+        if !frame { return; }
 
         // Update time accumulator
         let elapsed = prev_t.elapsed().as_secs_f32();
-        acc += elapsed;
+        acc = (acc + elapsed * tools.time_scale).min(MAX_FRAME_DT);
         prev_t = std::time::Instant::now();
         // If time for update, update and decrement accumulator
         while acc >= SIM_DT {
+            // Changes to the simulation are ignored while a replay is playing
+            let mut commands = std::mem::take(&mut ui_commands);
+            let live = replay.as_ref().is_none_or(|r| replay_tick >= r.ticks.len());
+
             // Handle key changes and update keys
             if now_keys[VirtualKeyCode::Q as usize] && !prev_keys[VirtualKeyCode::Q as usize] { *control_flow = ControlFlow::Exit; }
-            if now_keys[VirtualKeyCode::R as usize] && !prev_keys[VirtualKeyCode::R as usize] { 
-                for i in 0..GRID_WIDTH { for j in 0..GRID_HEIGHT { grid[i][j] = Particle::AIR; } }
+            if now_keys[VirtualKeyCode::R as usize] && !prev_keys[VirtualKeyCode::R as usize] { commands.push(Command::Clear); }
+            if now_keys[VirtualKeyCode::W as usize] && !prev_keys[VirtualKeyCode::W as usize] {
+                // Cycle the first emitter through every material except air
+                if let (Some(&e), true) = (simulation.world().emitters().first(), materials.len() > 1) {
+                    let particle = Particle(e.particle.0 % (materials.len() - 1) as u8 + 1);
+                    commands.push(Command::EditEmitter(0, Emitter { particle, ..e }));
+                }
+            }
+            if now_keys[VirtualKeyCode::Tab as usize] && !prev_keys[VirtualKeyCode::Tab as usize] { emitters_open = !emitters_open; }
+            if now_keys[VirtualKeyCode::T as usize] && !prev_keys[VirtualKeyCode::T as usize] { tools_open = !tools_open; }
+            if now_keys[VirtualKeyCode::H as usize] && !prev_keys[VirtualKeyCode::H as usize] { renderer.set_heatmap(!renderer.heatmap()); }
+            if now_keys[VirtualKeyCode::L as usize] && !prev_keys[VirtualKeyCode::L as usize] { stamps_open = !stamps_open; }
+            if now_keys[VirtualKeyCode::Escape as usize] && !prev_keys[VirtualKeyCode::Escape as usize] {
+                if stamp.is_some() { stamp = None; } else { selection = None; }
+            }
+            if now_keys[VirtualKeyCode::Space as usize] && !prev_keys[VirtualKeyCode::Space as usize] {
+                commands.push(Command::Pause(!simulation.paused()));
+            }
+            if now_keys[VirtualKeyCode::Period as usize] && !prev_keys[VirtualKeyCode::Period as usize] && simulation.paused() {
+                commands.push(Command::Step);
+            }
+            if now_keys[VirtualKeyCode::Minus as usize] && !prev_keys[VirtualKeyCode::Minus as usize] {
+                tools.time_scale = (tools.time_scale / 2.0).max(1.0 / 8.0);
+                update_title(&display, &simulation, &tools);
+            }
+            if now_keys[VirtualKeyCode::Equals as usize] && !prev_keys[VirtualKeyCode::Equals as usize] {
+                tools.time_scale = (tools.time_scale * 2.0).min(8.0);
+                update_title(&display, &simulation, &tools);
+            }
+            for (n, key) in material_keys.iter().enumerate() {
+                if n + 1 < materials.len() && now_keys[*key as usize] && !prev_keys[*key as usize] {
+                    tools.material = Particle(n as u8 + 1);
+                    update_title(&display, &simulation, &tools);
+                }
+            }
+            if now_keys[VirtualKeyCode::LBracket as usize] && !prev_keys[VirtualKeyCode::LBracket as usize] {
+                tools.brush_radius = (tools.brush_radius - 1).max(0);
+                update_title(&display, &simulation, &tools);
+            }
+            if now_keys[VirtualKeyCode::RBracket as usize] && !prev_keys[VirtualKeyCode::RBracket as usize] {
+                tools.brush_radius = (tools.brush_radius + 1).min(20);
+                update_title(&display, &simulation, &tools);
+            }
+            let ctrl = now_keys[VirtualKeyCode::LControl as usize] || now_keys[VirtualKeyCode::RControl as usize];
+            let undo = ctrl && now_keys[VirtualKeyCode::Z as usize] && !prev_keys[VirtualKeyCode::Z as usize];
+            let redo = ctrl && now_keys[VirtualKeyCode::Y as usize] && !prev_keys[VirtualKeyCode::Y as usize];
+            let shift = now_keys[VirtualKeyCode::LShift as usize] || now_keys[VirtualKeyCode::RShift as usize];
+            let alt = now_keys[VirtualKeyCode::LAlt as usize] || now_keys[VirtualKeyCode::RAlt as usize];
+            let pressed = |key : VirtualKeyCode| now_keys[key as usize] && !prev_keys[key as usize];
+            if let (true, Some((a, b))) = (ctrl && (pressed(VirtualKeyCode::C) || pressed(VirtualKeyCode::X)), selection) {
+                let (x, y, width, height) = region(a, b);
+                if let Some(copied) = simulation.world().copy_region(x, y, width, height) { clipboard = Some(copied); }
+                if pressed(VirtualKeyCode::X) { commands.push(Command::Erase { x, y, width, height }); }
+            }
+            if ctrl && pressed(VirtualKeyCode::V) && clipboard.is_some() { stamp = clipboard.clone(); }
+            if ctrl && (pressed(VirtualKeyCode::Left) || pressed(VirtualKeyCode::Right)) {
+                let angle = if pressed(VirtualKeyCode::Left) { std::f32::consts::FRAC_PI_4 } else { -std::f32::consts::FRAC_PI_4 };
+                commands.push(Command::SetGravity(Mat2::from_angle(angle) * simulation.world().gravity()));
+            }
+            if let (false, Some(held)) = (ctrl, &mut stamp) {
+                if pressed(VirtualKeyCode::Left) { *held = held.rotated(); }
+                if pressed(VirtualKeyCode::Right) { *held = held.rotated().rotated().rotated(); }
+                if pressed(VirtualKeyCode::F) { *held = if shift { held.rotated().rotated().flipped() } else { held.flipped() }; }
+            }
+            for (key, action) in [(VirtualKeyCode::S, FileAction::Save), (VirtualKeyCode::O, FileAction::Load), (VirtualKeyCode::E, FileAction::Export)] {
+                if ctrl && now_keys[key as usize] && !prev_keys[key as usize] {
+                    commands.extend(file_action(action, simulation.world(), &world_path, &materials));
+                }
+            }
+            if now_keys[VirtualKeyCode::PageUp as usize] && !prev_keys[VirtualKeyCode::PageUp as usize] {
+                let world = simulation.world();
+                commands.push(Command::Resize { width : (world.width() * 2).min(MAX_SIZE), height : (world.height() * 2).min(MAX_SIZE) });
+            }
+            if now_keys[VirtualKeyCode::PageDown as usize] && !prev_keys[VirtualKeyCode::PageDown as usize] {
+                let world = simulation.world();
+                commands.push(Command::Resize { width : (world.width() / 2).max(16), height : (world.height() / 2).max(16) });
             }
             prev_keys.copy_from_slice(&now_keys);
 
-            // Update simulation
-            // - Collect moves for all particles
-            let mut moves : Vec<Move> = Vec::new();
-            for i in 0..GRID_WIDTH {
-                for j in 0..GRID_HEIGHT {
-                    if grid[i][j] == Particle::SAND {
-                        if j > 0 && (grid[i][j - 1] == Particle::AIR || grid[i][j - 1] == Particle::WATER) {
-                            moves.push(Move { src: (i, j), dst: (i, j - 1) });
-                        } else if i > 0 && j > 0 && (grid[i - 1][j - 1] == Particle::AIR || grid[i - 1][j - 1] == Particle::WATER) {
-                            moves.push(Move { src: (i, j), dst: (i - 1, j - 1) });
-                        } else if i < (GRID_WIDTH - 1) && j > 0 && (grid[i + 1][j - 1] == Particle::AIR || grid[i + 1][j - 1] == Particle::WATER) {
-                            moves.push(Move { src: (i, j), dst: (i + 1, j - 1) });
-                        }
-                    } else if grid[i][j] == Particle::WATER {
-
+            // Paint or erase along the cursor path since the last tick
+            let world = simulation.world();
+            camera.clamp(world.width() as f32, world.height() as f32);
+            let transform = camera.transform(world.width() as f32, world.height() as f32);
+            let cell = cursor_to_cell(cursor, display.get_framebuffer_dimensions(), transform);
+            if !stroke.is_empty() && !mouse[0] && !mouse[1] {
+                let body = if stroke_rectangle {
+                    let (a, b) = (stroke[0], stroke[stroke.len() - 1]);
+                    let (min, max) = (Vec2::new(a.0.min(b.0) as f32, a.1.min(b.1) as f32), Vec2::new(a.0.max(b.0) as f32, a.1.max(b.1) as f32));
+                    Body::rectangle(min - 0.5, max + 0.5, tools.material)
+                } else {
+                    Body::polygon(&stroke.iter().map(|&(x, y)| Vec2::new(x as f32, y as f32)).collect::<Vec<_>>(), tools.material)
+                };
+                if materials[tools.material].state != State::Solid {
+                    eprintln!("Bodies must be made of a solid material");
+                } else if let Some(body) = body {
+                    commands.push(Command::AddBody(Box::new(body)));
+                }
+                stroke.clear();
+            }
+            if let Some(held) = &stamp {
+                if mouse[0] && !prev_mouse[0] {
+                    let (x, y) = stamp_origin(cell, held);
+                    commands.push(Command::Paste { x, y, stamp : Box::new(held.clone()) });
+                }
+                if mouse[1] && !prev_mouse[1] { stamp = None; }
+                prev_cell = None;
+            } else if alt && (mouse[0] || mouse[1]) {
+                if mouse[1] { selection = None; }
+                else if !prev_mouse[0] { selection = Some((cell, cell)); }
+                else if let Some((_, corner)) = &mut selection { *corner = cell; }
+                prev_cell = None;
+            } else if ctrl && (mouse[0] || mouse[1]) {
+                if stroke.is_empty() { stroke_rectangle = mouse[0]; }
+                if stroke.last() != Some(&cell) { stroke.push(cell); }
+                prev_cell = None;
+            } else if now_keys[VirtualKeyCode::G as usize] {
+                // Place a gravity well, or remove those under the cursor
+                if mouse[0] && !prev_mouse[0] {
+                    commands.push(Command::AddWell(GravityWell { x : cell.0, y : cell.1, radius : tools.well_radius, strength : tools.well_strength }));
+                }
+                if mouse[1] && !prev_mouse[1] {
+                    let at = Vec2::new(cell.0 as f32, cell.1 as f32);
+                    let wells = simulation.world().wells().iter().enumerate().rev();
+                    for (i, _) in wells.filter(|(_, w)| at.distance(Vec2::new(w.x as f32, w.y as f32)) <= w.radius) {
+                        commands.push(Command::RemoveWell(i));
                     }
                 }
+                prev_cell = None;
+            } else if shift {
+                // Place emitters on click rather than painting
+                let (kind, particle) = if mouse[0] { (EmitterKind::Source, tools.material) } else { (EmitterKind::Drain, Particle::AIR) };
+                if (mouse[0] && !prev_mouse[0]) || (mouse[1] && !prev_mouse[1]) {
+                    commands.push(Command::AddEmitter(Emitter { kind, x : cell.0, y : cell.1, particle, rate : 1.0, spread : tools.brush_radius }));
+                }
+                prev_cell = None;
+            } else if mouse[0] || mouse[1] {
+                let particle = if mouse[0] { tools.material } else { Particle::AIR };
+                commands.push(Command::Paint { from : prev_cell.unwrap_or(cell), to : cell, radius : tools.brush_radius, brush : tools.brush, particle });
+                prev_cell = Some(cell);
+            } else {
+                prev_cell = None;
             }
-            // - Sort moves by destination
-            moves.sort_unstable();
-            // - Iterate through moves, add to stack, and pick move from stack to execute on destination change
-            let mut dst_prev = 0;
-            moves.push(Move { src: (usize::MAX, usize::MAX), dst: (usize::MAX, usize::MAX) });
-            for i in 0..(moves.len() - 1) {
-                if moves[i + 1] != moves[i] {
-                    let m = moves[rng.gen_range(dst_prev..(i + 1))];
-                    let p = grid[m.src.0][m.src.1];
-                    grid[m.src.0][m.src.1] = grid[m.dst.0][m.dst.1];
-                    grid[m.dst.0][m.dst.1] = p;
-                    dst_prev = i + 1;
+            prev_mouse = mouse;
+
+            // Update simulation
+            let paused = simulation.paused();
+            match &replay {
+                Some(replay) if !live => {
+                    for command in &replay.ticks[replay_tick] { simulation.apply(command.clone()); }
+                    replay_tick += 1;
+                    if replay_tick == replay.ticks.len() { println!("Replay finished after {} ticks", replay_tick); }
+                }
+                _ => {
+                    let restore = if undo { history.undo(simulation.world()) } else if redo { history.redo(simulation.world()) } else { None };
+                    if let Some(command) = restore { simulation.apply(command); }
+                    for command in commands { history.apply(&mut simulation, command); }
+                    history.end_tick();
                 }
             }
+            if simulation.paused() != paused { update_title(&display, &simulation, &tools); }
+            let tick_start = Instant::now();
+            simulation.tick();
+            tick_ms = 0.9 * tick_ms + 0.1 * tick_start.elapsed().as_secs_f32() * 1000.0;
 
-            grid[rng.gen_range(0..GRID_WIDTH)][99] = Particle::SAND;
-            //grid[50][99] = Particle::SAND;
-            
             // Decrement accumulator
             acc -= SIM_DT;
         }
 
 
-        let mut target = display.draw();
-        target.clear_color(0.1, 0.1, 0.1, 1.0);
-        for i in 0..GRID_WIDTH {
-            for j in 0..GRID_HEIGHT {
-                if !matches!(grid[i][j], Particle::AIR) {
-                    let color = match grid[i][j] {
-                        Particle::SAND => [1.0, 0.883, 0.617f32],
-                        Particle::WATER => [0.176, 0.535, 0.938f32],
-                        _ => [1.0, 0.0, 1.0f32]
-                    };
-                    let width = GRID_WIDTH as f32;
-                    let height = GRID_HEIGHT as f32;
-                    let x = i as f32;
-                    let y = j as f32;
-                    let transform = Mat4::from_scale_rotation_translation(
-                        Vec3::new(1.0 / (GRID_WIDTH as f32), 1.0 / (GRID_HEIGHT as f32), 1.0),
-                        Quat::IDENTITY,
-                        Vec3::new(2.0 * ((x / width) - 0.5), 2.0 * ((y / height) - 0.5), 0.0)
-                    );
-                    let uniforms = glium::uniform!{
-                        transform : transform.to_cols_array_2d(),
-                        pColor : color
-                    };
-                    target.draw(&vertex_buffer, &indices, &program, &uniforms, &Default::default())
-                        .expect("Error drawing particle");
-                }
+        let gl_window = display.gl_window();
+        platform.prepare_frame(imgui.io_mut(), gl_window.window()).expect("Error preparing UI frame");
+        let ui = imgui.frame();
+        let world = simulation.world();
+        let (width, height) = (world.width() as f32, world.height() as f32);
+        camera.clamp(width, height);
+        let transform = camera.transform(width, height);
+        ui_commands.extend(show_emitters(&mut emitters_open, &ui, world));
+        let previous_tools = tools;
+        let mut effects = renderer.effects();
+        let (commands, action) = show_tools(&mut tools_open, &ui, &simulation, &mut tools, &mut effects, tick_ms, &world_path);
+        renderer.set_effects(effects);
+        ui_commands.extend(commands);
+        if let Some(action) = action { ui_commands.extend(file_action(action, world, &world_path, &materials)); }
+        if let Some(picked) = show_stamps(&mut stamps_open, &ui, &mut library, clipboard.as_ref(), &mut stamp_name) { stamp = Some(picked); }
+        if tools != previous_tools { update_title(&display, &simulation, &tools); }
+
+        // Mark each emitter's strip of cells, in its material color for sources and red for drains
+        let draw_list = ui.get_background_draw_list();
+        let (framebuffer, scale) = (display.get_framebuffer_dimensions(), ui.io().display_framebuffer_scale[0]);
+        for e in world.emitters() {
+            let to_ui = |x : f32| {
+                let (px, py) = cell_to_cursor((x, e.y as f32), framebuffer, transform);
+                [px / scale, py / scale]
+            };
+            let color = match e.kind {
+                EmitterKind::Source => { let [r, g, b] = world.materials()[e.particle].color; [r, g, b, 1.0] }
+                EmitterKind::Drain => [0.9, 0.1, 0.1, 1.0]
+            };
+            draw_list.add_line(to_ui(e.x as f32 - e.spread as f32 - 0.5), to_ui(e.x as f32 + e.spread as f32 + 0.5), color).thickness(2.0).build();
+        }
+        // Outline the area of each gravity well
+        for well in world.wells() {
+            let to_ui = |x : f32, y : f32| {
+                let (px, py) = cell_to_cursor((x, y), framebuffer, transform);
+                [px / scale, py / scale]
+            };
+            let center = to_ui(well.x as f32, well.y as f32);
+            let edge = to_ui(well.x as f32 + well.radius, well.y as f32);
+            draw_list.add_circle(center, edge[0] - center[0], [0.7, 0.4, 1.0, 1.0]).thickness(1.5).build();
+        }
+        // Outline the body being drawn
+        if !stroke.is_empty() {
+            let to_ui = |(x, y) : (f32, f32)| {
+                let (px, py) = cell_to_cursor((x, y), framebuffer, transform);
+                [px / scale, py / scale]
+            };
+            let (a, b) = (stroke[0], stroke[stroke.len() - 1]);
+            let outline : Vec<[f32; 2]> = if stroke_rectangle {
+                let (x0, x1) = (a.0.min(b.0) as f32 - 0.5, a.0.max(b.0) as f32 + 0.5);
+                let (y0, y1) = (a.1.min(b.1) as f32 - 0.5, a.1.max(b.1) as f32 + 0.5);
+                [(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)].into_iter().map(to_ui).collect()
+            } else {
+                stroke.iter().map(|&(x, y)| to_ui((x as f32, y as f32))).collect()
+            };
+            for segment in outline.windows(2) {
+                draw_list.add_line(segment[0], segment[1], [1.0, 1.0, 1.0, 1.0]).thickness(1.5).build();
             }
         }
+        // Outline the selection and the stamp being placed
+        let cell = cursor_to_cell(cursor, framebuffer, transform);
+        let held = stamp.as_ref().map(|held| {
+            let (x, y) = stamp_origin(cell, held);
+            (x, y, held.width(), held.height())
+        });
+        let outlines = [(selection.map(|(a, b)| region(a, b)), [0.4, 0.8, 1.0, 1.0]), (held, [1.0, 1.0, 1.0, 1.0])];
+        for (rect, color) in outlines {
+            if let Some((x, y, w, h)) = rect {
+                let to_ui = |(x, y) : (f32, f32)| {
+                    let (px, py) = cell_to_cursor((x - 0.5, y - 0.5), framebuffer, transform);
+                    [px / scale, py / scale]
+                };
+                draw_list.add_rect(to_ui((x as f32, y as f32)), to_ui((x as f32 + w as f32, y as f32 + h as f32)), color).thickness(1.5).build();
+            }
+        }
+        // When zoomed in, a minimap shows the whole world with the visible region outlined
+        let (minimap_rect, minimap_transform) = minimap(framebuffer, width, height);
+        if camera.zoom > 1.0 {
+            let to_ui = |grid : Vec2| {
+                let (px, py) = cell_to_cursor((grid.x, grid.y), framebuffer, minimap_transform);
+                [px / scale, py / scale]
+            };
+            let (lo, hi) = (camera.to_grid(width, height, Vec2::new(-1.0, -1.0)), camera.to_grid(width, height, Vec2::new(1.0, 1.0)));
+            draw_list.add_rect(to_ui(Vec2::ZERO), to_ui(Vec2::new(width, height)), [0.6, 0.6, 0.6, 1.0]).build();
+            draw_list.add_rect(to_ui(lo), to_ui(hi), [1.0, 0.9, 0.2, 1.0]).thickness(1.5).build();
+        }
+        drop(draw_list);
+
+        // Darker bars around the grid when the window's shape doesn't match it
+        let mut target = display.draw();
+        target.clear_color(0.05, 0.05, 0.05, 1.0);
+        target.clear(Some(&grid_rect(framebuffer, transform, width, height)), Some((0.1, 0.1, 0.1, 1.0)), false, None, None);
+        renderer.upload(&display, world);
+        renderer.draw(&mut target, transform);
+        if let (Some(held), Some((x, y, _, _))) = (&stamp, held) {
+            stamp_renderer.upload(&display, held);
+            stamp_renderer.draw(&mut target, transform * Mat4::from_translation(Vec3::new(x as f32, y as f32, 0.0)));
+        }
+        if camera.zoom > 1.0 {
+            target.clear(Some(&minimap_rect), Some((0.05, 0.05, 0.05, 1.0)), false, None, None);
+            renderer.draw(&mut target, minimap_transform);
+        }
+        platform.prepare_render(&ui, gl_window.window());
+        let draw_data = ui.render();
+        ui_renderer.render(&mut target, draw_data).expect("Error rendering UI");
         target.finish().expect("Error finishing draw");
     });
 }
\ No newline at end of file
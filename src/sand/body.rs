@@ -0,0 +1,395 @@
+//! Rigid bodies moving through a `SandWorld`.
+//!
+//! A body is a convex polygon made of one solid material. Between ticks its cells are stamped into
+//! the grid, so particles land on and pile up against it like against any other solid. Once the
+//! particles have moved, every body in turn is lifted out of the grid, moved and stamped back at its
+//! new pose, with the other bodies still in place.
+//!
+//! Cells a moving body would cover are either obstacles or get pushed aside into the nearest empty
+//! cell. Obstacles are solids (including other bodies), the grid edges, and powders unless the body
+//! is fast enough to dig into them, so a dropped body sinks into a sand pile and then comes to rest
+//! on it. A body also stops short of particles with no empty cell nearby, so none are lost.
+//! Touching an obstacle applies an impulse at the mean point of contact, which makes bodies bounce,
+//! slide and tip over. Pushing particles aside costs the body their mass in momentum, and liquids
+//! around a body lift it in proportion to their density, so bodies lighter than water float.
+
+use glam::{Vec2, Mat2};
+use crate::{SandWorld, Particle, State, MAX_SPEED};
+use crate::gravity::gravity_at;
+use crate::step::mark_around;
+
+// Share of the speed into an obstacle kept as a bounce, for impacts faster than `BOUNCE_SPEED`
+const RESTITUTION : f32 = 0.3;
+const BOUNCE_SPEED : f32 = 1.0;
+// Largest tangential impulse at a contact, relative to the normal one
+const FRICTION : f32 = 0.5;
+// Speed above which a body pushes powders aside instead of resting on them
+const DIG_SPEED : f32 = 2.0;
+// Share of the velocity lost per tick when surrounded by liquid
+const LIQUID_DRAG : f32 = 0.1;
+// Bodies touching an obstacle stop once slower than this, so resting bodies don't jitter
+const SLEEP_SPEED : f32 = 0.05;
+// Largest distance a body is pushed back out of an obstacle in one tick, and the increments tried
+const MAX_PUSH : f32 = 2.0;
+const PUSH_STEP : f32 = 0.25;
+
+/// A rigid convex polygon of a solid material, see `SandWorld::add_body`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Body {
+    pub particle : Particle,
+    /// Center of mass, in grid coordinates
+    pub position : Vec2,
+    /// Rotation around the center of mass, in radians counter-clockwise
+    pub angle : f32,
+    /// In cells per tick
+    pub velocity : Vec2,
+    /// In radians per tick
+    pub angular_velocity : f32,
+    // Corners relative to the center of mass, counter-clockwise
+    pub(crate) shape : Vec<Vec2>,
+    pub(crate) area : f32,
+    // Moment of inertia divided by mass
+    pub(crate) inertia : f32,
+    // Grid indices of the cells currently stamped, in ascending order
+    pub(crate) cells : Vec<usize>
+}
+
+impl Body {
+    /// The axis-aligned rectangle spanning two opposite corners; `None` if it's less than a cell
+    pub fn rectangle(a : Vec2, b : Vec2, particle : Particle) -> Option<Body> {
+        let (min, max) = (a.min(b), a.max(b));
+        Body::polygon(&[min, Vec2::new(max.x, min.y), max, Vec2::new(min.x, max.y)], particle)
+    }
+
+    /// The convex hull of `points`, e.g. a stroke drawn with the mouse; `None` if it's less than a cell
+    pub fn polygon(points : &[Vec2], particle : Particle) -> Option<Body> {
+        let hull = convex_hull(points);
+        let cross = |a : Vec2, b : Vec2| a.perp_dot(b);
+        let edges = || hull.iter().zip(hull.iter().cycle().skip(1)).map(|(&a, &b)| (a, b));
+        let area = edges().map(|(a, b)| cross(a, b)).sum::<f32>() / 2.0;
+        if hull.len() < 3 || area < 1.0 { return None; }
+        let center = edges().map(|(a, b)| (a + b) * cross(a, b)).fold(Vec2::ZERO, |s, v| s + v) / (6.0 * area);
+        let shape : Vec<Vec2> = hull.iter().map(|&v| v - center).collect();
+        let second_moment = shape.iter().zip(shape.iter().cycle().skip(1))
+            .map(|(&a, &b)| cross(a, b) * (a.dot(a) + a.dot(b) + b.dot(b)))
+            .sum::<f32>() / 12.0;
+        Some(Body {
+            particle, position : center, angle : 0.0, velocity : Vec2::ZERO, angular_velocity : 0.0,
+            shape, area, inertia : second_moment / area, cells : Vec::new()
+        })
+    }
+
+    /// Number of cells the body covers, roughly
+    pub fn area(&self) -> f32 { self.area }
+
+    /// Corners at the current pose, counter-clockwise
+    pub fn vertices(&self) -> impl Iterator<Item = Vec2> + '_ {
+        let rotation = Mat2::from_angle(self.angle);
+        self.shape.iter().map(move |&v| self.position + rotation * v)
+    }
+
+    // Distance from the center of mass to the farthest corner
+    fn radius(&self) -> f32 {
+        self.shape.iter().map(|v| v.length()).fold(0.0, f32::max)
+    }
+
+    // Cells whose center lies inside the body at the given pose, row by row from the bottom; may
+    // include cells outside the grid
+    fn footprint(&self, position : Vec2, angle : f32) -> Vec<(i64, i64)> {
+        let rotation = Mat2::from_angle(angle);
+        let corners : Vec<Vec2> = self.shape.iter().map(|&v| position + rotation * v).collect();
+        let (min, max) = corners.iter().fold((corners[0], corners[0]), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+        let mut cells = Vec::new();
+        for y in (min.y.ceil() as i64)..=(max.y.floor() as i64) {
+            for x in (min.x.ceil() as i64)..=(max.x.floor() as i64) {
+                let p = Vec2::new(x as f32, y as f32);
+                let inside = corners.iter().zip(corners.iter().cycle().skip(1)).all(|(&a, &b)| (b - a).perp_dot(p - a) >= 0.0);
+                if inside { cells.push((x, y)); }
+            }
+        }
+        cells
+    }
+}
+
+// Counter-clockwise convex hull without collinear points (Andrew's monotone chain)
+fn convex_hull(points : &[Vec2]) -> Vec<Vec2> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    sorted.dedup();
+    if sorted.len() < 3 { return sorted; }
+    let mut hull : Vec<Vec2> = Vec::new();
+    for pass in [&sorted[..], &sorted.iter().rev().copied().collect::<Vec<_>>()[..]] {
+        let start = hull.len();
+        for &p in pass {
+            while hull.len() >= start + 2 && (hull[hull.len() - 1] - hull[hull.len() - 2]).perp_dot(p - hull[hull.len() - 2]) <= 0.0 {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        // The last point of each pass starts the other one
+        hull.pop();
+    }
+    hull
+}
+
+impl SandWorld {
+    pub fn bodies(&self) -> &[Body] { &self.bodies }
+
+    /// Places `body` in the world, pushing aside particles where it overlaps them. Fails if the body
+    /// isn't made of a solid, leaves the grid, overlaps a solid or has no room to push particles to.
+    pub fn add_body(&mut self, mut body : Body) -> bool {
+        if self.materials[body.particle].state != State::Solid { return false; }
+        let footprint = body.footprint(body.position, body.angle);
+        if footprint.iter().any(|&cell| self.blocks(cell, true)) { return false; }
+        let room = self.make_room(&footprint, body.radius());
+        if room.iter().any(|(_, to)| to.is_none()) { return false; }
+        self.stamp(&mut body, &footprint, &room, &[]);
+        let temperature = self.materials[body.particle].temperature;
+        for &c in &body.cells {
+            self.temperature[c] = temperature;
+            self.wake_heat(c % self.width, c / self.width);
+        }
+        self.bodies.push(body);
+        true
+    }
+
+    // Takes over a body whose cells are already in the grid, e.g. after loading or resizing; false
+    // if it doesn't fit in the grid any more
+    pub(crate) fn adopt_body(&mut self, mut body : Body) -> bool {
+        let footprint = body.footprint(body.position, body.angle);
+        if footprint.iter().any(|&(x, y)| !self.in_grid(x, y)) { return false; }
+        body.cells = footprint.iter().map(|&(x, y)| y as usize * self.width + x as usize)
+            .filter(|&c| self.grid[c] == body.particle)
+            .collect();
+        self.bodies.push(body);
+        true
+    }
+
+    // Removes every body, turning the cells it still holds into air
+    pub(crate) fn lift_bodies(&mut self) {
+        for body in std::mem::take(&mut self.bodies) {
+            for &c in &body.cells {
+                if self.grid[c] == body.particle { self.set(c % self.width, c / self.width, Particle::AIR); }
+            }
+        }
+    }
+
+    // Puts back a body taken from this world, in place if its cells are still in the grid and like
+    // `add_body` otherwise
+    pub(crate) fn place_body(&mut self, body : Body) -> bool {
+        let footprint = body.footprint(body.position, body.angle);
+        let in_place = footprint.iter().all(|&(x, y)| self.in_grid(x, y) && self.grid[y as usize * self.width + x as usize] == body.particle);
+        if in_place { self.adopt_body(body) } else { self.add_body(body) }
+    }
+
+    // Moves every body one tick, removing those whose cells were mostly turned into something else,
+    // e.g. burned, and leaving their remaining cells in place
+    pub(crate) fn update_bodies(&mut self) {
+        let mut bodies = std::mem::take(&mut self.bodies);
+        bodies.retain_mut(|body| self.update_body(body));
+        self.bodies = bodies;
+    }
+
+    fn update_body(&mut self, body : &mut Body) -> bool {
+        let old = std::mem::take(&mut body.cells);
+        let kept = old.iter().filter(|&&c| self.grid[c] == body.particle).count();
+        if kept * 2 < old.len() {
+            // What is left of the body stays behind as plain particles
+            for &c in &old { self.mark(c); }
+            return false;
+        }
+        for &c in &old {
+            if self.grid[c] == body.particle { self.grid[c] = Particle::AIR; }
+        }
+
+        // Gravity, and buoyancy and drag from the liquid around the body
+        let density = self.materials[body.particle].density;
+        let footprint = body.footprint(body.position, body.angle);
+        let (mut ring, mut liquid, mut lift) = (0, 0, 0.0);
+        for &(x, y) in &footprint {
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0)] {
+                let (nx, ny) = (x + dx, y + dy);
+                if footprint.binary_search_by_key(&(ny, nx), |&(x, y)| (y, x)).is_ok() { continue; }
+                ring += 1;
+                if !self.in_grid(nx, ny) { continue; }
+                let m = &self.materials[self.grid[ny as usize * self.width + nx as usize]];
+                if m.state == State::Liquid {
+                    liquid += 1;
+                    lift += m.density;
+                }
+            }
+        }
+        let gravity = gravity_at(self.gravity, &self.wells, body.position);
+        body.velocity += gravity;
+        if ring > 0 {
+            body.velocity -= gravity * lift / (ring as f32 * density);
+            let drag = 1.0 - LIQUID_DRAG * liquid as f32 / ring as f32;
+            body.velocity *= drag;
+            body.angular_velocity *= drag;
+        }
+        let radius = body.radius().max(1.0);
+        body.velocity = body.velocity.clamp_length_max(MAX_SPEED);
+        body.angular_velocity = body.angular_velocity.clamp(-MAX_SPEED / radius, MAX_SPEED / radius);
+
+        // Move in steps of at most half a cell, stopping at the first contact
+        let digging = body.velocity.length() >= DIG_SPEED;
+        let steps = (body.velocity.length().max(body.angular_velocity.abs() * radius) * 2.0).ceil().max(1.0);
+        // Poses along the way, starting with the current one
+        let mut poses = vec![(body.position, body.angle)];
+        for _ in 0..steps as usize {
+            let (position, angle) = poses[poses.len() - 1];
+            let (next, next_angle) = (position + body.velocity / steps, angle + body.angular_velocity / steps);
+            let obstacles : Vec<(i64, i64)> = body.footprint(next, next_angle).into_iter().filter(|&c| self.blocks(c, digging)).collect();
+            if obstacles.is_empty() {
+                poses.push((next, next_angle));
+                continue;
+            }
+            let normal = self.contact(body, &obstacles, next, digging);
+            // Push the body back out along the normal, or keep it where it was
+            for k in 1..=(MAX_PUSH / PUSH_STEP) as usize {
+                let pushed = next + normal * PUSH_STEP * k as f32;
+                if body.footprint(pushed, next_angle).into_iter().all(|c| !self.blocks(c, digging)) {
+                    poses.push((pushed, next_angle));
+                    break;
+                }
+            }
+            if body.velocity.length() < SLEEP_SPEED && body.angular_velocity.abs() * radius < SLEEP_SPEED {
+                body.velocity = Vec2::ZERO;
+                body.angular_velocity = 0.0;
+            }
+            break;
+        }
+
+        // Stop short of the first pose where a particle in the way has nowhere to go, as if it were
+        // an obstacle. Particles the body was stamped over where it started stay where they are.
+        let mut footprint = Vec::new();
+        let mut room = Vec::new();
+        for (k, &(position, angle)) in poses.iter().enumerate().rev() {
+            footprint = body.footprint(position, angle);
+            room = self.make_room(&footprint, radius);
+            (body.position, body.angle) = (position, angle);
+            if room.iter().all(|(_, to)| to.is_some()) { break; }
+            if k > 0 {
+                body.velocity = Vec2::ZERO;
+                body.angular_velocity = 0.0;
+            }
+        }
+
+        // Whatever was pushed aside slows the body down
+        let displaced = self.stamp(body, &footprint, &room, &old);
+        for &c in &old {
+            if body.cells.binary_search(&c).is_err() { self.mark(c); }
+        }
+        let slowdown = density * body.area / (density * body.area + displaced);
+        body.velocity *= slowdown;
+        body.angular_velocity *= slowdown;
+        true
+    }
+
+    // Applies the impulse for touching `obstacles` at a body centered on `position`, returning the
+    // contact normal
+    fn contact(&self, body : &mut Body, obstacles : &[(i64, i64)], position : Vec2, digging : bool) -> Vec2 {
+        // The normal points from the obstacles towards free cells next to them
+        let mut sum = Vec2::ZERO;
+        let mut normal = Vec2::ZERO;
+        for &(x, y) in obstacles {
+            sum += Vec2::new(x as f32, y as f32);
+            for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                if !self.blocks((x + dx, y + dy), digging) { normal += Vec2::new(dx as f32, dy as f32); }
+            }
+        }
+        let point = sum / obstacles.len() as f32;
+        let normal = normal.try_normalize().or_else(|| (position - point).try_normalize()).unwrap_or(Vec2::Y);
+        let r = point - position;
+        let k = body.inertia.max(f32::EPSILON);
+        let contact_velocity = body.velocity + r.perp() * body.angular_velocity;
+        let speed = contact_velocity.dot(normal);
+        if speed >= 0.0 { return normal; }
+        let restitution = if -speed > BOUNCE_SPEED { RESTITUTION } else { 0.0 };
+        let rn = r.perp_dot(normal);
+        let j = -(1.0 + restitution) * speed / (1.0 + rn * rn / k);
+        body.velocity += normal * j;
+        body.angular_velocity += rn * j / k;
+        let tangent = contact_velocity - normal * speed;
+        if let Some(t) = tangent.try_normalize() {
+            let rt = r.perp_dot(t);
+            let jt = (tangent.length() / (1.0 + rt * rt / k)).min(FRICTION * j);
+            body.velocity -= t * jt;
+            body.angular_velocity -= rt * jt / k;
+        }
+        normal
+    }
+
+    // Where each particle in the cells of `footprint` goes to make room for a body: the nearest empty
+    // cell outside it, searching rings of growing size up to twice the body's `radius`, top row first.
+    // `None` for particles with nowhere to go.
+    fn make_room(&self, footprint : &[(i64, i64)], radius : f32) -> Vec<(usize, Option<usize>)> {
+        let reach = radius.ceil() as i64 * 2 + 2;
+        let covered = |x : i64, y : i64| footprint.binary_search_by_key(&(y, x), |&(x, y)| (y, x)).is_ok();
+        let mut room : Vec<(usize, Option<usize>)> = Vec::new();
+        for &(x, y) in footprint {
+            if !self.in_grid(x, y) { continue; }
+            let c = y as usize * self.width + x as usize;
+            if self.grid[c] == Particle::AIR { continue; }
+            let free = (1..=reach).flat_map(|r| (-r..=r).rev().flat_map(move |dy| {
+                let dxs : Vec<i64> = if dy.abs() == r { (-r..=r).collect() } else { vec![-r, r] };
+                dxs.into_iter().map(move |dx| (x + dx, y + dy))
+            })).map(|(nx, ny)| (nx, ny, ny * self.width as i64 + nx)).find(|&(nx, ny, n)| {
+                self.in_grid(nx, ny) && !covered(nx, ny) && self.grid[n as usize] == Particle::AIR
+                    && !room.iter().any(|&(_, to)| to == Some(n as usize))
+            });
+            room.push((c, free.map(|(_, _, n)| n as usize)));
+        }
+        room
+    }
+
+    // Writes the body into the cells of `footprint`, first moving the particles found there as given
+    // by `room`, and returns the total density of the particles moved. Cells of particles with
+    // nowhere to go are left out of the body. Cells that weren't part of the body before (`old`)
+    // wake their chunks.
+    fn stamp(&mut self, body : &mut Body, footprint : &[(i64, i64)], room : &[(usize, Option<usize>)], old : &[usize]) -> f32 {
+        let mut displaced = 0.0;
+        for &(c, to) in room {
+            let Some(n) = to else { continue };
+            let p = self.grid[c];
+            displaced += self.materials[p].density;
+            self.grid[n] = p;
+            self.velocity[n] = [0.0; 2];
+            self.temperature[n] = self.temperature[c];
+            self.shade[n] = self.shade[c];
+            self.grid[c] = Particle::AIR;
+            self.mark(n);
+        }
+        body.cells.clear();
+        for &(x, y) in footprint {
+            if !self.in_grid(x, y) { continue; }
+            let c = y as usize * self.width + x as usize;
+            if self.grid[c] != Particle::AIR { continue; }
+            self.grid[c] = body.particle;
+            self.velocity[c] = [0.0; 2];
+            body.cells.push(c);
+            if old.binary_search(&c).is_err() { self.mark(c); }
+        }
+        displaced
+    }
+
+    // Whether a body can't move into a cell; powders only stop bodies too slow to dig into them
+    fn blocks(&self, (x, y) : (i64, i64), digging : bool) -> bool {
+        if !self.in_grid(x, y) { return true; }
+        match self.materials[self.grid[y as usize * self.width + x as usize]].state {
+            State::Solid => true,
+            State::Powder => !digging,
+            State::Liquid | State::Gas => false
+        }
+    }
+
+    fn in_grid(&self, x : i64, y : i64) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+    }
+
+    // Wakes the chunks around a cell changed by a body, for the next tick
+    fn mark(&mut self, c : usize) {
+        self.wake_heat(c % self.width, c / self.width);
+        mark_around(&mut self.dirty, self.chunks_x, self.width, self.height, c % self.width, c / self.width);
+    }
+}
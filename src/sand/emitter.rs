@@ -0,0 +1,74 @@
+use rand::Rng;
+use crate::{SandWorld, Particle};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EmitterKind {
+    /// Fills cells with its material
+    Source,
+    /// Empties cells holding its material, or any material if it is air
+    Drain
+}
+
+/// A persistent source or sink of particles, acting on a horizontal strip of cells every tick
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Emitter {
+    pub kind : EmitterKind,
+    pub x : i32,
+    pub y : i32,
+    pub particle : Particle,
+    /// Cells affected per tick; a fractional part is a chance of one more. Above the number of cells
+    /// it reaches, every one of them is affected once per tick.
+    pub rate : f32,
+    /// How many cells to the left and right of `x` the emitter reaches
+    pub spread : i32
+}
+
+impl Emitter {
+    /// The default emitter: drops one particle per tick in a random column of the top row
+    pub fn spawner(world : &SandWorld, particle : Particle) -> Emitter {
+        let half = world.width() as i32 / 2;
+        Emitter { kind : EmitterKind::Source, x : half, y : world.height() as i32 - 1, particle, rate : 1.0, spread : half }
+    }
+
+    fn apply<R : Rng>(&self, world : &mut SandWorld, rng : &mut R) {
+        if self.y < 0 || self.y >= world.height() as i32 { return; }
+        // In i64 so no position or spread overflows
+        let (x, spread) = (self.x as i64, self.spread as i64);
+        let left = (x - spread).max(0);
+        let right = (x + spread).min(world.width() as i64 - 1);
+        if left > right { return; }
+        let (xs, y) = (left as usize..=right as usize, self.y as usize);
+        if self.rate > xs.clone().count() as f32 {
+            for x in xs { self.affect(world, x, y); }
+            return;
+        }
+        let mut count = self.rate.max(0.0).floor() as usize;
+        let extra = self.rate.fract();
+        if extra > 0.0 && rng.gen::<f32>() < extra { count += 1; }
+        for _ in 0..count {
+            let x = rng.gen_range(xs.clone());
+            self.affect(world, x, y);
+        }
+    }
+
+    fn affect(&self, world : &mut SandWorld, x : usize, y : usize) {
+        match self.kind {
+            EmitterKind::Source => world.set(x, y, self.particle),
+            EmitterKind::Drain => if self.particle == Particle::AIR || world.get(x, y) == self.particle {
+                world.set(x, y, Particle::AIR);
+            }
+        }
+    }
+}
+
+impl SandWorld {
+    pub fn emitters(&self) -> &[Emitter] { &self.emitters }
+    pub fn emitters_mut(&mut self) -> &mut Vec<Emitter> { &mut self.emitters }
+
+    /// Runs every emitter once, in order
+    pub fn emit<R : Rng>(&mut self, rng : &mut R) {
+        let emitters = std::mem::take(&mut self.emitters);
+        for e in &emitters { e.apply(self, rng); }
+        self.emitters = emitters;
+    }
+}
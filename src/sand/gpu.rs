@@ -0,0 +1,198 @@
+//! Runs `SandWorld::step_margolus` on the GPU.
+//!
+//! The grid lives in a pair of integer textures. Each tick a fragment shader computes the new
+//! particle of every cell from its Margolus block in one texture, rendering into the other, and the
+//! two swap roles. The result is read back into the world after every tick, so painting, emitters,
+//! saving and drawing work the same as with the CPU backends.
+
+use std::{borrow::Cow, sync::Arc};
+use glium::{
+    Surface,
+    backend::Facade,
+    framebuffer::SimpleFrameBuffer,
+    texture::{RawImage2d, ClientFormat, UnsignedTexture2d, UncompressedUintFormat, UncompressedFloatFormat, MipmapsOption, Texture1d},
+    uniforms::{MagnifySamplerFilter, MinifySamplerFilter},
+};
+use rand::Rng;
+use crate::{SandWorld, Particle, MaterialRegistry};
+use crate::margolus::material_table;
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position : [f32; 2]
+}
+glium::implement_vertex!(Vertex, position);
+
+const VERT_SRC : &str = r#"
+    #version 140
+    in vec2 position;
+    void main() {
+        gl_Position = vec4(position, 0, 1);
+    }
+"#;
+
+// Mirrors `margolus::update_block`; see there for the rules
+const FRAG_SRC : &str = r#"
+    #version 140
+    out uint next;
+    uniform usampler2D cells;
+    // Density and kind of each material
+    uniform sampler1D table;
+    uniform uint seed;
+    uniform int offset;
+
+    const uint OUTSIDE = 256u;
+    const uint FIXED = 0u;
+    const uint SINKS = 2u;
+    const uint FLOWS = 3u;
+    const uint RISES = 4u;
+
+    uint hash(uint x) {
+        x ^= x >> 16;
+        x *= 0x7feb352du;
+        x ^= x >> 15;
+        x *= 0x846ca68bu;
+        return x ^ (x >> 16);
+    }
+    uint fetch(ivec2 cell) {
+        ivec2 size = textureSize(cells, 0);
+        if (any(lessThan(cell, ivec2(0))) || any(greaterThanEqual(cell, size))) { return OUTSIDE; }
+        return texelFetch(cells, cell, 0).r;
+    }
+    vec2 props(uint p) {
+        return p < uint(textureSize(table, 0)) ? texelFetch(table, int(p), 0).rg : vec2(0, FIXED);
+    }
+    bool falls(uint top, uint bottom) {
+        vec2 t = props(top), b = props(bottom);
+        uint kt = uint(t.y), kb = uint(b.y);
+        return ((kt == SINKS || kt == FLOWS) && kb != FIXED && t.x > b.x) || (kb == RISES && kt != FIXED && b.x < t.x);
+    }
+    bool flows(uint a, uint b) {
+        vec2 pa = props(a), pb = props(b);
+        uint ka = uint(pa.y), kb = uint(pb.y);
+        return kb != FIXED && ((ka == FLOWS && pa.x > pb.x) || (ka == RISES && pa.x < pb.x));
+    }
+    void main() {
+        ivec2 shifted = ivec2(gl_FragCoord.xy) + offset;
+        ivec2 block = shifted / 2;
+        ivec2 origin = block * 2 - offset;
+        uint c[4] = uint[4](fetch(origin), fetch(origin + ivec2(1, 0)), fetch(origin + ivec2(0, 1)), fetch(origin + ivec2(1, 1)));
+        uint random = hash(seed + hash(uint(block.x) + hash(uint(block.y))));
+        uint swap;
+        bool moved = false;
+        for (int dx = 0; dx < 2; dx++) {
+            if (falls(c[2 + dx], c[dx])) {
+                swap = c[2 + dx]; c[2 + dx] = c[dx]; c[dx] = swap;
+                moved = true;
+            }
+        }
+        if (!moved) {
+            int first = int(random & 1u);
+            for (int k = 0; k < 2 && !moved; k++) {
+                int dx = k == 0 ? first : 1 - first;
+                if (falls(c[2 + dx], c[1 - dx])) {
+                    swap = c[2 + dx]; c[2 + dx] = c[1 - dx]; c[1 - dx] = swap;
+                    moved = true;
+                }
+            }
+        }
+        if (!moved) {
+            for (int row = 0; row < 4; row += 2) {
+                bool coin = ((random >> uint(1 + row / 2)) & 1u) == 1u;
+                if (coin && (flows(c[row], c[row + 1]) || flows(c[row + 1], c[row]))) {
+                    swap = c[row]; c[row] = c[row + 1]; c[row + 1] = swap;
+                }
+            }
+        }
+        next = c[(shifted.y % 2) * 2 + shifted.x % 2];
+    }
+"#;
+
+/// Advances a `SandWorld` with the Margolus rules on the GPU, with the same results as
+/// `SandWorld::step_margolus`
+pub struct GpuStepper {
+    program : glium::Program,
+    vertex_buffer : glium::VertexBuffer<Vertex>,
+    // The grid before and after the tick being computed
+    cells : [UnsignedTexture2d; 2],
+    table : Texture1d,
+    // Registry the table was built from, to rebuild it when the world's materials change
+    table_materials : Option<Arc<MaterialRegistry>>,
+    // The grid as last read back, to tell when the world was changed in between and needs uploading
+    cell_data : Vec<u8>
+}
+
+impl GpuStepper {
+    pub fn new<F : Facade>(facade : &F) -> GpuStepper {
+        let vertex_buffer = glium::VertexBuffer::new(facade, &[
+            Vertex { position : [-1.0, -1.0] },
+            Vertex { position : [1.0, -1.0] },
+            Vertex { position : [1.0, 1.0] },
+            Vertex { position : [1.0, 1.0] },
+            Vertex { position : [-1.0, 1.0] },
+            Vertex { position : [-1.0, -1.0] },
+        ]).expect("Error creating vertex buffer");
+        let program = glium::Program::from_source(facade, VERT_SRC, FRAG_SRC, None)
+            .expect("Error compiling Margolus shader program");
+        let empty = || UnsignedTexture2d::empty_with_format(facade, UncompressedUintFormat::U8, MipmapsOption::NoMipmap, 1, 1)
+            .expect("Error creating cell texture");
+        let table = Texture1d::empty_with_format(facade, UncompressedFloatFormat::F32F32, MipmapsOption::NoMipmap, 1)
+            .expect("Error creating material table texture");
+        GpuStepper { program, vertex_buffer, cells : [empty(), empty()], table, table_materials : None, cell_data : Vec::new() }
+    }
+
+    /// Advances `world` by one tick; takes the same number from `rng` as `SandWorld::step_margolus`
+    pub fn step<F : Facade, R : Rng>(&mut self, facade : &F, world : &mut SandWorld, rng : &mut R) {
+        let seed : u32 = rng.gen();
+        let (width, height) = (world.width() as u32, world.height() as u32);
+        if !self.table_materials.as_ref().is_some_and(|m| Arc::ptr_eq(m, world.materials())) {
+            let table : Vec<(f32, f32)> = material_table(world.materials()).into_iter().map(|(density, kind)| (density, kind as f32)).collect();
+            self.table = Texture1d::with_format(facade, table, UncompressedFloatFormat::F32F32, MipmapsOption::NoMipmap)
+                .expect("Error creating material table texture");
+            self.table_materials = Some(world.materials().clone());
+        }
+        let changed = self.cells[0].dimensions() != (width, height) || self.cell_data.len() != world.cells().len()
+            || world.cells().iter().zip(&self.cell_data).any(|(p, &q)| p.index() != q);
+        if changed {
+            self.cell_data.clear();
+            self.cell_data.extend(world.cells().iter().map(|p| p.index()));
+            let image = RawImage2d { data : Cow::Borrowed(&self.cell_data[..]), width, height, format : ClientFormat::U8 };
+            self.cells[0] = UnsignedTexture2d::with_format(facade, image, UncompressedUintFormat::U8, MipmapsOption::NoMipmap)
+                .expect("Error creating cell texture");
+            self.cells[1] = UnsignedTexture2d::empty_with_format(facade, UncompressedUintFormat::U8, MipmapsOption::NoMipmap, width, height)
+                .expect("Error creating cell texture");
+        }
+
+        let uniforms = glium::uniform!{
+            cells : self.cells[0].sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            table : self.table.sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            seed : seed,
+            offset : (seed & 1) as i32
+        };
+        let mut target = SimpleFrameBuffer::new(facade, &self.cells[1]).expect("Error creating Margolus framebuffer");
+        let indices = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
+        target.draw(&self.vertex_buffer, indices, &self.program, &uniforms, &Default::default())
+            .expect("Error running Margolus shader");
+        drop(target);
+        self.cells.swap(0, 1);
+
+        // Read back as RGBA, whose rows never need padding
+        let rect = glium::Rect { left : 0, bottom : 0, width, height };
+        let image = self.cells[0].main_level().first_layer().into_image(None).expect("Error reading cell texture");
+        let rows : Vec<Vec<(u8, u8, u8, u8)>> = image.raw_read(&rect);
+        world.moved = 0;
+        for (c, p) in rows.iter().flatten().map(|&(p, _, _, _)| p).enumerate() {
+            if world.grid[c].index() != p {
+                world.grid[c] = Particle(p);
+                world.moved += 1;
+            }
+        }
+        self.cell_data.clear();
+        self.cell_data.extend(world.cells().iter().map(|p| p.index()));
+        world.dirty.fill(true);
+    }
+}
@@ -0,0 +1,72 @@
+//! Direction and strength of gravity: a vector for the whole world, plus wells that pull particles
+//! within their radius towards their center.
+//!
+//! Material moves are written for gravity pointing down (`dy = -1`). Each cell turns them by the
+//! multiple of 45 degrees nearest to its gravity, so sand piles up against whichever side gravity
+//! points to and gases rise away from it. Falling particles accelerate along the exact vector.
+//! Without gravity, particles keep the speed they already have but no longer fall or flow.
+
+use glam::Vec2;
+use crate::SandWorld;
+
+/// Pulls everything within `radius` cells of `(x, y)` towards it with `strength` cells per tick
+/// squared, on top of the world's gravity
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct GravityWell {
+    pub x : i32,
+    pub y : i32,
+    pub radius : f32,
+    pub strength : f32
+}
+
+/// Gravity at `at`, in grid coordinates
+pub(crate) fn gravity_at(gravity : Vec2, wells : &[GravityWell], at : Vec2) -> Vec2 {
+    wells.iter().fold(gravity, |g, well| {
+        let to_center = Vec2::new(well.x as f32, well.y as f32) - at;
+        let distance = to_center.length();
+        if distance > 0.0 && distance <= well.radius { g + to_center * (well.strength / distance) } else { g }
+    })
+}
+
+/// Number of 45 degree counter-clockwise turns from down to the direction nearest to `g`, or `None`
+/// without gravity
+pub(crate) fn turns(g : Vec2) -> Option<usize> {
+    if g == Vec2::ZERO { return None; }
+    let angle = g.x.atan2(-g.y);
+    Some((angle / std::f32::consts::FRAC_PI_4).round().rem_euclid(8.0) as usize % 8)
+}
+
+// Neighbor offsets in counter-clockwise order, so turning one by 45 degrees moves it to the next
+const RING : [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0)];
+
+/// Neighbor offset `(dx, dy)` turned `turns` times by 45 degrees counter-clockwise
+pub(crate) fn turn((dx, dy) : (i32, i32), turns : usize) -> (i32, i32) {
+    match RING.iter().position(|&d| d == (dx, dy)) {
+        Some(k) => RING[(k + turns) % RING.len()],
+        None => (dx, dy)
+    }
+}
+
+impl SandWorld {
+    /// Gravity everywhere outside of wells, in cells per tick squared
+    pub fn gravity(&self) -> Vec2 { self.gravity }
+
+    /// Changes the gravity, waking every chunk since settled particles may start moving again
+    pub fn set_gravity(&mut self, gravity : Vec2) {
+        self.gravity = gravity;
+        self.dirty.fill(true);
+    }
+
+    pub fn wells(&self) -> &[GravityWell] { &self.wells }
+
+    /// The gravity wells, for changing them; wakes every chunk like `set_gravity`
+    pub fn wells_mut(&mut self) -> &mut Vec<GravityWell> {
+        self.dirty.fill(true);
+        &mut self.wells
+    }
+
+    /// Gravity at the cell `(x, y)`, including wells
+    pub fn gravity_at(&self, x : usize, y : usize) -> Vec2 {
+        gravity_at(self.gravity, &self.wells, Vec2::new(x as f32, y as f32))
+    }
+}
@@ -0,0 +1,163 @@
+//! Temperature field of a `SandWorld`.
+//!
+//! Every cell has a temperature that is exchanged with its 4 neighbors each tick, at a rate given
+//! by the average conductivity of the two materials. The exchange is symmetric, so the total heat
+//! is conserved and the grid edges are insulated. Afterwards cells whose temperature crossed their
+//! material's `above` or `below` threshold change phase.
+//!
+//! Like movement, heat is updated per chunk, on the pool's threads. A chunk is skipped once no cell
+//! in it changes noticeably any more, until a particle or temperature changes around it. Heat only
+//! flows between chunks that are both updated, so sleeping chunks keep their heat; a noticeable
+//! flow into a sleeping chunk wakes it for the next tick.
+
+use rayon::prelude::*;
+use crate::{SandWorld, Particle, MaterialRegistry, CHUNK_SIZE};
+use crate::step::mark_around;
+use crate::pool::pool;
+
+// Largest temperature change in a tick that still counts as settled
+const SETTLED : f32 = 1e-3;
+
+/// Next temperatures of one chunk, kept between ticks to reuse the allocation
+#[derive(Default, Clone)]
+pub(crate) struct HeatChunk {
+    next : Vec<f32>,
+    /// Whether the chunk was updated this tick
+    updated : bool,
+    /// Whether its temperatures, or the flow into a neighbor that was skipped, changed noticeably
+    changed : bool
+}
+
+impl SandWorld {
+    /// Temperature of the cell at `(x, y)`
+    pub fn temperature(&self, x : usize, y : usize) -> f32 { self.temperature[y * self.width + x] }
+
+    pub fn set_temperature(&mut self, x : usize, y : usize, t : f32) {
+        self.temperature[y * self.width + x] = t;
+        self.wake_heat(x, y);
+    }
+
+    /// All temperatures, row by row from the bottom
+    pub fn temperatures(&self) -> &[f32] { &self.temperature }
+
+    /// Number of chunks whose heat `step` will update next tick; settled regions are skipped
+    pub fn awake_heat_chunks(&self) -> usize {
+        self.heat_dirty.iter().filter(|&&d| d).count()
+    }
+
+    // Marks the chunks around a cell whose particle or temperature changed, so heat is updated there
+    pub(crate) fn wake_heat(&mut self, x : usize, y : usize) {
+        mark_around(&mut self.heat_dirty, self.chunks_x, self.width, self.height, x, y);
+    }
+
+    // Diffuses heat for one tick in the chunks that aren't settled and applies the resulting phase changes
+    pub(crate) fn update_heat(&mut self) {
+        let (width, height, chunks_x) = (self.width, self.height, self.chunks_x);
+        let awake = self.heat_dirty.iter().filter(|&&d| d).count();
+        if awake == 0 { return; }
+        let (grid, temperature, materials, dirty) = (&self.grid[..], &self.temperature[..], &*self.materials, &self.heat_dirty[..]);
+        let run = |(c, chunk) : (usize, &mut HeatChunk)| {
+            chunk.updated = dirty[c];
+            if !chunk.updated { return; }
+            let area = Area { grid, temperature, materials, dirty, width, height, chunks_x };
+            chunk.changed = area.diffuse(c, &mut chunk.next);
+        };
+        match (self.threads > 1 && awake > 1).then(|| pool(self.threads)) {
+            Some(pool) => pool.install(|| self.heat_chunks.par_iter_mut().enumerate().for_each(run)),
+            None => self.heat_chunks.iter_mut().enumerate().for_each(run)
+        }
+
+        self.heat_dirty.fill(false);
+        let chunks_y = height.div_ceil(CHUNK_SIZE);
+        for c in 0..self.heat_chunks.len() {
+            if !self.heat_chunks[c].updated { continue; }
+            let (xs, ys) = chunk_cells(c, chunks_x, width, height);
+            let next = std::mem::take(&mut self.heat_chunks[c].next);
+            for (row, y) in next.chunks(xs.len()).zip(ys) {
+                self.temperature[y * width + xs.start..y * width + xs.end].copy_from_slice(row);
+            }
+            self.heat_chunks[c].next = next;
+            if self.heat_chunks[c].changed {
+                let (cx, cy) = (c % chunks_x, c / chunks_x);
+                for ny in cy.saturating_sub(1)..=(cy + 1).min(chunks_y - 1) {
+                    for nx in cx.saturating_sub(1)..=(cx + 1).min(chunks_x - 1) { self.heat_dirty[ny * chunks_x + nx] = true; }
+                }
+            }
+        }
+
+        for c in 0..self.heat_chunks.len() {
+            if !self.heat_chunks[c].updated { continue; }
+            let (xs, ys) = chunk_cells(c, chunks_x, width, height);
+            for y in ys {
+                for x in xs.clone() {
+                    let i = y * width + x;
+                    let (p, t) = (self.grid[i], self.temperature[i]);
+                    let m = &self.materials[p];
+                    let into = match (m.above, m.below) {
+                        (Some(above), _) if t > above.at => above.into,
+                        (_, Some(below)) if t < below.at => below.into,
+                        _ => continue
+                    };
+                    self.change_phase(i, into);
+                }
+            }
+        }
+    }
+
+    fn change_phase(&mut self, i : usize, into : Particle) {
+        let (x, y) = (i % self.width, i / self.width);
+        self.grid[i] = into;
+        self.velocity[i] = [0.0; 2];
+        self.wake_heat(x, y);
+        mark_around(&mut self.dirty, self.chunks_x, self.width, self.height, x, y);
+    }
+}
+
+// Cell ranges covered by the chunk `c`
+fn chunk_cells(c : usize, chunks_x : usize, width : usize, height : usize) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+    let (cx, cy) = (c % chunks_x, c / chunks_x);
+    ((cx * CHUNK_SIZE)..((cx + 1) * CHUNK_SIZE).min(width), (cy * CHUNK_SIZE)..((cy + 1) * CHUNK_SIZE).min(height))
+}
+
+// Read-only view of the temperature field while diffusing, shared between threads
+struct Area<'a> {
+    grid : &'a [Particle],
+    temperature : &'a [f32],
+    materials : &'a MaterialRegistry,
+    /// Chunks updated this tick
+    dirty : &'a [bool],
+    width : usize,
+    height : usize,
+    chunks_x : usize
+}
+
+impl Area<'_> {
+    // Computes the next temperature of every cell in the chunk `c`, row by row, into `next`.
+    // Returns whether any of them changed noticeably or would have exchanged a noticeable amount of
+    // heat with a chunk that isn't updated.
+    fn diffuse(&self, c : usize, next : &mut Vec<f32>) -> bool {
+        let (width, height, materials) = (self.width, self.height, self.materials);
+        let (xs, ys) = chunk_cells(c, self.chunks_x, width, height);
+        next.clear();
+        let mut changed = false;
+        for y in ys {
+            for x in xs.clone() {
+                let i = y * width + x;
+                let (t, k) = (self.temperature[i], materials[self.grid[i]].conductivity);
+                let mut flow = 0.0;
+                let mut exchange = |j : usize| {
+                    let f = 0.125 * (k + materials[self.grid[j]].conductivity) * (self.temperature[j] - t);
+                    let (jx, jy) = (j % width, j / width);
+                    if self.dirty[(jy / CHUNK_SIZE) * self.chunks_x + jx / CHUNK_SIZE] { flow += f; } else { changed |= f.abs() >= SETTLED; }
+                };
+                if x > 0 { exchange(i - 1); }
+                if x + 1 < width { exchange(i + 1); }
+                if y > 0 { exchange(i - width); }
+                if y + 1 < height { exchange(i + width); }
+                next.push(t + flow);
+                changed |= flow.abs() >= SETTLED;
+            }
+        }
+        changed
+    }
+}
@@ -0,0 +1,291 @@
+//! Undo and redo of user edits.
+//!
+//! An edit is kept as the cells it changed, each with its value from before and after the edit,
+//! plus the emitters, gravity wells, gravity or bodies if it changed those. Undoing returns a
+//! `Command::Patch` setting them back, so it is recorded and replayed like any other change, and
+//! the rest of the world stays as the simulation made it since. Loading and resizing replace the
+//! whole grid, so for those the world is kept as a keyframe in the `.sand` format instead, and
+//! undoing returns a `Command::Load` of it. Edits of the same kind in consecutive ticks, like a
+//! paint stroke or a dragged slider, make a single step. The oldest steps are dropped to stay
+//! within a memory budget.
+//!
+//! Restored cells get the temperature of their material and no velocity. Restoring the bodies puts
+//! them back where they were when the edit was made. Keyframes hold particles, emitters and
+//! bodies; temperatures and particle velocities restart from their defaults when restored.
+
+use std::{collections::VecDeque, mem::{discriminant, size_of, Discriminant}};
+use glam::Vec2;
+use crate::{SandWorld, Particle, Simulation, Command, Emitter, GravityWell, Body};
+
+/// Cells to set and settings to replace in a world, see `Command::Patch`
+#[derive(Clone, Default)]
+pub struct Patch {
+    // Grid index and particle of every cell to set, in ascending order
+    pub(crate) cells : Vec<(usize, Particle)>,
+    pub(crate) emitters : Option<Vec<Emitter>>,
+    pub(crate) wells : Option<Vec<GravityWell>>,
+    pub(crate) gravity : Option<Vec2>,
+    pub(crate) bodies : Option<Vec<Body>>
+}
+
+impl Patch {
+    pub(crate) fn apply(&self, world : &mut SandWorld) {
+        if self.bodies.is_some() { world.lift_bodies(); }
+        for &(c, p) in &self.cells {
+            // Patches read from replays may not fit the grid
+            if c < world.grid.len() { world.set(c % world.width, c / world.width, p); }
+        }
+        if let Some(emitters) = &self.emitters { *world.emitters_mut() = emitters.clone(); }
+        if let Some(wells) = &self.wells { *world.wells_mut() = wells.clone(); }
+        if let Some(gravity) = self.gravity { world.set_gravity(gravity); }
+        for body in self.bodies.iter().flatten() { world.place_body(body.clone()); }
+    }
+
+    // Joins a patch applied after this one, keeping what was set last
+    fn then(&mut self, later : Patch) {
+        self.cells = combine(later.cells, std::mem::take(&mut self.cells));
+        self.emitters = later.emitters.or(self.emitters.take());
+        self.wells = later.wells.or(self.wells.take());
+        self.gravity = later.gravity.or(self.gravity);
+        self.bodies = later.bodies.or(self.bodies.take());
+    }
+
+    // Adds what `other` sets and this patch doesn't
+    fn fill(&mut self, other : Patch) {
+        self.cells = combine(std::mem::take(&mut self.cells), other.cells);
+        self.emitters = self.emitters.take().or(other.emitters);
+        self.wells = self.wells.take().or(other.wells);
+        self.gravity = self.gravity.or(other.gravity);
+        self.bodies = self.bodies.take().or(other.bodies);
+    }
+
+    fn bytes(&self) -> usize {
+        let bodies = self.bodies.iter().flatten().map(|b| size_of::<Body>() + b.shape.len() * size_of::<Vec2>() + b.cells.len() * size_of::<usize>());
+        self.cells.len() * size_of::<(usize, Particle)>()
+            + self.emitters.as_ref().map_or(0, |e| e.len() * size_of::<Emitter>())
+            + self.wells.as_ref().map_or(0, |w| w.len() * size_of::<GravityWell>())
+            + bodies.sum::<usize>()
+    }
+}
+
+// Cells set by two patches, taking those of `first` where both set a cell
+fn combine(first : Vec<(usize, Particle)>, second : Vec<(usize, Particle)>) -> Vec<(usize, Particle)> {
+    let mut cells = first;
+    cells.extend(second);
+    // The sort is stable, so of the cells with the same index the one from `first` comes first
+    cells.sort_by_key(|&(c, _)| c);
+    cells.dedup_by_key(|&mut (c, _)| c);
+    cells
+}
+
+enum Step {
+    // The world before loading or resizing, as a `.sand` file
+    Keyframe(Vec<u8>),
+    Edit { undo : Patch, redo : Patch }
+}
+
+impl Step {
+    // Command undoing the step, or redoing it; `None` if its keyframe can't be read
+    fn command(&self, world : &SandWorld, undo : bool) -> Option<Command> {
+        match self {
+            Step::Keyframe(state) => {
+                SandWorld::load_with_materials(&mut &state[..], world.materials().clone()).ok().map(|world| Command::Load(Box::new(world)))
+            }
+            Step::Edit { undo : patch, .. } if undo => Some(Command::Patch(Box::new(patch.clone()))),
+            Step::Edit { redo : patch, .. } => Some(Command::Patch(Box::new(patch.clone())))
+        }
+    }
+
+    // The step reverting its own command, given the world before that command is applied
+    fn reversed(self, world : &SandWorld) -> Step {
+        match self {
+            Step::Keyframe(_) => Step::Keyframe(keyframe(world)),
+            edit => edit
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        match self {
+            Step::Keyframe(state) => state.len(),
+            Step::Edit { undo, redo } => undo.bytes() + redo.bytes()
+        }
+    }
+}
+
+// What an edit may change, taken before applying it
+struct Before {
+    // Bounds of the cells the edit may change, left and bottom inclusive, and their particles row by row
+    region : (usize, usize, usize, usize),
+    cells : Vec<Particle>,
+    emitters : Vec<Emitter>,
+    wells : Vec<GravityWell>,
+    gravity : Vec2,
+    // Only kept for edits adding or removing bodies
+    bodies : Option<Vec<Body>>
+}
+
+impl Before {
+    fn take(command : &Command, world : &SandWorld) -> Before {
+        let (width, height) = (world.width() as i64, world.height() as i64);
+        let clip = |x0 : i64, y0 : i64, x1 : i64, y1 : i64| {
+            let (x0, y0) = (x0.clamp(0, width) as usize, y0.clamp(0, height) as usize);
+            (x0, y0, (x1.clamp(0, width) as usize).max(x0), (y1.clamp(0, height) as usize).max(y0))
+        };
+        let region = match command {
+            Command::Paint { from, to, radius, .. } => {
+                let r = *radius as i64;
+                let (x0, x1) = (from.0.min(to.0) as i64, from.0.max(to.0) as i64);
+                let (y0, y1) = (from.1.min(to.1) as i64, from.1.max(to.1) as i64);
+                clip(x0 - r, y0 - r, x1 + r + 1, y1 + r + 1)
+            }
+            Command::Erase { x, y, width, height } => clip(*x as i64, *y as i64, *x as i64 + *width as i64, *y as i64 + *height as i64),
+            Command::Paste { x, y, stamp } => clip(*x as i64, *y as i64, *x as i64 + stamp.width() as i64, *y as i64 + stamp.height() as i64),
+            Command::Clear | Command::AddBody(_) => clip(0, 0, width, height),
+            _ => (0, 0, 0, 0)
+        };
+        let (x0, y0, x1, y1) = region;
+        Before {
+            region,
+            cells : (y0..y1).flat_map(|y| &world.cells()[y * world.width() + x0..y * world.width() + x1]).copied().collect(),
+            emitters : world.emitters().to_vec(),
+            wells : world.wells().to_vec(),
+            gravity : world.gravity(),
+            bodies : matches!(command, Command::Clear | Command::AddBody(_)).then(|| world.bodies().to_vec())
+        }
+    }
+
+    // Patches undoing and redoing the edit, given the world after it
+    fn diff(self, world : &SandWorld) -> Step {
+        let (x0, y0, x1, y1) = self.region;
+        let (mut undo, mut redo) = (Patch::default(), Patch::default());
+        let indices = (y0..y1).flat_map(|y| (x0..x1).map(move |x| (x, y)));
+        for ((x, y), old) in indices.zip(self.cells) {
+            let new = world.get(x, y);
+            if new != old {
+                undo.cells.push((y * world.width() + x, old));
+                redo.cells.push((y * world.width() + x, new));
+            }
+        }
+        if self.emitters != world.emitters() {
+            (undo.emitters, redo.emitters) = (Some(self.emitters), Some(world.emitters().to_vec()));
+        }
+        if self.wells != world.wells() {
+            (undo.wells, redo.wells) = (Some(self.wells), Some(world.wells().to_vec()));
+        }
+        if self.gravity != world.gravity() {
+            (undo.gravity, redo.gravity) = (Some(self.gravity), Some(world.gravity()));
+        }
+        if let Some(bodies) = self.bodies.filter(|b| b != world.bodies()) {
+            (undo.bodies, redo.bodies) = (Some(bodies), Some(world.bodies().to_vec()));
+        }
+        Step::Edit { undo, redo }
+    }
+}
+
+pub struct History {
+    undo : VecDeque<(Discriminant<Command>, Step)>,
+    redo : Vec<(Discriminant<Command>, Step)>,
+    /// Largest number of bytes kept in steps
+    budget : usize,
+    // Kinds of edits made during the current tick and the previous one
+    current : Vec<Discriminant<Command>>,
+    previous : Vec<Discriminant<Command>>
+}
+
+impl History {
+    pub fn new(budget : usize) -> History {
+        History { undo : VecDeque::new(), redo : Vec::new(), budget, current : Vec::new(), previous : Vec::new() }
+    }
+
+    /// Applies `command` to `simulation`, keeping what it changes so it can be undone. Edits of the
+    /// same kind as one from this tick or the previous one join its step; others start a new step
+    /// and clear the redo steps.
+    pub fn apply(&mut self, simulation : &mut Simulation, command : Command) {
+        if matches!(command, Command::Pause(_) | Command::Step) {
+            simulation.apply(command);
+            return;
+        }
+        let kind = discriminant(&command);
+        let continued = self.current.contains(&kind) || self.previous.contains(&kind);
+        if !self.current.contains(&kind) { self.current.push(kind); }
+        let step = self.undo.iter().rposition(|(k, _)| *k == kind).filter(|_| continued);
+        if matches!(command, Command::Load(_) | Command::Resize { .. }) {
+            if step.is_none() { self.push(kind, Step::Keyframe(keyframe(simulation.world()))); }
+            simulation.apply(command);
+        } else {
+            let before = Before::take(&command, simulation.world());
+            simulation.apply(command);
+            let edit = before.diff(simulation.world());
+            match (step.map(|i| &mut self.undo[i].1), edit) {
+                (Some(Step::Edit { undo, redo }), Step::Edit { undo : later_undo, redo : later_redo }) => {
+                    // Undoing goes back to before the first edit of the step, redoing to after the last
+                    undo.fill(later_undo);
+                    redo.then(later_redo);
+                }
+                (_, edit) => self.push(kind, edit)
+            }
+        }
+        self.trim();
+    }
+
+    /// Ends a tick, so edits in the tick after next start new steps
+    pub fn end_tick(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// Command reverting the last edit, if any
+    pub fn undo(&mut self, world : &SandWorld) -> Option<Command> {
+        let command = self.undo.back()?.1.command(world, true)?;
+        let (kind, step) = self.undo.pop_back()?;
+        self.redo.push((kind, step.reversed(world)));
+        self.end_edits();
+        Some(command)
+    }
+
+    /// Command making the last undone edit again, if any
+    pub fn redo(&mut self, world : &SandWorld) -> Option<Command> {
+        let command = self.redo.last()?.1.command(world, false)?;
+        let (kind, step) = self.redo.pop()?;
+        self.undo.push_back((kind, step.reversed(world)));
+        self.end_edits();
+        Some(command)
+    }
+
+    pub fn undo_steps(&self) -> usize { self.undo.len() }
+    pub fn redo_steps(&self) -> usize { self.redo.len() }
+
+    /// Memory held by steps, in bytes
+    pub fn bytes(&self) -> usize {
+        self.undo.iter().chain(&self.redo).map(|(_, step)| step.bytes()).sum()
+    }
+
+    fn push(&mut self, kind : Discriminant<Command>, step : Step) {
+        self.undo.push_back((kind, step));
+        self.redo.clear();
+    }
+
+    // After undoing or redoing, the next edit starts a new step
+    fn end_edits(&mut self) {
+        self.trim();
+        self.current.clear();
+        self.previous.clear();
+    }
+
+    // Drops the oldest undo steps, then the furthest redo steps, until steps fit the budget
+    fn trim(&mut self) {
+        let mut bytes = self.bytes();
+        while bytes > self.budget {
+            let dropped = match self.undo.pop_front() {
+                Some((_, step)) => step,
+                None => self.redo.remove(0).1
+            };
+            bytes -= dropped.bytes();
+        }
+    }
+}
+
+fn keyframe(world : &SandWorld) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).expect("Error saving keyframe");
+    bytes
+}
@@ -0,0 +1,33 @@
+//! Headless falling-sand simulation, driven by the `falling-sand` binary.
+
+mod particle;
+mod material;
+mod world;
+mod step;
+mod pool;
+mod emitter;
+mod body;
+mod gravity;
+mod heat;
+mod save;
+mod replay;
+mod history;
+mod stamp;
+mod rules;
+mod margolus;
+pub mod gpu;
+pub mod render;
+
+pub use particle::Particle;
+pub use material::{MaterialRegistry, Material, MaterialError, State, Reaction, Decay, PhaseChange};
+pub use world::{SandWorld, Brush, MAX_SIZE};
+pub use step::{CHUNK_SIZE, GRAVITY, MAX_SPEED};
+pub use emitter::{Emitter, EmitterKind};
+pub use body::Body;
+pub use gravity::GravityWell;
+pub use save::WorldFileError;
+pub use replay::{Simulation, Replay, Command};
+pub use history::{History, Patch};
+pub use stamp::StampLibrary;
+
+pub use rules::{Rule, Proposal, Neighborhood, Life, LangtonsAnt};
\ No newline at end of file
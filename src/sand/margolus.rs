@@ -0,0 +1,124 @@
+//! Margolus-neighborhood update, a simpler alternative to `SandWorld::step` that also runs as a
+//! fragment shader, see `gpu::GpuStepper`.
+//!
+//! The grid is split into 2 x 2 blocks, shifted by one cell in both directions on a random half of
+//! the ticks, and every block is rearranged on its own: particles sink or rise through their column,
+//! else topple diagonally, else liquids and gases flow sideways. Each block takes its random choices
+//! from a hash of the tick's seed and its position, so blocks can be updated in any order, and the
+//! GPU gets exactly the same result. Only particles move; velocities, reactions, heat and bodies are
+//! left alone, cell shades stay in place, and gravity always points down.
+
+use rand::Rng;
+use crate::{SandWorld, Particle, MaterialRegistry, State};
+
+/// Never moves nor gets displaced, like solids and the outside of the grid
+pub(crate) const FIXED : u32 = 0;
+/// Gets displaced but doesn't move on its own, like air
+pub(crate) const IDLE : u32 = 1;
+/// Falls and piles up, like powders heavier than air
+pub(crate) const SINKS : u32 = 2;
+/// Falls and spreads sideways, like liquids heavier than air
+pub(crate) const FLOWS : u32 = 3;
+/// Moving materials lighter than air, which rise and spread sideways
+pub(crate) const RISES : u32 = 4;
+
+/// Stand-in particle index for cells outside the grid
+pub(crate) const OUTSIDE : u32 = 256;
+
+/// Density and behavior of every material, indexed by particle
+pub(crate) fn material_table(materials : &MaterialRegistry) -> Vec<(f32, u32)> {
+    let air = materials[Particle::AIR].density;
+    materials.iter().map(|(_, m)| {
+        let kind = match m.state {
+            State::Solid => FIXED,
+            _ if m.moves.is_empty() => IDLE,
+            _ if m.density < air => RISES,
+            State::Powder if m.density > air => SINKS,
+            State::Liquid if m.density > air => FLOWS,
+            _ => IDLE
+        };
+        (m.density, kind)
+    }).collect()
+}
+
+/// Integer hash (lowbias32), the same as in the shader
+pub(crate) fn hash(mut x : u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^ (x >> 16)
+}
+
+/// Random bits of the block `(bx, by)` in the tick with `seed`
+pub(crate) fn block_random(seed : u32, bx : u32, by : u32) -> u32 {
+    hash(seed.wrapping_add(hash(bx.wrapping_add(hash(by)))))
+}
+
+/// Rearranges the bottom-left, bottom-right, top-left and top-right cells of a block
+pub(crate) fn update_block(cells : &mut [u32; 4], table : &[(f32, u32)], random : u32) {
+    let props = |p : u32| table.get(p as usize).copied().unwrap_or((0.0, FIXED));
+    // Whether the particle on top and the one below it trade places
+    let falls = |top : u32, bottom : u32| {
+        let ((dt, kt), (db, kb)) = (props(top), props(bottom));
+        ((kt == SINKS || kt == FLOWS) && kb != FIXED && dt > db) || (kb == RISES && kt != FIXED && db < dt)
+    };
+    // Whether `a` flows sideways into `b`
+    let flows = |a : u32, b : u32| {
+        let ((da, ka), (db, kb)) = (props(a), props(b));
+        kb != FIXED && ((ka == FLOWS && da > db) || (ka == RISES && da < db))
+    };
+    let mut moved = false;
+    for dx in 0..2 {
+        if falls(cells[2 + dx], cells[dx]) {
+            cells.swap(2 + dx, dx);
+            moved = true;
+        }
+    }
+    if moved { return; }
+    let first = (random & 1) as usize;
+    for dx in [first, 1 - first] {
+        if falls(cells[2 + dx], cells[1 - dx]) {
+            cells.swap(2 + dx, 1 - dx);
+            return;
+        }
+    }
+    for row in [0, 2] {
+        let coin = (random >> (1 + row / 2)) & 1 == 1;
+        if coin && (flows(cells[row], cells[row + 1]) || flows(cells[row + 1], cells[row])) {
+            cells.swap(row, row + 1);
+        }
+    }
+}
+
+impl SandWorld {
+    /// Advances the simulation by one tick with the Margolus rules of this module instead of the
+    /// ones of `step`. Draws a single number from `rng`, as `GpuStepper::step` does.
+    pub fn step_margolus<R : Rng>(&mut self, rng : &mut R) {
+        let seed : u32 = rng.gen();
+        let table = material_table(&self.materials);
+        let offset = (seed & 1) as usize;
+        let (width, height) = (self.width, self.height);
+        self.moved = 0;
+        for by in 0..(height + offset).div_ceil(2) {
+            for bx in 0..(width + offset).div_ceil(2) {
+                // Cells of the block, with those past the edges of the grid as `OUTSIDE`
+                let cell = |k : usize| {
+                    let (x, y) = ((2 * bx + k % 2).wrapping_sub(offset), (2 * by + k / 2).wrapping_sub(offset));
+                    (x < width && y < height).then(|| y * width + x)
+                };
+                let before = [0, 1, 2, 3].map(|k| cell(k).map_or(OUTSIDE, |c| self.grid[c].index() as u32));
+                let mut after = before;
+                update_block(&mut after, &table, block_random(seed, bx as u32, by as u32));
+                for k in 0..4 {
+                    if let (Some(c), true) = (cell(k), after[k] != before[k]) {
+                        self.grid[c] = Particle(after[k] as u8);
+                        self.moved += 1;
+                    }
+                }
+            }
+        }
+        // Chunks weren't tracked, so let the next `step` look at everything
+        self.dirty.fill(true);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{fmt, ops::Index, str::FromStr};
+use crate::Particle;
+
+const DEFAULT_MATERIALS : &str = include_str!("../../assets/materials.txt");
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum State {
+    Powder,
+    Liquid,
+    Gas,
+    Solid
+}
+
+impl FromStr for State {
+    type Err = String;
+    fn from_str(s : &str) -> Result<State, String> {
+        match s {
+            "powder" => Ok(State::Powder),
+            "liquid" => Ok(State::Liquid),
+            "gas" => Ok(State::Gas),
+            "solid" => Ok(State::Solid),
+            _ => Err(format!("unknown state '{}'", s))
+        }
+    }
+}
+
+/// Transformation of a cell and one of its 8 neighbors when they touch
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Reaction {
+    /// Material of the neighbor that triggers the reaction
+    pub with : Particle,
+    /// What the reacting cell becomes
+    pub into : Particle,
+    /// What the neighbor becomes
+    pub other_into : Particle,
+    /// Probability per tick and neighbor
+    pub chance : f32
+}
+
+/// Spontaneous transformation of a cell, e.g. fire burning out into smoke
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Decay {
+    pub into : Particle,
+    /// Probability per tick
+    pub chance : f32
+}
+
+/// Transformation of a cell when its temperature crosses a threshold, e.g. water boiling into steam
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PhaseChange {
+    pub into : Particle,
+    /// Temperature the cell has to rise above, or fall below
+    pub at : f32
+}
+
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name : String,
+    pub color : [f32; 3],
+    pub density : f32,
+    pub state : State,
+    /// Groups of `(dx, dy)` neighbor offsets, tried in order; offsets within a group are tried in random order
+    pub moves : Vec<Vec<(i32, i32)>>,
+    pub reactions : Vec<Reaction>,
+    pub decay : Option<Decay>,
+    /// Temperature of newly placed cells
+    pub temperature : f32,
+    /// Share of the temperature difference exchanged with each neighbor per tick, between 0 and 1
+    pub conductivity : f32,
+    pub above : Option<PhaseChange>,
+    pub below : Option<PhaseChange>
+}
+
+#[derive(Debug)]
+pub struct MaterialError {
+    pub line : usize,
+    pub message : String
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for MaterialError { }
+
+fn parse_color(s : &str) -> Result<[f32; 3], String> {
+    let c : Vec<f32> = s.split_whitespace().map(|v| v.parse::<f32>()).collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid color '{}'", s))?;
+    match c[..] {
+        [r, g, b] => Ok([r, g, b]),
+        _ => Err(format!("color needs 3 components, got {}", c.len()))
+    }
+}
+
+fn parse_moves(s : &str) -> Result<Vec<Vec<(i32, i32)>>, String> {
+    s.split('|').map(|group| {
+        group.split_whitespace().map(|offset| {
+            let (dx, dy) = offset.split_once(',').ok_or(format!("invalid offset '{}'", offset))?;
+            let d = (dx.parse::<i32>(), dy.parse::<i32>());
+            match d {
+                (Ok(dx), Ok(dy)) if (dx, dy) != (0, 0) && dx.abs() <= 1 && dy.abs() <= 1 => Ok((dx, dy)),
+                _ => Err(format!("offset '{}' must be a neighbor like -1,0", offset))
+            }
+        }).collect::<Result<Vec<_>, String>>()
+    }).filter(|group| !matches!(group, Ok(g) if g.is_empty())).collect()
+}
+
+// Splits `value @ chance` into its parts, with the chance defaulting to 1
+fn parse_chance(s : &str) -> Result<(&str, f32), String> {
+    match s.split_once('@') {
+        Some((value, chance)) => match chance.trim().parse::<f32>() {
+            Ok(c) if (0.0..=1.0).contains(&c) => Ok((value.trim(), c)),
+            _ => Err(format!("chance '{}' must be between 0 and 1", chance.trim()))
+        },
+        None => Ok((s.trim(), 1.0))
+    }
+}
+
+// Splits `value @ temperature` into its parts
+fn parse_threshold(s : &str) -> Result<(&str, f32), String> {
+    let (value, at) = s.split_once('@').ok_or_else(|| format!("expected `material @ temperature`, got '{}'", s))?;
+    let at = at.trim().parse::<f32>().map_err(|_| format!("invalid temperature '{}'", at.trim()))?;
+    Ok((value.trim(), at))
+}
+
+// Material names referenced by a `react`, `decay`, `above` or `below` property, resolved once all sections are read
+enum Pending {
+    React { material : usize, line : usize, with : String, into : String, other_into : String, chance : f32 },
+    Decay { material : usize, line : usize, into : String, chance : f32 },
+    Phase { material : usize, line : usize, into : String, at : f32, above : bool }
+}
+
+/// Materials a `Particle` can refer to, indexed by `Particle::0`. Index 0 is always air.
+#[derive(Clone, Debug)]
+pub struct MaterialRegistry {
+    materials : Vec<Material>
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        MaterialRegistry::parse(DEFAULT_MATERIALS).expect("Invalid built-in material definitions")
+    }
+}
+
+impl Index<Particle> for MaterialRegistry {
+    type Output = Material;
+    fn index(&self, p : Particle) -> &Material { &self.materials[p.0 as usize] }
+}
+
+impl MaterialRegistry {
+    /// Parses material definitions in the format of `assets/materials.txt`
+    pub fn parse(src : &str) -> Result<MaterialRegistry, MaterialError> {
+        let mut materials : Vec<Material> = Vec::new();
+        let mut pending = Vec::new();
+        for (n, line) in src.lines().enumerate() {
+            let err = |message : String| MaterialError { line : n + 1, message };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() { continue; }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim();
+                if name.is_empty() { return Err(err("empty material name".into())); }
+                if materials.iter().any(|m| m.name == name) { return Err(err(format!("duplicate material '{}'", name))); }
+                if materials.is_empty() && name != "air" { return Err(err("the first material must be air".into())); }
+                if materials.len() == u8::MAX as usize + 1 { return Err(err("too many materials".into())); }
+                materials.push(Material {
+                    name : name.to_string(),
+                    color : [1.0, 0.0, 1.0],
+                    density : 1.0,
+                    state : State::Solid,
+                    moves : Vec::new(),
+                    reactions : Vec::new(),
+                    decay : None,
+                    temperature : 20.0,
+                    conductivity : 0.1,
+                    above : None,
+                    below : None
+                });
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| err(format!("expected `key = value`, got '{}'", line)))?;
+            let (key, value) = (key.trim(), value.trim());
+            let material = materials.last_mut().ok_or_else(|| err("property outside of a [material] section".into()))?;
+            match key {
+                "color" => material.color = parse_color(value).map_err(err)?,
+                "density" => material.density = value.parse().map_err(|_| err(format!("invalid density '{}'", value)))?,
+                "state" => material.state = value.parse().map_err(err)?,
+                "moves" => material.moves = parse_moves(value).map_err(err)?,
+                "react" => {
+                    let (value, chance) = parse_chance(value).map_err(err)?;
+                    let (with, into) = value.split_once("->").ok_or_else(|| err(format!("expected `neighbor -> self neighbor`, got '{}'", value)))?;
+                    let into : Vec<&str> = into.split_whitespace().collect();
+                    let [into, other_into] = into[..] else {
+                        return Err(err(format!("reaction needs two results, got {}", into.len())));
+                    };
+                    pending.push(Pending::React {
+                        material : materials.len() - 1, line : n + 1,
+                        with : with.trim().to_string(), into : into.to_string(), other_into : other_into.to_string(), chance
+                    });
+                }
+                "decay" => {
+                    let (into, chance) = parse_chance(value).map_err(err)?;
+                    pending.push(Pending::Decay { material : materials.len() - 1, line : n + 1, into : into.to_string(), chance });
+                }
+                "temperature" => material.temperature = value.parse().map_err(|_| err(format!("invalid temperature '{}'", value)))?,
+                "conductivity" => material.conductivity = match value.parse::<f32>() {
+                    Ok(c) if (0.0..=1.0).contains(&c) => c,
+                    _ => return Err(err(format!("conductivity '{}' must be between 0 and 1", value)))
+                },
+                "above" | "below" => {
+                    let (into, at) = parse_threshold(value).map_err(err)?;
+                    pending.push(Pending::Phase { material : materials.len() - 1, line : n + 1, into : into.to_string(), at, above : key == "above" });
+                }
+                _ => return Err(err(format!("unknown property '{}'", key)))
+            }
+        }
+        let find = |materials : &[Material], name : &str, line : usize| {
+            materials.iter().position(|m| m.name == name).map(|i| Particle(i as u8))
+                .ok_or_else(|| MaterialError { line, message : format!("unknown material '{}'", name) })
+        };
+        for p in pending {
+            match p {
+                Pending::React { material, line, with, into, other_into, chance } => {
+                    let reaction = Reaction {
+                        with : find(&materials, &with, line)?,
+                        into : find(&materials, &into, line)?,
+                        other_into : find(&materials, &other_into, line)?,
+                        chance
+                    };
+                    materials[material].reactions.push(reaction);
+                }
+                Pending::Decay { material, line, into, chance } => {
+                    materials[material].decay = Some(Decay { into : find(&materials, &into, line)?, chance });
+                }
+                Pending::Phase { material, line, into, at, above } => {
+                    let change = Some(PhaseChange { into : find(&materials, &into, line)?, at });
+                    if above { materials[material].above = change; } else { materials[material].below = change; }
+                }
+            }
+        }
+        if materials.is_empty() { return Err(MaterialError { line : 0, message : "no materials defined".into() }); }
+        Ok(MaterialRegistry { materials })
+    }
+
+    pub fn len(&self) -> usize { self.materials.len() }
+    pub fn is_empty(&self) -> bool { self.materials.is_empty() }
+
+    /// Every material paired with the particle referring to it
+    pub fn iter(&self) -> impl Iterator<Item = (Particle, &Material)> {
+        self.materials.iter().enumerate().map(|(i, m)| (Particle(i as u8), m))
+    }
+
+    /// Looks up a material by name
+    pub fn find(&self, name : &str) -> Option<Particle> {
+        self.materials.iter().position(|m| m.name == name).map(|i| Particle(i as u8))
+    }
+
+    /// Whether a particle of `src` may move into a cell holding `dst`
+    pub fn can_displace(&self, src : Particle, dst : Particle) -> bool {
+        let (s, d) = (&self[src], &self[dst]);
+        if src == dst || d.state == State::Solid { return false; }
+        let air = self.materials[0].density;
+        if s.density > air { s.density > d.density } else { s.density < d.density }
+    }
+}
@@ -0,0 +1,10 @@
+/// Contents of a cell: an index into the world's `MaterialRegistry`
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Particle(pub u8);
+
+impl Particle {
+    /// The empty cell, always the first material of a registry
+    pub const AIR : Particle = Particle(0);
+
+    pub fn index(self) -> u8 { self.0 }
+}
@@ -0,0 +1,14 @@
+//! Worker threads shared by every `SandWorld`, so updates don't spawn threads every tick.
+
+use std::sync::{Arc, Mutex};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// Pool with `threads` workers, created on first use and kept for the rest of the process
+pub(crate) fn pool(threads : usize) -> Arc<ThreadPool> {
+    static POOLS : Mutex<Vec<(usize, Arc<ThreadPool>)>> = Mutex::new(Vec::new());
+    let mut pools = POOLS.lock().expect("Error locking thread pools");
+    if let Some((_, pool)) = pools.iter().find(|(n, _)| *n == threads) { return pool.clone(); }
+    let pool = Arc::new(ThreadPoolBuilder::new().num_threads(threads).build().expect("Error creating thread pool"));
+    pools.push((threads, pool.clone()));
+    pool
+}
@@ -0,0 +1,308 @@
+use std::{borrow::Cow, sync::Arc, time::Instant};
+use glium::{
+    Surface,
+    backend::Facade,
+    texture::{RawImage2d, ClientFormat, UnsignedTexture2d, UncompressedUintFormat, UncompressedFloatFormat, MipmapsOption, Texture1d, Texture2d},
+    uniforms::{MagnifySamplerFilter, MinifySamplerFilter},
+};
+use glam::{Mat4, Vec2, Vec3, Vec4, Quat};
+use crate::{SandWorld, MaterialRegistry, State};
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    position : [f32; 2]
+}
+glium::implement_vertex!(Vertex, position);
+
+const VERT_SRC : &str = r#"
+    #version 140
+    in vec2 position;
+    out vec2 vCell;
+    uniform mat4 transform;
+    uniform vec2 gridSize;
+    void main() {
+        // Grid coordinates have cell centers at integers, so the quad spans half a cell past each edge
+        vCell = position * gridSize - 0.5;
+        gl_Position = transform * vec4(vCell, 0, 1);
+    }
+"#;
+
+const FRAG_SRC : &str = r#"
+    #version 140
+    in vec2 vCell;
+    out vec4 color;
+    // Particle index and shade of each cell
+    uniform usampler2D cells;
+    // Color of each material, with alpha 1 for liquids
+    uniform sampler1D palette;
+    uniform sampler2D heat;
+    uniform bool heatmap;
+    uniform bool jitter;
+    uniform bool shimmer;
+    uniform bool glow;
+    uniform bool lighting;
+    // Seconds since the renderer was created
+    uniform float time;
+    // Blue below room temperature, then red, yellow and white up to lava temperatures
+    vec3 heatColor(float t) {
+        if (t < 20.0) { return mix(vec3(0.1), vec3(0.2, 0.4, 1.0), clamp((20.0 - t) / 50.0, 0.0, 1.0)); }
+        float n = 3.0 * sqrt(clamp((t - 20.0) / 1500.0, 0.0, 1.0));
+        return max(vec3(0.1), clamp(vec3(n, n - 1.0, n - 2.0), 0.0, 1.0));
+    }
+    // Light given off by incandescent cells, from dull red to yellowish white
+    vec3 glowColor(float t) {
+        float g = clamp((t - 400.0) / 1100.0, 0.0, 1.0);
+        return g * mix(vec3(1.0, 0.2, 0.0), vec3(1.0, 0.9, 0.6), g);
+    }
+    void main() {
+        ivec2 cell = ivec2(floor(vCell + 0.5));
+        ivec2 size = textureSize(cells, 0);
+        uvec2 c = texelFetch(cells, cell, 0).rg;
+        uint p = c.r;
+        if (heatmap) {
+            vec3 base = p == 0u ? vec3(0.1) : texelFetch(palette, int(p), 0).rgb;
+            color = vec4(mix(base, heatColor(texelFetch(heat, cell, 0).r), 0.75), 1);
+            return;
+        }
+        vec3 rgb = vec3(0);
+        if (p != 0u) {
+            vec4 m = texelFetch(palette, int(p), 0);
+            float shade = float(c.g) / 255.0 - 0.5;
+            rgb = m.rgb;
+            if (jitter) { rgb *= 1.0 + 0.16 * shade; }
+            if (shimmer && m.a > 0.5) { rgb *= 1.0 + 0.08 * sin(3.0 * time + 0.7 * float(cell.x) + 0.45 * float(cell.y) + 6.0 * shade); }
+            if (lighting) {
+                // Cells surrounded by others, or by the edges of the grid, get less light than those
+                // on the surface of a pile
+                int occupied = 0;
+                for (int dy = -1; dy <= 1; dy++) {
+                    for (int dx = -1; dx <= 1; dx++) {
+                        ivec2 n = cell + ivec2(dx, dy);
+                        bool inside = all(greaterThanEqual(n, ivec2(0))) && all(lessThan(n, size));
+                        if ((dx != 0 || dy != 0) && (!inside || texelFetch(cells, n, 0).r != 0u)) { occupied++; }
+                    }
+                }
+                rgb *= 1.0 - 0.35 * float(occupied) / 8.0;
+            }
+        }
+        // Hot cells light up their surroundings, air included, fading with distance
+        vec3 light = vec3(0);
+        if (glow) {
+            for (int dy = -2; dy <= 2; dy++) {
+                for (int dx = -2; dx <= 2; dx++) {
+                    ivec2 n = clamp(cell + ivec2(dx, dy), ivec2(0), size - 1);
+                    light += glowColor(texelFetch(heat, n, 0).r) / float(1 + dx * dx + dy * dy);
+                }
+            }
+            light *= 0.35;
+        }
+        if (p != 0u) {
+            color = vec4(rgb + light, 1);
+            return;
+        }
+        float alpha = min(max(light.r, max(light.g, light.b)), 1.0);
+        if (alpha == 0.0) { discard; }
+        color = vec4(light / alpha, alpha);
+    }
+"#;
+
+/// Maps grid coordinates (cell centers at integers) to normalized device coordinates
+pub fn grid_to_ndc(width : f32, height : f32) -> Mat4 {
+    Mat4::from_scale_rotation_translation(
+        Vec3::new(2.0 / width, 2.0 / height, 1.0),
+        Quat::IDENTITY,
+        Vec3::new(-1.0, -1.0, 0.0)
+    )
+}
+
+/// Part of a grid shown in the viewport: the grid point at the center of the viewport, and a
+/// magnification where 1 fits the whole grid. Cells stay square whatever the shape of the viewport,
+/// which shows more than the grid along its longer side when zoomed out.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Camera {
+    pub center : Vec2,
+    pub zoom : f32,
+    /// Width over height of the viewport in pixels
+    pub aspect : f32
+}
+
+impl Camera {
+    pub const MAX_ZOOM : f32 = 64.0;
+
+    /// Shows the whole grid in a viewport of the same shape, as `grid_to_ndc` does
+    pub fn fit(width : f32, height : f32) -> Camera {
+        Camera { center : Vec2::new(width, height) / 2.0, zoom : 1.0, aspect : width / height }
+    }
+
+    // Normalized device units per cell at zoom 1, fitting the whole grid with square cells
+    fn scale(&self, width : f32, height : f32) -> Vec2 {
+        let y = (2.0 / height).min(2.0 * self.aspect / width);
+        Vec2::new(y / self.aspect, y)
+    }
+
+    /// Maps grid coordinates to normalized device coordinates under this view
+    pub fn transform(&self, width : f32, height : f32) -> Mat4 {
+        let scale = self.scale(width, height) * self.zoom;
+        Mat4::from_scale(scale.extend(1.0)) * Mat4::from_translation(-self.center.extend(0.0))
+    }
+
+    /// Grid coordinates shown at a point in normalized device coordinates
+    pub fn to_grid(&self, width : f32, height : f32, ndc : Vec2) -> Vec2 {
+        let grid = self.transform(width, height).inverse() * Vec4::new(ndc.x, ndc.y, 0.0, 1.0);
+        Vec2::new(grid.x, grid.y)
+    }
+
+    /// Multiplies the zoom by `factor`, keeping the grid point `at` in the same place on screen
+    pub fn zoom_at(&mut self, at : Vec2, factor : f32, width : f32, height : f32) {
+        let zoom = (self.zoom * factor).clamp(1.0, Camera::MAX_ZOOM);
+        self.center = at + (self.center - at) * self.zoom / zoom;
+        self.zoom = zoom;
+        self.clamp(width, height);
+    }
+
+    /// Drags the view: `from` and `to` are the grid points under the cursor before and after it
+    /// moved, and the view follows so `from` stays under the cursor
+    pub fn pan(&mut self, from : Vec2, to : Vec2, width : f32, height : f32) {
+        self.center += from - to;
+        self.clamp(width, height);
+    }
+
+    /// Keeps the view within a grid, e.g. after it or the viewport was resized. Along a direction
+    /// where the whole grid is visible, it stays centered.
+    pub fn clamp(&mut self, width : f32, height : f32) {
+        self.zoom = self.zoom.clamp(1.0, Camera::MAX_ZOOM);
+        let half = Vec2::ONE / (self.scale(width, height) * self.zoom);
+        let axis = |c : f32, half : f32, size : f32| if half >= size / 2.0 { size / 2.0 } else { c.clamp(half, size - half) };
+        self.center = Vec2::new(axis(self.center.x, half.x, width), axis(self.center.y, half.y, height));
+    }
+}
+
+/// Optional effects of `GridRenderer`, each of which can be turned off to see what it costs
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Effects {
+    /// Varies the color of every particle a little, see `SandWorld::shades`
+    pub jitter : bool,
+    /// Ripples the color of liquids over time
+    pub shimmer : bool,
+    /// Makes incandescent cells shine onto their surroundings; needs temperatures to be uploaded
+    pub glow : bool,
+    /// Darkens cells by how many of their neighbors are occupied
+    pub lighting : bool
+}
+
+impl Default for Effects {
+    fn default() -> Effects {
+        Effects { jitter : true, shimmer : true, glow : true, lighting : false }
+    }
+}
+
+/// Draws a `SandWorld` in a single draw call, uploading the grid as a texture of particle indices
+/// and shades that the fragment shader resolves to colors through a palette texture. With the
+/// heatmap or glow enabled, cell temperatures are uploaded as well.
+pub struct GridRenderer {
+    program : glium::Program,
+    vertex_buffer : glium::VertexBuffer<Vertex>,
+    palette : Texture1d,
+    // Registry the palette was built from, to rebuild it when the world's materials change
+    palette_materials : Option<Arc<MaterialRegistry>>,
+    cells : UnsignedTexture2d,
+    cell_data : Vec<u8>,
+    heat : Texture2d,
+    heatmap : bool,
+    effects : Effects,
+    // Clock of animated effects
+    start : Instant
+}
+
+impl GridRenderer {
+    pub fn new<F : Facade>(facade : &F) -> GridRenderer {
+        let vertex_buffer = glium::VertexBuffer::new(facade, &[
+            Vertex { position : [0.0, 0.0] },
+            Vertex { position : [1.0, 0.0] },
+            Vertex { position : [1.0, 1.0] },
+            Vertex { position : [1.0, 1.0] },
+            Vertex { position : [0.0, 1.0] },
+            Vertex { position : [0.0, 0.0] },
+        ]).expect("Error creating vertex buffer");
+        let program = glium::Program::from_source(facade, VERT_SRC, FRAG_SRC, None)
+            .expect("Error compiling grid shader program");
+        let palette = Texture1d::empty(facade, 1).expect("Error creating palette texture");
+        let cells = UnsignedTexture2d::empty_with_format(facade, UncompressedUintFormat::U8U8, MipmapsOption::NoMipmap, 1, 1)
+            .expect("Error creating cell texture");
+        let heat = Texture2d::empty_with_format(facade, UncompressedFloatFormat::F32, MipmapsOption::NoMipmap, 1, 1)
+            .expect("Error creating heat texture");
+        GridRenderer {
+            program, vertex_buffer, palette, palette_materials : None, cells, cell_data : Vec::new(), heat, heatmap : false,
+            effects : Effects::default(), start : Instant::now()
+        }
+    }
+
+    pub fn heatmap(&self) -> bool { self.heatmap }
+    /// Shows cell temperatures instead of plain particle colors, starting with the next `upload`
+    pub fn set_heatmap(&mut self, heatmap : bool) { self.heatmap = heatmap; }
+
+    pub fn effects(&self) -> Effects { self.effects }
+    /// Changes the effects, starting with the next `upload`
+    pub fn set_effects(&mut self, effects : Effects) { self.effects = effects; }
+
+    /// Uploads the current contents of `world` to the cell texture
+    pub fn upload<F : Facade>(&mut self, facade : &F, world : &SandWorld) {
+        let (width, height) = (world.width(), world.height());
+        if !self.palette_materials.as_ref().is_some_and(|m| Arc::ptr_eq(m, world.materials())) {
+            let colors : Vec<(f32, f32, f32, f32)> = world.materials().iter()
+                .map(|(_, m)| (m.color[0], m.color[1], m.color[2], (m.state == State::Liquid) as u8 as f32))
+                .collect();
+            self.palette = Texture1d::new(facade, colors).expect("Error creating palette texture");
+            self.palette_materials = Some(world.materials().clone());
+        }
+        self.cell_data.clear();
+        self.cell_data.extend(world.cells().iter().zip(world.shades()).flat_map(|(p, &shade)| [p.index(), shade]));
+        let image = RawImage2d {
+            data : Cow::Borrowed(&self.cell_data[..]),
+            width : width as u32,
+            height : height as u32,
+            format : ClientFormat::U8U8
+        };
+        // The whole grid changes every tick, so a fresh texture is cheaper than a partial write
+        self.cells = UnsignedTexture2d::with_format(facade, image, UncompressedUintFormat::U8U8, MipmapsOption::NoMipmap)
+            .expect("Error creating cell texture");
+        if self.heatmap || self.effects.glow {
+            let image = RawImage2d {
+                data : Cow::Borrowed(world.temperatures()),
+                width : width as u32,
+                height : height as u32,
+                format : ClientFormat::F32
+            };
+            self.heat = Texture2d::with_format(facade, image, UncompressedFloatFormat::F32, MipmapsOption::NoMipmap)
+                .expect("Error creating heat texture");
+        }
+    }
+
+    /// Draws the last uploaded grid with `transform` mapping grid coordinates to clip space
+    pub fn draw<S : Surface>(&self, target : &mut S, transform : Mat4) {
+        let uniforms = glium::uniform!{
+            transform : transform.to_cols_array_2d(),
+            gridSize : [self.cells.width() as f32, self.cells.height() as f32],
+            cells : self.cells.sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            palette : self.palette.sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            heat : self.heat.sampled()
+                .magnify_filter(MagnifySamplerFilter::Nearest)
+                .minify_filter(MinifySamplerFilter::Nearest),
+            heatmap : self.heatmap,
+            jitter : self.effects.jitter,
+            shimmer : self.effects.shimmer,
+            glow : self.effects.glow,
+            lighting : self.effects.lighting,
+            time : self.start.elapsed().as_secs_f32()
+        };
+        let indices = glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList);
+        // Glow spilling into air is blended over what's behind the grid
+        let params = glium::DrawParameters { blend : glium::Blend::alpha_blending(), ..Default::default() };
+        target.draw(&self.vertex_buffer, indices, &self.program, &uniforms, &params)
+            .expect("Error drawing grid");
+    }
+}
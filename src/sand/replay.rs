@@ -0,0 +1,441 @@
+//! Reproducible sessions: every change to a `Simulation` goes through a `Command`, and a recording
+//! keeps the seed, the starting world (with its emitters) and the commands applied before each
+//! tick, so a `Replay` reproduces a run bit for bit.
+//!
+//! Replay file layout (little-endian):
+//! - magic `b"SRPL"`, format version `u16`
+//! - seed `u64`, in version 1 followed by the spawner's material `u8`
+//! - palette, as in `.sand` files; material indices in commands refer to it
+//! - the starting world, as a complete `.sand` file
+//! - tick count `u32`, then per tick a command count `u32` followed by the commands, each a `u8` tag and its fields:
+//!   - 0 paint: from `[i32; 2]`, to `[i32; 2]`, radius `i32`, material `u8`, since version 4 brush `u8` (0 circle, 1 square)
+//!   - 1 clear
+//!   - 2 resize: width `u32`, height `u32`
+//!   - 3 add emitter: the emitter as stored in `.sand` files (in version 1, set spawner: material `u8`)
+//!   - 4 pause: `u8` (0 or 1)
+//!   - 5 load: a complete `.sand` file
+//!   - 6 step
+//!   - 7 edit emitter: index `u32`, then the emitter
+//!   - 8 remove emitter: index `u32`
+//!   - 9 add body: the body as stored in `.sand` files (since version 3)
+//!   - 10 paste: x `i32`, y `i32`, then the stamp as a complete `.sand` file (since version 5)
+//!   - 11 erase: x `i32`, y `i32`, width `u32`, height `u32` (since version 5)
+//!   - 12 set gravity: `[f32; 2]` (since version 6)
+//!   - 13 add gravity well: the well as stored in `.sand` files (since version 6)
+//!   - 14 remove gravity well: index `u32` (since version 6)
+//!   - 15 patch: cell count `u32` and per cell its index `u32` in the grid and material `u8`, then a
+//!     `u8` of flags for what else it sets, each as stored in `.sand` files in this order: emitters
+//!     (1) and gravity wells (2) with their count `u32`, gravity (4), bodies (8) with their count
+//!     `u32` (since version 7)
+
+use std::{io::{self, Read, Write}, sync::Arc};
+use glam::Vec2;
+use rand::{SeedableRng, rngs::StdRng};
+use crate::{SandWorld, Particle, MaterialRegistry, WorldFileError, Emitter, EmitterKind, Body, Brush, GravityWell, Patch, MAX_SIZE};
+use crate::save::{
+    Palette, read_u8, read_u16, read_u32, read_i32, write_palette, read_palette, palette_particle,
+    write_emitter, read_emitter, write_body, read_body, write_well, read_well, read_gravity, check_size
+};
+
+const MAGIC : &[u8; 4] = b"SRPL";
+const VERSION : u16 = 7;
+
+/// A change to a `Simulation` made from outside, e.g. by the user
+#[derive(Clone)]
+pub enum Command {
+    /// `SandWorld::paint_line`
+    Paint { from : (i32, i32), to : (i32, i32), radius : i32, brush : Brush, particle : Particle },
+    Clear,
+    Resize { width : usize, height : usize },
+    AddEmitter(Emitter),
+    /// Replaces the emitter at an index; ignored if there is none
+    EditEmitter(usize, Emitter),
+    /// Removes the emitter at an index; ignored if there is none
+    RemoveEmitter(usize),
+    /// `SandWorld::add_body`; ignored if the body doesn't fit
+    AddBody(Box<Body>),
+    /// `SandWorld::paste` of a stamp with its bottom-left cell at `(x, y)`
+    Paste { x : i32, y : i32, stamp : Box<SandWorld> },
+    /// `SandWorld::erase_region`
+    Erase { x : i32, y : i32, width : usize, height : usize },
+    /// `SandWorld::set_gravity`
+    SetGravity(Vec2),
+    AddWell(GravityWell),
+    /// Removes the gravity well at an index; ignored if there is none
+    RemoveWell(usize),
+    Pause(bool),
+    /// Advances one tick even while paused
+    Step,
+    /// Replaces the whole world, e.g. with one loaded from a file. A world without rules keeps the current ones.
+    Load(Box<SandWorld>),
+    /// Sets some cells and replaces some settings, e.g. to undo an edit, see `History`
+    Patch(Box<Patch>)
+}
+
+/// A recorded session: the starting state and the commands applied before each tick
+#[derive(Clone)]
+pub struct Replay {
+    pub seed : u64,
+    pub world : SandWorld,
+    pub ticks : Vec<Vec<Command>>
+}
+
+/// A `SandWorld` with its own seeded random generator, so the same seed and commands always lead
+/// to the same state
+pub struct Simulation {
+    world : SandWorld,
+    rng : StdRng,
+    paused : bool,
+    pending : Vec<Command>,
+    recording : Option<Replay>,
+    // Advances the world by one tick, `SandWorld::step` unless replaced with `set_stepper`
+    stepper : Box<Stepper>
+}
+
+type Stepper = dyn FnMut(&mut SandWorld, &mut StdRng);
+
+impl Simulation {
+    pub fn new(world : SandWorld, seed : u64) -> Simulation {
+        Simulation {
+            world, rng : StdRng::seed_from_u64(seed), paused : false, pending : Vec::new(), recording : None,
+            stepper : Box::new(|world, rng| world.step(rng))
+        }
+    }
+
+    /// Creates a simulation that records its commands, see `replay`
+    pub fn recorded(world : SandWorld, seed : u64) -> Simulation {
+        let recording = Replay { seed, world : world.clone(), ticks : Vec::new() };
+        Simulation { recording : Some(recording), ..Simulation::new(world, seed) }
+    }
+
+    /// Replaces the update run every tick, e.g. with `SandWorld::step_margolus`. It isn't recorded,
+    /// so a replay has to be played with the same one.
+    pub fn set_stepper<S : FnMut(&mut SandWorld, &mut StdRng) + 'static>(&mut self, stepper : S) {
+        self.stepper = Box::new(stepper);
+    }
+
+    pub fn world(&self) -> &SandWorld { &self.world }
+    pub fn paused(&self) -> bool { self.paused }
+
+    /// Everything recorded so far, if the simulation was created with `recorded`
+    pub fn replay(&self) -> Option<&Replay> { self.recording.as_ref() }
+
+    pub fn apply(&mut self, command : Command) {
+        match &command {
+            Command::Paint { from, to, radius, brush, particle } => self.world.paint_line(*from, *to, *radius, *brush, *particle),
+            Command::Clear => self.world.clear(),
+            Command::Resize { width, height } => self.world.resize(*width, *height),
+            Command::AddEmitter(e) => self.world.emitters_mut().push(*e),
+            Command::EditEmitter(i, e) => if let Some(old) = self.world.emitters_mut().get_mut(*i) { *old = *e; },
+            Command::RemoveEmitter(i) => if *i < self.world.emitters().len() { self.world.emitters_mut().remove(*i); },
+            Command::AddBody(body) => { self.world.add_body((**body).clone()); }
+            Command::Paste { x, y, stamp } => self.world.paste(stamp, *x, *y),
+            Command::Erase { x, y, width, height } => self.world.erase_region(*x, *y, *width, *height),
+            Command::SetGravity(gravity) => self.world.set_gravity(*gravity),
+            Command::AddWell(well) => self.world.wells_mut().push(*well),
+            Command::RemoveWell(i) => if *i < self.world.wells().len() { self.world.wells_mut().remove(*i); },
+            Command::Pause(paused) => self.paused = *paused,
+            Command::Load(world) => {
+                // Rules belong to the program rather than the file, so they carry over to worlds without any
+                let rules = std::mem::take(&mut self.world.rules);
+                self.world = (**world).clone();
+                if self.world.rules.is_empty() { self.world.rules = rules; }
+            }
+            Command::Step => self.advance(),
+            Command::Patch(patch) => patch.apply(&mut self.world)
+        }
+        if self.recording.is_some() { self.pending.push(command); }
+    }
+
+    /// Steps the world and runs its emitters, unless paused
+    pub fn tick(&mut self) {
+        if let Some(recording) = &mut self.recording {
+            recording.ticks.push(std::mem::take(&mut self.pending));
+        }
+        if !self.paused { self.advance(); }
+    }
+
+    fn advance(&mut self) {
+        (self.stepper)(&mut self.world, &mut self.rng);
+        self.world.emit(&mut self.rng);
+    }
+}
+
+fn read_command<R : Read>(r : &mut R, version : u16, palette : &Palette, materials : &Arc<MaterialRegistry>) -> Result<Command, WorldFileError> {
+    let tag = read_u8(r)?;
+    read_fields(r, tag, version, palette, materials)
+}
+
+// Reads the fields of a command with `tag`
+fn read_fields<R : Read>(r : &mut R, tag : u8, version : u16, palette : &Palette, materials : &Arc<MaterialRegistry>) -> Result<Command, WorldFileError> {
+    Ok(match tag {
+        0 => {
+            let from = (read_i32(r)?, read_i32(r)?);
+            let to = (read_i32(r)?, read_i32(r)?);
+            let radius = read_i32(r)?;
+            // Strokes reach at most a grid's size past any edge, so painting them takes bounded time
+            let reach = -(MAX_SIZE as i32)..=2 * MAX_SIZE as i32;
+            if !(0..=MAX_SIZE as i32).contains(&radius) || ![from.0, from.1, to.0, to.1].iter().all(|v| reach.contains(v)) {
+                return Err(WorldFileError::Format(format!("paint stroke from {:?} to {:?} with radius {}", from, to, radius)));
+            }
+            let particle = palette_particle(palette, read_u8(r)?)?;
+            let brush = match if version >= 4 { read_u8(r)? } else { 0 } {
+                0 => Brush::Circle,
+                1 => Brush::Square,
+                b => return Err(WorldFileError::Format(format!("unknown brush {}", b)))
+            };
+            Command::Paint { from, to, radius, brush, particle }
+        }
+        1 => Command::Clear,
+        2 => {
+            let (width, height) = (read_u32(r)? as usize, read_u32(r)? as usize);
+            check_size(width, height)?;
+            Command::Resize { width, height }
+        }
+        3 => Command::AddEmitter(read_emitter(r, palette)?),
+        4 => Command::Pause(read_u8(r)? != 0),
+        5 => Command::Load(Box::new(SandWorld::load_with_materials(r, materials.clone())?)),
+        6 => Command::Step,
+        7 => Command::EditEmitter(read_u32(r)? as usize, read_emitter(r, palette)?),
+        8 => Command::RemoveEmitter(read_u32(r)? as usize),
+        9 => Command::AddBody(Box::new(read_body(r, palette)?)),
+        10 => {
+            let (x, y) = (read_i32(r)?, read_i32(r)?);
+            Command::Paste { x, y, stamp : Box::new(SandWorld::load_with_materials(r, materials.clone())?) }
+        }
+        11 => {
+            let (x, y) = (read_i32(r)?, read_i32(r)?);
+            let (width, height) = (read_u32(r)? as usize, read_u32(r)? as usize);
+            Command::Erase { x, y, width, height }
+        }
+        12 => Command::SetGravity(read_gravity(r)?),
+        13 => Command::AddWell(read_well(r)?),
+        14 => Command::RemoveWell(read_u32(r)? as usize),
+        15 => Command::Patch(Box::new(read_patch(r, palette)?)),
+        tag => return Err(WorldFileError::Format(format!("unknown command {}", tag)))
+    })
+}
+
+fn write_patch<W : Write>(w : &mut W, patch : &Patch) -> io::Result<()> {
+    w.write_all(&(patch.cells.len() as u32).to_le_bytes())?;
+    for &(c, p) in &patch.cells {
+        w.write_all(&(c as u32).to_le_bytes())?;
+        w.write_all(&[p.index()])?;
+    }
+    let flags = patch.emitters.is_some() as u8 | (patch.wells.is_some() as u8) << 1
+        | (patch.gravity.is_some() as u8) << 2 | (patch.bodies.is_some() as u8) << 3;
+    w.write_all(&[flags])?;
+    if let Some(emitters) = &patch.emitters {
+        w.write_all(&(emitters.len() as u32).to_le_bytes())?;
+        for e in emitters { write_emitter(w, e)?; }
+    }
+    if let Some(wells) = &patch.wells {
+        w.write_all(&(wells.len() as u32).to_le_bytes())?;
+        for well in wells { write_well(w, well)?; }
+    }
+    if let Some(gravity) = patch.gravity {
+        for v in gravity.to_array() { w.write_all(&v.to_le_bytes())?; }
+    }
+    if let Some(bodies) = &patch.bodies {
+        w.write_all(&(bodies.len() as u32).to_le_bytes())?;
+        for body in bodies { write_body(w, body)?; }
+    }
+    Ok(())
+}
+
+fn read_patch<R : Read>(r : &mut R, palette : &Palette) -> Result<Patch, WorldFileError> {
+    let mut patch = Patch::default();
+    for _ in 0..read_u32(r)? {
+        let c = read_u32(r)? as usize;
+        patch.cells.push((c, palette_particle(palette, read_u8(r)?)?));
+    }
+    let flags = read_u8(r)?;
+    if flags & 1 != 0 {
+        patch.emitters = Some((0..read_u32(r)?).map(|_| read_emitter(r, palette)).collect::<Result<_, _>>()?);
+    }
+    if flags & 2 != 0 {
+        patch.wells = Some((0..read_u32(r)?).map(|_| read_well(r)).collect::<Result<_, _>>()?);
+    }
+    if flags & 4 != 0 { patch.gravity = Some(read_gravity(r)?); }
+    if flags & 8 != 0 {
+        patch.bodies = Some((0..read_u32(r)?).map(|_| read_body(r, palette)).collect::<Result<_, _>>()?);
+    }
+    Ok(patch)
+}
+
+// The spawner of version 1 replays, which dropped one particle per tick in a random column of the
+// top row, whatever the size of the world, and was kept when loading another world. It becomes the
+// first emitter of the world, as made by `Emitter::spawner`.
+struct Spawner {
+    particle : Particle,
+    width : usize,
+    height : usize
+}
+
+impl Spawner {
+    fn emitter(&self) -> Emitter {
+        let half = self.width as i32 / 2;
+        Emitter { kind : EmitterKind::Source, x : half, y : self.height as i32 - 1, particle : self.particle, rate : 1.0, spread : half }
+    }
+
+    // Reads a version 1 command, adding the commands with the same effect to `commands`
+    fn read_command<R : Read>(&mut self, r : &mut R, palette : &Palette, materials : &Arc<MaterialRegistry>, commands : &mut Vec<Command>) -> Result<(), WorldFileError> {
+        match read_u8(r)? {
+            // Set spawner: material `u8`
+            3 => {
+                self.particle = palette_particle(palette, read_u8(r)?)?;
+                commands.push(Command::EditEmitter(0, self.emitter()));
+            }
+            tag @ 0..=6 => match read_fields(r, tag, 1, palette, materials)? {
+                Command::Resize { width, height } => {
+                    (self.width, self.height) = (width, height);
+                    commands.push(Command::Resize { width, height });
+                    commands.push(Command::EditEmitter(0, self.emitter()));
+                }
+                Command::Load(mut world) => {
+                    (self.width, self.height) = (world.width(), world.height());
+                    world.emitters_mut().insert(0, self.emitter());
+                    commands.push(Command::Load(world));
+                }
+                command => commands.push(command)
+            },
+            tag => return Err(WorldFileError::Format(format!("unknown command {}", tag)))
+        }
+        Ok(())
+    }
+}
+
+impl Replay {
+    /// A fresh simulation in the recorded starting state
+    pub fn start(&self) -> Simulation {
+        Simulation::new(self.world.clone(), self.seed)
+    }
+
+    /// Runs every recorded tick, returning the final state
+    pub fn play(&self) -> Simulation {
+        let mut simulation = self.start();
+        for commands in &self.ticks {
+            for command in commands { simulation.apply(command.clone()); }
+            simulation.tick();
+        }
+        simulation
+    }
+
+    pub fn save<W : Write>(&self, w : &mut W) -> Result<(), WorldFileError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&self.seed.to_le_bytes())?;
+        write_palette(w, self.world.materials())?;
+        self.world.save(w)?;
+        w.write_all(&(self.ticks.len() as u32).to_le_bytes())?;
+        for commands in &self.ticks {
+            w.write_all(&(commands.len() as u32).to_le_bytes())?;
+            for command in commands {
+                match command {
+                    Command::Paint { from, to, radius, brush, particle } => {
+                        w.write_all(&[0])?;
+                        for v in [from.0, from.1, to.0, to.1, *radius] { w.write_all(&v.to_le_bytes())?; }
+                        w.write_all(&[particle.index(), *brush as u8])?;
+                    }
+                    Command::Clear => w.write_all(&[1])?,
+                    Command::Resize { width, height } => {
+                        w.write_all(&[2])?;
+                        w.write_all(&(*width as u32).to_le_bytes())?;
+                        w.write_all(&(*height as u32).to_le_bytes())?;
+                    }
+                    Command::AddEmitter(e) => {
+                        w.write_all(&[3])?;
+                        write_emitter(w, e)?;
+                    }
+                    Command::EditEmitter(i, e) => {
+                        w.write_all(&[7])?;
+                        w.write_all(&(*i as u32).to_le_bytes())?;
+                        write_emitter(w, e)?;
+                    }
+                    Command::RemoveEmitter(i) => {
+                        w.write_all(&[8])?;
+                        w.write_all(&(*i as u32).to_le_bytes())?;
+                    }
+                    Command::AddBody(body) => {
+                        w.write_all(&[9])?;
+                        write_body(w, body)?;
+                    }
+                    Command::Paste { x, y, stamp } => {
+                        w.write_all(&[10])?;
+                        for v in [*x, *y] { w.write_all(&v.to_le_bytes())?; }
+                        stamp.save(w)?;
+                    }
+                    Command::Erase { x, y, width, height } => {
+                        w.write_all(&[11])?;
+                        for v in [*x, *y] { w.write_all(&v.to_le_bytes())?; }
+                        for v in [*width, *height] { w.write_all(&(v as u32).to_le_bytes())?; }
+                    }
+                    Command::SetGravity(gravity) => {
+                        w.write_all(&[12])?;
+                        for v in gravity.to_array() { w.write_all(&v.to_le_bytes())?; }
+                    }
+                    Command::AddWell(well) => {
+                        w.write_all(&[13])?;
+                        write_well(w, well)?;
+                    }
+                    Command::RemoveWell(i) => {
+                        w.write_all(&[14])?;
+                        w.write_all(&(*i as u32).to_le_bytes())?;
+                    }
+                    Command::Pause(paused) => w.write_all(&[4, *paused as u8])?,
+                    Command::Load(world) => {
+                        w.write_all(&[5])?;
+                        world.save(w)?;
+                    }
+                    Command::Step => w.write_all(&[6])?,
+                    Command::Patch(patch) => {
+                        w.write_all(&[15])?;
+                        write_patch(w, patch)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a replay written by `save`, using the built-in materials
+    pub fn load<R : Read>(r : &mut R) -> Result<Replay, WorldFileError> {
+        Replay::load_with_materials(r, Arc::new(MaterialRegistry::default()))
+    }
+
+    /// Reads a replay written by `save`, mapping its materials onto `materials` by name
+    pub fn load_with_materials<R : Read>(r : &mut R, materials : Arc<MaterialRegistry>) -> Result<Replay, WorldFileError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(WorldFileError::Format("not a replay file".into())); }
+        let version = read_u16(r)?;
+        if version == 0 || version > VERSION {
+            return Err(WorldFileError::Format(format!("unsupported replay version {}", version)));
+        }
+        let mut seed = [0u8; 8];
+        r.read_exact(&mut seed)?;
+        let spawn = if version == 1 { Some(read_u8(r)?) } else { None };
+        let palette = read_palette(r, &materials)?;
+        let mut world = SandWorld::load_with_materials(r, materials.clone())?;
+        let mut spawner = match spawn {
+            Some(index) => {
+                let spawner = Spawner { particle : palette_particle(&palette, index)?, width : world.width(), height : world.height() };
+                world.emitters_mut().insert(0, spawner.emitter());
+                Some(spawner)
+            }
+            None => None
+        };
+        let mut ticks = Vec::new();
+        for _ in 0..read_u32(r)? {
+            let mut commands = Vec::new();
+            for _ in 0..read_u32(r)? {
+                match &mut spawner {
+                    Some(spawner) => spawner.read_command(r, &palette, &materials, &mut commands)?,
+                    None => commands.push(read_command(r, version, &palette, &materials)?)
+                }
+            }
+            ticks.push(commands);
+        }
+        Ok(Replay { seed : u64::from_le_bytes(seed), world, ticks })
+    }
+}
@@ -0,0 +1,119 @@
+//! Custom cell behavior written in Rust.
+//!
+//! A `Rule` set on a material replaces its built-in decay, reactions and moves: every tick it looks
+//! at a particle's `Neighborhood` and may return a `Proposal`, which becomes a regular move and is
+//! resolved against all other moves as usual. Rules see the grid as it was at the start of the tick,
+//! so synchronous automata like the Game of Life work regardless of the order cells are updated in.
+//! Rules are part of the host program rather than of the world, so they aren't saved with it.
+
+use rand::RngCore;
+use crate::{Particle, MaterialRegistry};
+
+/// Behavior of the particles of a material, see `SandWorld::set_rule`
+pub trait Rule : Send + Sync {
+    /// What the particle at the center of `cell` does this tick, if anything
+    fn update(&self, cell : &Neighborhood, rng : &mut dyn RngCore) -> Option<Proposal>;
+}
+
+/// A change proposed by a `Rule`, involving the particle's cell and at most one of its 8 neighbors.
+/// Offsets further away are ignored.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Proposal {
+    /// Swaps with the neighbor at an offset, if the particle can displace it
+    Move(i32, i32),
+    /// Turns the particle into another one
+    Become(Particle),
+    /// Turns the particle into `into` and the neighbor at `(dx, dy)` into `other_into`
+    React { dx : i32, dy : i32, into : Particle, other_into : Particle }
+}
+
+/// A particle and its surroundings, as they were at the start of the tick
+pub struct Neighborhood<'a> {
+    pub(crate) grid : &'a [Particle],
+    pub(crate) materials : &'a MaterialRegistry,
+    pub(crate) width : usize,
+    pub(crate) height : usize,
+    pub(crate) x : usize,
+    pub(crate) y : usize
+}
+
+impl Neighborhood<'_> {
+    pub fn x(&self) -> usize { self.x }
+    pub fn y(&self) -> usize { self.y }
+    pub fn materials(&self) -> &MaterialRegistry { self.materials }
+
+    /// The particle whose rule is running
+    pub fn particle(&self) -> Particle { self.grid[self.y * self.width + self.x] }
+
+    /// The particle at an offset from this cell; `None` outside the grid
+    pub fn get(&self, dx : i32, dy : i32) -> Option<Particle> {
+        let (x, y) = (self.x as i64 + dx as i64, self.y as i64 + dy as i64);
+        let in_grid = x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height;
+        in_grid.then(|| self.grid[y as usize * self.width + x as usize])
+    }
+
+    /// Number of the 8 neighbors holding `p`
+    pub fn count(&self, p : Particle) -> usize {
+        NEIGHBORS.iter().filter(|&&(dx, dy)| self.get(dx, dy) == Some(p)).count()
+    }
+}
+
+const NEIGHBORS : [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0)];
+
+/// Conway's Game of Life: `alive` particles survive with 2 or 3 living neighbors, and air with
+/// exactly 3 comes alive. Set it as the rule of both `alive` and air.
+pub struct Life {
+    pub alive : Particle
+}
+
+impl Rule for Life {
+    fn update(&self, cell : &Neighborhood, _ : &mut dyn RngCore) -> Option<Proposal> {
+        let (alive, neighbors) = (cell.particle() == self.alive, cell.count(self.alive));
+        match (alive, neighbors) {
+            (true, 2 | 3) | (false, 0..=2 | 4..) => None,
+            (true, _) => Some(Proposal::Become(Particle::AIR)),
+            (false, _) => Some(Proposal::Become(self.alive))
+        }
+    }
+}
+
+/// Langton's ant walking over a grid of air and `black` cells: on air it turns right, on black it
+/// turns left, then it flips the color of its cell and steps forward. An ant is one of 8 particles,
+/// for its heading (north, east, south, west) and the color of the cell under it; set the rule on
+/// all of them.
+pub struct LangtonsAnt {
+    pub black : Particle,
+    /// Ants on air, then ants on black, each by heading
+    pub ants : [[Particle; 4]; 2]
+}
+
+impl LangtonsAnt {
+    /// Looks up the materials `black`, `ant-north`, `ant-east`, `ant-south`, `ant-west` and the same
+    /// with a `-black` suffix
+    pub fn from_materials(materials : &MaterialRegistry) -> Option<LangtonsAnt> {
+        let heading = |suffix : &str| -> Option<[Particle; 4]> {
+            let find = |dir : &str| materials.find(&format!("ant-{}{}", dir, suffix));
+            Some([find("north")?, find("east")?, find("south")?, find("west")?])
+        };
+        Some(LangtonsAnt { black : materials.find("black")?, ants : [heading("")?, heading("-black")?] })
+    }
+}
+
+impl Rule for LangtonsAnt {
+    fn update(&self, cell : &Neighborhood, _ : &mut dyn RngCore) -> Option<Proposal> {
+        let p = cell.particle();
+        let (on_black, heading) = (0..2).flat_map(|c| (0..4).map(move |d| (c, d))).find(|&(c, d)| self.ants[c][d] == p)?;
+        let heading = if on_black == 1 { (heading + 3) % 4 } else { (heading + 1) % 4 };
+        let (dx, dy) = [(0, 1), (1, 0), (0, -1), (-1, 0)][heading];
+        let flipped = if on_black == 1 { Particle::AIR } else { self.black };
+        match cell.get(dx, dy) {
+            None => Some(Proposal::Become(self.ants[on_black][heading])),
+            Some(next) if next == Particle::AIR || next == self.black => {
+                let ant = self.ants[(next == self.black) as usize][heading];
+                Some(Proposal::React { dx, dy, into : flipped, other_into : ant })
+            }
+            // Blocked by another ant or anything else: wait
+            Some(_) => None
+        }
+    }
+}
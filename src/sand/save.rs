@@ -0,0 +1,350 @@
+//! World files: a compact binary `.sand` format, plus import/export of indexed PNG images.
+//!
+//! `.sand` layout (little-endian):
+//! - magic `b"SAND"`, format version `u16`
+//! - width `u32`, height `u32`
+//! - palette: entry count `u8` (0 meaning 256), then per entry a name (`u8` length + UTF-8 bytes) and an RGB color (`[u8; 3]`)
+//! - cells, row by row from the bottom, run-length encoded as (`u32` run length, `u8` palette index) pairs
+//! - since version 2: emitter count `u32`, then per emitter its kind `u8` (0 source, 1 drain), x `i32`, y `i32`,
+//!   palette index `u8`, rate `f32` and spread `i32`
+//! - since version 3: body count `u32`, then per body its palette index `u8`, position `[f32; 2]`, angle `f32`,
+//!   velocity `[f32; 2]`, angular velocity `f32`, area `f32`, inertia `f32`, corner count `u32` and the
+//!   corners relative to the position `[f32; 2]`. The body's cells are part of the cell data.
+//! - since version 4: gravity `[f32; 2]`, then well count `u32` and per gravity well its x `i32`,
+//!   y `i32`, radius `f32` and strength `f32`
+//!
+//! Palette entries are matched to materials by name when loading, so files stay readable if the
+//! set or order of materials in the registry changes, as long as every material in use still exists.
+
+use std::{fmt, io::{self, Read, Write}, sync::Arc};
+use glam::Vec2;
+use crate::{SandWorld, Particle, MaterialRegistry, Emitter, EmitterKind, Body, GravityWell, MAX_SIZE};
+
+const MAGIC : &[u8; 4] = b"SAND";
+const VERSION : u16 = 4;
+
+#[derive(Debug)]
+pub enum WorldFileError {
+    Io(io::Error),
+    PngDecoding(png::DecodingError),
+    PngEncoding(png::EncodingError),
+    /// The file was readable but its contents are not a valid world
+    Format(String)
+}
+
+impl fmt::Display for WorldFileError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorldFileError::Io(e) => write!(f, "I/O error: {}", e),
+            WorldFileError::PngDecoding(e) => write!(f, "PNG decoding error: {}", e),
+            WorldFileError::PngEncoding(e) => write!(f, "PNG encoding error: {}", e),
+            WorldFileError::Format(msg) => write!(f, "Invalid world file: {}", msg)
+        }
+    }
+}
+
+impl std::error::Error for WorldFileError { }
+
+impl From<io::Error> for WorldFileError {
+    fn from(e : io::Error) -> Self { WorldFileError::Io(e) }
+}
+impl From<png::DecodingError> for WorldFileError {
+    fn from(e : png::DecodingError) -> Self { WorldFileError::PngDecoding(e) }
+}
+impl From<png::EncodingError> for WorldFileError {
+    fn from(e : png::EncodingError) -> Self { WorldFileError::PngEncoding(e) }
+}
+
+fn color_bytes(color : [f32; 3]) -> [u8; 3] {
+    color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+// Material whose color is closest to `rgb`, used to map image pixels back to particles
+fn nearest_particle(materials : &MaterialRegistry, rgb : [u8; 3]) -> Particle {
+    let distance = |color : [f32; 3]| {
+        color_bytes(color).iter().zip(rgb.iter()).map(|(&a, &b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+    };
+    materials.iter().min_by_key(|(_, m)| distance(m.color)).map(|(p, _)| p).unwrap_or(Particle::AIR)
+}
+
+pub(crate) fn read_u8<R : Read>(r : &mut R) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+pub(crate) fn read_u16<R : Read>(r : &mut R) -> io::Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+pub(crate) fn read_u32<R : Read>(r : &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+pub(crate) fn read_i32<R : Read>(r : &mut R) -> io::Result<i32> {
+    Ok(read_u32(r)? as i32)
+}
+pub(crate) fn read_f32<R : Read>(r : &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(r)?))
+}
+
+/// Palette entries read from a file: the matching material, if any, and the stored name
+pub(crate) type Palette = Vec<(Option<Particle>, String)>;
+
+pub(crate) fn write_palette<W : Write>(w : &mut W, materials : &MaterialRegistry) -> io::Result<()> {
+    w.write_all(&[materials.len() as u8])?;
+    for (_, m) in materials.iter() {
+        let name = &m.name.as_bytes()[..m.name.len().min(u8::MAX as usize)];
+        w.write_all(&[name.len() as u8])?;
+        w.write_all(name)?;
+        w.write_all(&color_bytes(m.color))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_palette<R : Read>(r : &mut R, materials : &MaterialRegistry) -> Result<Palette, WorldFileError> {
+    let mut palette = Vec::new();
+    let count = match read_u8(r)? { 0 => 256, n => n as usize };
+    for _ in 0..count {
+        let mut name = vec![0u8; read_u8(r)? as usize];
+        r.read_exact(&mut name)?;
+        let mut color = [0u8; 3];
+        r.read_exact(&mut color)?;
+        let name = String::from_utf8(name).map_err(|_| WorldFileError::Format("material name is not UTF-8".into()))?;
+        palette.push((materials.find(&name), name));
+    }
+    Ok(palette)
+}
+
+pub(crate) fn write_emitter<W : Write>(w : &mut W, e : &Emitter) -> io::Result<()> {
+    let kind = match e.kind { EmitterKind::Source => 0u8, EmitterKind::Drain => 1 };
+    w.write_all(&[kind])?;
+    w.write_all(&e.x.to_le_bytes())?;
+    w.write_all(&e.y.to_le_bytes())?;
+    w.write_all(&[e.particle.index()])?;
+    w.write_all(&e.rate.to_le_bytes())?;
+    w.write_all(&e.spread.to_le_bytes())
+}
+
+pub(crate) fn read_emitter<R : Read>(r : &mut R, palette : &Palette) -> Result<Emitter, WorldFileError> {
+    let kind = match read_u8(r)? {
+        0 => EmitterKind::Source,
+        1 => EmitterKind::Drain,
+        k => return Err(WorldFileError::Format(format!("unknown emitter kind {}", k)))
+    };
+    let (x, y) = (read_i32(r)?, read_i32(r)?);
+    let particle = palette_particle(palette, read_u8(r)?)?;
+    let rate = read_f32(r)?;
+    let spread = read_i32(r)?;
+    if !rate.is_finite() { return Err(WorldFileError::Format(format!("emitter with rate {}", rate))); }
+    if !(0..=MAX_SIZE as i32).contains(&spread) { return Err(WorldFileError::Format(format!("emitter with spread {}", spread))); }
+    Ok(Emitter { kind, x, y, particle, rate, spread })
+}
+
+pub(crate) fn write_well<W : Write>(w : &mut W, well : &GravityWell) -> io::Result<()> {
+    w.write_all(&well.x.to_le_bytes())?;
+    w.write_all(&well.y.to_le_bytes())?;
+    w.write_all(&well.radius.to_le_bytes())?;
+    w.write_all(&well.strength.to_le_bytes())
+}
+
+pub(crate) fn read_well<R : Read>(r : &mut R) -> Result<GravityWell, WorldFileError> {
+    let well = GravityWell { x : read_i32(r)?, y : read_i32(r)?, radius : read_f32(r)?, strength : read_f32(r)? };
+    if !(well.radius.is_finite() && well.strength.is_finite()) {
+        return Err(WorldFileError::Format(format!("gravity well with radius {} and strength {}", well.radius, well.strength)));
+    }
+    Ok(well)
+}
+
+pub(crate) fn read_gravity<R : Read>(r : &mut R) -> Result<Vec2, WorldFileError> {
+    let gravity = Vec2::new(read_f32(r)?, read_f32(r)?);
+    if !gravity.is_finite() { return Err(WorldFileError::Format(format!("gravity {}", gravity))); }
+    Ok(gravity)
+}
+
+// Writes every field of a body bit for bit, so a replayed body moves exactly like the recorded one
+pub(crate) fn write_body<W : Write>(w : &mut W, body : &Body) -> io::Result<()> {
+    w.write_all(&[body.particle.index()])?;
+    let fields = [body.position.x, body.position.y, body.angle, body.velocity.x, body.velocity.y, body.angular_velocity, body.area, body.inertia];
+    for v in fields { w.write_all(&v.to_le_bytes())?; }
+    w.write_all(&(body.shape.len() as u32).to_le_bytes())?;
+    for v in &body.shape {
+        w.write_all(&v.x.to_le_bytes())?;
+        w.write_all(&v.y.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_body<R : Read>(r : &mut R, palette : &Palette) -> Result<Body, WorldFileError> {
+    let particle = palette_particle(palette, read_u8(r)?)?;
+    let mut fields = [0f32; 8];
+    for v in &mut fields { *v = read_f32(r)?; }
+    let [x, y, angle, vx, vy, angular_velocity, area, inertia] = fields;
+    let count = read_u32(r)? as usize;
+    if !(3..=1 << 16).contains(&count) {
+        return Err(WorldFileError::Format(format!("invalid body with {} corners", count)));
+    }
+    let shape = (0..count).map(|_| Ok(Vec2::new(read_f32(r)?, read_f32(r)?))).collect::<io::Result<Vec<_>>>()?;
+    if !fields.iter().chain(shape.iter().flat_map(|v| [&v.x, &v.y])).all(|v| v.is_finite()) {
+        return Err(WorldFileError::Format("body with non-finite values".into()));
+    }
+    // Bodies stay inside the grid, which also bounds the cells looked at for their footprint
+    let max = MAX_SIZE as f32;
+    let in_bounds = [x, y].iter().all(|v| (0.0..=max).contains(v)) && shape.iter().all(|v| v.length() <= max);
+    if !in_bounds || area <= 0.0 || inertia <= 0.0 {
+        return Err(WorldFileError::Format(format!("invalid body at ({}, {}) with area {} and inertia {}", x, y, area, inertia)));
+    }
+    Ok(Body {
+        particle, position : Vec2::new(x, y), angle, velocity : Vec2::new(vx, vy), angular_velocity,
+        shape, area, inertia, cells : Vec::new()
+    })
+}
+
+// Number of cells of a grid read from a file, failing if either side is zero or larger than `MAX_SIZE`
+pub(crate) fn check_size(width : usize, height : usize) -> Result<usize, WorldFileError> {
+    match width.checked_mul(height) {
+        Some(cells) if (1..=MAX_SIZE).contains(&width) && (1..=MAX_SIZE).contains(&height) => Ok(cells),
+        _ => Err(WorldFileError::Format(format!("invalid dimensions {}x{}", width, height)))
+    }
+}
+
+// Particle for a palette index, failing if the index is out of range or names an unknown material
+pub(crate) fn palette_particle(palette : &Palette, index : u8) -> Result<Particle, WorldFileError> {
+    match palette.get(index as usize) {
+        Some((Some(p), _)) => Ok(*p),
+        Some((None, name)) => Err(WorldFileError::Format(format!("unknown material '{}'", name))),
+        None => Err(WorldFileError::Format(format!("palette index {} out of range", index)))
+    }
+}
+
+impl SandWorld {
+    /// Writes the world in the `.sand` format
+    pub fn save<W : Write>(&self, w : &mut W) -> Result<(), WorldFileError> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&(self.width() as u32).to_le_bytes())?;
+        w.write_all(&(self.height() as u32).to_le_bytes())?;
+        write_palette(w, self.materials())?;
+        let mut cells = self.cells().iter();
+        if let Some(&first) = cells.next() {
+            let (mut run, mut current) = (1u32, first);
+            for &p in cells {
+                if p == current && run < u32::MAX {
+                    run += 1;
+                } else {
+                    w.write_all(&run.to_le_bytes())?;
+                    w.write_all(&[current.index()])?;
+                    run = 1;
+                    current = p;
+                }
+            }
+            w.write_all(&run.to_le_bytes())?;
+            w.write_all(&[current.index()])?;
+        }
+        w.write_all(&(self.emitters().len() as u32).to_le_bytes())?;
+        for e in self.emitters() { write_emitter(w, e)?; }
+        w.write_all(&(self.bodies().len() as u32).to_le_bytes())?;
+        for body in self.bodies() { write_body(w, body)?; }
+        for v in self.gravity().to_array() { w.write_all(&v.to_le_bytes())?; }
+        w.write_all(&(self.wells().len() as u32).to_le_bytes())?;
+        for well in self.wells() { write_well(w, well)?; }
+        Ok(())
+    }
+
+    /// Reads a world written by `save`, using the built-in materials
+    pub fn load<R : Read>(r : &mut R) -> Result<SandWorld, WorldFileError> {
+        SandWorld::load_with_materials(r, Arc::new(MaterialRegistry::default()))
+    }
+
+    /// Reads a world written by `save`, mapping its palette onto `materials` by name
+    pub fn load_with_materials<R : Read>(r : &mut R, materials : Arc<MaterialRegistry>) -> Result<SandWorld, WorldFileError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(WorldFileError::Format("not a .sand file".into())); }
+        let version = read_u16(r)?;
+        if version == 0 || version > VERSION {
+            return Err(WorldFileError::Format(format!("unsupported version {}", version)));
+        }
+        let width = read_u32(r)? as usize;
+        let height = read_u32(r)? as usize;
+        let cells = check_size(width, height)?;
+        let palette = read_palette(r, &materials)?;
+        let mut world = SandWorld::with_materials(width, height, materials);
+        let mut i = 0;
+        while i < cells {
+            let run = read_u32(r)? as usize;
+            let p = palette_particle(&palette, read_u8(r)?)?;
+            if run == 0 || run > cells - i {
+                return Err(WorldFileError::Format("cell data does not match dimensions".into()));
+            }
+            for c in i..(i + run) { world.set(c % width, c / width, p); }
+            i += run;
+        }
+        if version >= 2 {
+            for _ in 0..read_u32(r)? {
+                let e = read_emitter(r, &palette)?;
+                world.emitters_mut().push(e);
+            }
+        }
+        if version >= 3 {
+            for _ in 0..read_u32(r)? {
+                let body = read_body(r, &palette)?;
+                if !world.adopt_body(body) { return Err(WorldFileError::Format("body outside the grid".into())); }
+            }
+        }
+        if version >= 4 {
+            world.set_gravity(read_gravity(r)?);
+            for _ in 0..read_u32(r)? {
+                let well = read_well(r)?;
+                world.wells_mut().push(well);
+            }
+        }
+        Ok(world)
+    }
+
+    /// Writes the world as an indexed PNG whose palette holds the material colors, top row first
+    pub fn export_png<W : Write>(&self, w : W) -> Result<(), WorldFileError> {
+        let mut encoder = png::Encoder::new(w, self.width() as u32, self.height() as u32);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(self.materials().iter().flat_map(|(_, m)| color_bytes(m.color)).collect::<Vec<u8>>());
+        let mut writer = encoder.write_header()?;
+        let mut data = Vec::with_capacity(self.width() * self.height());
+        for row in self.cells().chunks(self.width()).rev() {
+            data.extend(row.iter().map(|p| p.index()));
+        }
+        writer.write_image_data(&data)?;
+        Ok(())
+    }
+
+    /// Reads a PNG image, mapping each pixel to the built-in material with the nearest color
+    pub fn import_png<R : Read>(r : R) -> Result<SandWorld, WorldFileError> {
+        SandWorld::import_png_with_materials(r, Arc::new(MaterialRegistry::default()))
+    }
+
+    /// Reads a PNG image, mapping each pixel to the material in `materials` with the nearest color
+    pub fn import_png_with_materials<R : Read>(r : R, materials : Arc<MaterialRegistry>) -> Result<SandWorld, WorldFileError> {
+        let mut decoder = png::Decoder::new(r);
+        decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+        let mut reader = decoder.read_info()?;
+        // Before allocating the buffer, whose size comes from the header
+        check_size(reader.info().width as usize, reader.info().height as usize)?;
+        let mut buf = vec![0u8; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        let (width, height) = (info.width as usize, info.height as usize);
+        let channels = info.color_type.samples();
+        let mut world = SandWorld::with_materials(width, height, materials.clone());
+        for (row, line) in buf.chunks(info.line_size).take(height).enumerate() {
+            for x in 0..width {
+                let px = &line[(x * channels)..((x + 1) * channels)];
+                let rgb = match info.color_type {
+                    png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [px[0]; 3],
+                    _ => [px[0], px[1], px[2]]
+                };
+                world.set(x, height - 1 - row, nearest_particle(&materials, rgb));
+            }
+        }
+        Ok(world)
+    }
+}
@@ -0,0 +1,145 @@
+//! Stamps: rectangles of cells copied out of a world, turned or mirrored, and pasted back, plus a
+//! library of named stamps kept as `.sand` files in a directory.
+//!
+//! A stamp is itself a `SandWorld` holding only cells. Emitters and bodies are left behind when
+//! copying, though the cells of a body are copied as plain particles.
+
+use std::{fs::{self, File}, io::{self, BufReader, BufWriter, Write}, path::{Path, PathBuf}, sync::Arc};
+use crate::{SandWorld, Particle, MaterialRegistry, WorldFileError};
+
+impl SandWorld {
+    /// Copies the `width` x `height` rectangle with its bottom-left cell at `(x, y)`, clipped to the
+    /// grid, into a new world with the same materials. `None` if nothing of it is in the grid.
+    pub fn copy_region(&self, x : i32, y : i32, width : usize, height : usize) -> Option<SandWorld> {
+        let (x0, y0, x1, y1) = self.clip(x, y, width, height)?;
+        let mut stamp = SandWorld::with_materials(x1 - x0, y1 - y0, self.materials.clone());
+        for j in y0..y1 {
+            for i in x0..x1 {
+                stamp.set(i - x0, j - y0, self.get(i, j));
+            }
+        }
+        Some(stamp)
+    }
+
+    /// Fills the `width` x `height` rectangle with its bottom-left cell at `(x, y)` with air,
+    /// clipped to the grid
+    pub fn erase_region(&mut self, x : i32, y : i32, width : usize, height : usize) {
+        if let Some((x0, y0, x1, y1)) = self.clip(x, y, width, height) {
+            for j in y0..y1 {
+                for i in x0..x1 { self.set(i, j, Particle::AIR); }
+            }
+        }
+    }
+
+    /// Copies the cells of `stamp` with its bottom-left cell at `(x, y)`, clipped to the grid. Air in
+    /// the stamp leaves the cells under it alone. Particles are taken as indices into this world's
+    /// materials.
+    pub fn paste(&mut self, stamp : &SandWorld, x : i32, y : i32) {
+        for j in 0..stamp.height {
+            for i in 0..stamp.width {
+                let (p, (wx, wy)) = (stamp.get(i, j), (x + i as i32, y + j as i32));
+                let in_grid = wx >= 0 && wy >= 0 && (wx as usize) < self.width && (wy as usize) < self.height;
+                if in_grid && p != Particle::AIR && (p.index() as usize) < self.materials.len() {
+                    self.set(wx as usize, wy as usize, p);
+                }
+            }
+        }
+    }
+
+    /// The cells turned a quarter turn counterclockwise
+    pub fn rotated(&self) -> SandWorld {
+        let mut turned = SandWorld::with_materials(self.height, self.width, self.materials.clone());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                turned.set(self.height - 1 - y, x, self.get(x, y));
+            }
+        }
+        turned
+    }
+
+    /// The cells mirrored left to right
+    pub fn flipped(&self) -> SandWorld {
+        let mut mirrored = SandWorld::with_materials(self.width, self.height, self.materials.clone());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                mirrored.set(self.width - 1 - x, y, self.get(x, y));
+            }
+        }
+        mirrored
+    }
+
+    // Cell ranges `x0..x1`, `y0..y1` of a rectangle clipped to the grid, or `None` if it's outside
+    fn clip(&self, x : i32, y : i32, width : usize, height : usize) -> Option<(usize, usize, usize, usize)> {
+        let clamp = |v : i64, size : usize| v.clamp(0, size as i64) as usize;
+        let (x0, x1) = (clamp(x as i64, self.width), clamp(x as i64 + width as i64, self.width));
+        let (y0, y1) = (clamp(y as i64, self.height), clamp(y as i64 + height as i64, self.height));
+        (x0 < x1 && y0 < y1).then_some((x0, y0, x1, y1))
+    }
+}
+
+/// Named stamps, each saved as `<name>.sand` in a directory
+pub struct StampLibrary {
+    dir : PathBuf,
+    // Sorted by name
+    stamps : Vec<(String, SandWorld)>
+}
+
+impl StampLibrary {
+    /// Reads every `.sand` file in `dir` with `materials`. A directory that doesn't exist yet is an
+    /// empty library, created on the first `save`.
+    pub fn open<P : AsRef<Path>>(dir : P, materials : Arc<MaterialRegistry>) -> Result<StampLibrary, WorldFileError> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut stamps = Vec::new();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(StampLibrary { dir, stamps }),
+            Err(e) => return Err(e.into())
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some("sand".as_ref()) { continue; }
+            let name = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue
+            };
+            let stamp = SandWorld::load_with_materials(&mut BufReader::new(File::open(&path)?), materials.clone())
+                .map_err(|e| WorldFileError::Format(format!("{}: {}", path.display(), e)))?;
+            stamps.push((name, stamp));
+        }
+        stamps.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(StampLibrary { dir, stamps })
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+
+    /// Names and stamps, sorted by name
+    pub fn stamps(&self) -> &[(String, SandWorld)] { &self.stamps }
+
+    /// Adds `stamp` as `name`, replacing any stamp with that name, and writes it to disk. Names must
+    /// be usable as file names.
+    pub fn save(&mut self, name : &str, stamp : &SandWorld) -> Result<(), WorldFileError> {
+        let valid = !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+        if !valid { return Err(WorldFileError::Format(format!("invalid stamp name {:?}", name))); }
+        fs::create_dir_all(&self.dir)?;
+        let mut w = BufWriter::new(File::create(self.path(name))?);
+        stamp.save(&mut w)?;
+        w.flush()?;
+        let stamp = stamp.clone();
+        match self.stamps.binary_search_by(|(n, _)| n.as_str().cmp(name)) {
+            Ok(i) => self.stamps[i].1 = stamp,
+            Err(i) => self.stamps.insert(i, (name.to_string(), stamp))
+        }
+        Ok(())
+    }
+
+    /// Removes the stamp at `index` along with its file
+    pub fn remove(&mut self, index : usize) -> Result<(), WorldFileError> {
+        let (name, _) = self.stamps.remove(index);
+        fs::remove_file(self.path(&name))?;
+        Ok(())
+    }
+
+    fn path(&self, name : &str) -> PathBuf {
+        self.dir.join(format!("{}.sand", name))
+    }
+}
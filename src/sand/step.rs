@@ -0,0 +1,366 @@
+//! Per-tick update of a `SandWorld`.
+//!
+//! Each particle proposes at most one `Move` per tick. Proposals are sorted by destination and one
+//! is picked at random for every contested cell, then executed. `step` does this per chunk: the
+//! grid is split into `CHUNK_SIZE` squares updated in four checkerboard phases, so chunks of the
+//! same phase are a whole chunk apart and never propose moves into the same cell, and can collect
+//! and resolve their moves on separate threads of a pool kept for the whole process. Chunks where
+//! nothing happened are skipped until a neighboring change wakes them. `step_serial` runs the same
+//! rules over the whole grid at once.
+//!
+//! Falling particles (powders and liquids heavier than air) carry a velocity in cells per tick that
+//! builds up under gravity. Once it reaches 2 cells per tick, the particle travels along it as far
+//! as it can displace the cells in its way. When it hits something, its speed turns sideways, which
+//! makes it scatter. Velocity is capped well below half a chunk, so chunks in the same phase still
+//! never propose moves into the same cell. "Down" and "sideways" are relative to the gravity of each
+//! cell, see the `gravity` module.
+//!
+//! Particles of materials with a `Rule` propose moves through it instead, reading the grid as it was
+//! at the start of the tick.
+//!
+//! Rigid bodies move after the particles, see the `body` module.
+
+use std::{cmp::Ordering, ops::Range, sync::Arc};
+use glam::Vec2;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rayon::prelude::*;
+use crate::{SandWorld, Particle, MaterialRegistry, State, Rule, Proposal, Neighborhood, GravityWell};
+use crate::gravity::{gravity_at, turns, turn};
+use crate::pool::pool;
+
+/// Side length of the square chunks updated in parallel by `SandWorld::step`
+pub const CHUNK_SIZE : usize = 32;
+
+/// Default downward acceleration of falling particles, in cells per tick squared
+pub const GRAVITY : f32 = 0.25;
+/// Largest distance a particle can travel in one tick
+pub const MAX_SPEED : f32 = 8.0;
+// Share of the sideways velocity kept each tick
+const DRAG : f32 = 0.9;
+// Share of the speed along gravity turned sideways on impact
+const SCATTER : f32 = 0.5;
+
+const NEIGHBORS : [(i64, i64); 8] = [(-1, -1), (0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0)];
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum Action {
+    /// Exchange the contents of source and destination
+    Swap,
+    /// Turn source and destination into the given particles
+    React(Particle, Particle)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Move {
+    src : (usize, usize),
+    dst : (usize, usize),
+    action : Action,
+    /// Velocity of the moving particle once it arrives
+    velocity : [f32; 2]
+}
+impl PartialEq for Move {
+    fn eq(&self, other: &Self) -> bool { self.dst.0 == other.dst.0 && self.dst.1 == other.dst.1 }
+}
+impl Eq for Move { }
+impl Ord for Move {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.dst.0 == other.dst.0 { self.dst.1.cmp(&other.dst.1) }
+        else { self.dst.0.cmp(&other.dst.0) }
+    }
+}
+impl PartialOrd for Move {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Moves chosen for one chunk in the current phase, kept between phases and ticks to reuse the allocations
+#[derive(Default, Clone)]
+pub(crate) struct ChunkState {
+    moves : Vec<Move>,
+    /// Cells of particles that couldn't move and lose their velocity
+    stops : Vec<usize>,
+    /// Whether the chunk proposed anything or holds particles that may still change on their own
+    active : bool
+}
+
+// Read-only view of the grid while collecting moves, shared between threads
+struct Cells<'a> {
+    grid : &'a [Particle],
+    velocity : &'a [[f32; 2]],
+    touched : &'a [bool],
+    materials : &'a MaterialRegistry,
+    rules : &'a [Option<Arc<dyn Rule>>],
+    /// The grid at the start of the tick, only kept up to date while there are rules
+    previous : &'a [Particle],
+    gravity : Vec2,
+    wells : &'a [GravityWell],
+    width : usize,
+    height : usize
+}
+
+impl Cells<'_> {
+    fn get(&self, x : usize, y : usize) -> Particle { self.grid[y * self.width + x] }
+
+    // Collects one move per particle in the region: the proposal of its rule if it has one, else a
+    // spontaneous decay, else a reaction with a neighbor, else a fast fall along its velocity, else
+    // the first group of neighbor offsets with a cell it can displace. Particles that already moved
+    // this tick are skipped. Returns whether the region is still active.
+    fn collect<R : Rng>(&self, xs : Range<usize>, ys : Range<usize>, rng : &mut R, moves : &mut Vec<Move>, stops : &mut Vec<usize>) -> bool {
+        let (width, height, materials) = (self.width, self.height, self.materials);
+        let air = materials[Particle::AIR].density;
+        let mut active = false;
+        for i in xs {
+            for j in ys.clone() {
+                if self.touched[j * width + i] { continue; }
+                let p = self.get(i, j);
+                let m = &materials[p];
+                let neighbor = |(dx, dy) : (i64, i64)| {
+                    let (x, y) = (i as i64 + dx, j as i64 + dy);
+                    (x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height).then_some((x as usize, y as usize))
+                };
+                if let Some(rule) = self.rules.get(p.index() as usize).and_then(Option::as_ref) {
+                    let cell = Neighborhood { grid : self.previous, materials, width, height, x : i, y : j };
+                    let proposed = rule.update(&cell, rng).and_then(|proposal| {
+                        let (dx, dy, action) = match proposal {
+                            Proposal::Move(dx, dy) => (dx, dy, Action::Swap),
+                            Proposal::Become(into) => (0, 0, Action::React(into, into)),
+                            Proposal::React { dx, dy, into, other_into } => (dx, dy, Action::React(into, other_into))
+                        };
+                        let adjacent = dx.abs() <= 1 && dy.abs() <= 1;
+                        adjacent.then(|| neighbor((dx as i64, dy as i64))).flatten().map(|dst| Move { src: (i, j), dst, action, velocity: [0.0; 2] })
+                    });
+                    if let Some(proposed) = proposed {
+                        moves.push(proposed);
+                        active = true;
+                    }
+                    continue;
+                }
+                if let Some(decay) = m.decay {
+                    active = true;
+                    if rng.gen::<f32>() < decay.chance {
+                        moves.push(Move { src: (i, j), dst: (i, j), action: Action::React(decay.into, decay.into), velocity: [0.0; 2] });
+                        continue;
+                    }
+                }
+                if !m.reactions.is_empty() {
+                    let start = rng.gen_range(0..NEIGHBORS.len());
+                    let reaction = (0..NEIGHBORS.len()).filter_map(|k| neighbor(NEIGHBORS[(start + k) % NEIGHBORS.len()])).find_map(|(x, y)| {
+                        let q = self.get(x, y);
+                        active |= m.reactions.iter().any(|r| r.with == q);
+                        m.reactions.iter().find(|r| r.with == q && rng.gen::<f32>() < r.chance).map(|r| ((x, y), r))
+                    });
+                    if let Some((dst, r)) = reaction {
+                        moves.push(Move { src: (i, j), dst, action: Action::React(r.into, r.other_into), velocity: [0.0; 2] });
+                        continue;
+                    }
+                }
+                let falls = matches!(m.state, State::Powder | State::Liquid) && m.density > air && !m.moves.is_empty();
+                // Velocities are worked out with gravity pointing down, along `-up`, and turned back
+                let g = gravity_at(self.gravity, self.wells, Vec2::new(i as f32, j as f32));
+                let up = if g == Vec2::ZERO { Vec2::Y } else { -g.normalize() };
+                let side = Vec2::new(up.y, -up.x);
+                let to_grid = |[x, y] : [f32; 2]| {
+                    let v = side * x + up * y;
+                    [v.x.clamp(-MAX_SPEED, MAX_SPEED), v.y.clamp(-MAX_SPEED, MAX_SPEED)]
+                };
+                let [vx, vy] = self.velocity[j * width + i];
+                let (along, across) = (Vec2::new(vx, vy).dot(side), Vec2::new(vx, vy).dot(up));
+                let local = if falls { [(along * DRAG).clamp(-MAX_SPEED, MAX_SPEED), (across - g.length()).max(-MAX_SPEED)] } else { [0.0; 2] };
+                let v = to_grid(local);
+                let steps = v[0].abs().max(v[1].abs()).floor() as i64;
+                if steps >= 2 {
+                    // March along the velocity one cell at a time, stopping before the first cell it can't displace
+                    let reached = (1..=steps).map(|k| {
+                        (((v[0] * k as f32) / steps as f32).round() as i64, ((v[1] * k as f32) / steps as f32).round() as i64)
+                    }).map_while(|d| neighbor(d).filter(|&(x, y)| materials.can_displace(p, self.get(x, y)))).enumerate().last();
+                    if let Some((k, dst)) = reached {
+                        let velocity = if k + 1 == steps as usize { v } else { to_grid(impact(local, 0, rng)) };
+                        moves.push(Move { src: (i, j), dst, action: Action::Swap, velocity });
+                        active = true;
+                        continue;
+                    }
+                }
+                let mut moved = false;
+                // Without gravity nothing falls, flows or rises
+                let (groups, turns) = match turns(g) {
+                    Some(turns) => (&m.moves[..], turns),
+                    None => (&[][..], 0)
+                };
+                for group in groups {
+                    let start = if group.len() > 1 { rng.gen_range(0..group.len()) } else { 0 };
+                    let dst = (0..group.len()).map(|k| group[(start + k) % group.len()]).find_map(|(dx, dy)| {
+                        let (tx, ty) = turn((dx, dy), turns);
+                        neighbor((tx as i64, ty as i64)).filter(|&(x, y)| materials.can_displace(p, self.get(x, y))).map(|dst| (dst, dx, dy))
+                    });
+                    if let Some((dst, dx, dy)) = dst {
+                        // Keep accelerating while falling straight, scatter when deflected
+                        let velocity = if !falls || (dx == 0 && dy < 0) { v } else { to_grid(impact(local, dx, rng)) };
+                        moves.push(Move { src: (i, j), dst, action: Action::Swap, velocity });
+                        active = true;
+                        moved = true;
+                        break;
+                    }
+                }
+                if !moved && (vx != 0.0 || vy != 0.0) {
+                    stops.push(j * width + i);
+                    active = true;
+                }
+            }
+        }
+        active
+    }
+}
+
+// Velocity after hitting something: the speed is scattered sideways, towards `dx` or a random side
+fn impact<R : Rng>(v : [f32; 2], dx : i32, rng : &mut R) -> [f32; 2] {
+    let side = if dx != 0 { dx.signum() as f32 } else if rng.gen::<bool>() { 1.0 } else { -1.0 };
+    [side * (v[0].abs() + v[1].abs() * SCATTER).min(MAX_SPEED), 0.0]
+}
+
+// Sorts moves by destination and keeps one randomly picked move per destination
+fn resolve<R : Rng>(moves : &mut Vec<Move>, rng : &mut R) {
+    moves.sort_unstable();
+    let (mut chosen, mut start) = (0, 0);
+    while start < moves.len() {
+        let mut end = start + 1;
+        while end < moves.len() && moves[end] == moves[start] { end += 1; }
+        moves[chosen] = moves[rng.gen_range(start..end)];
+        chosen += 1;
+        start = end;
+    }
+    moves.truncate(chosen);
+}
+
+impl SandWorld {
+    /// Advances the simulation by one tick, updating awake chunks in parallel
+    pub fn step<R : Rng>(&mut self, rng : &mut R) {
+        self.touched.fill(false);
+        self.moved = 0;
+        self.snapshot_for_rules();
+        // Every chunk gets its own generator seeded from `rng`, so results don't depend on thread scheduling
+        let seed : u64 = rng.gen();
+        let (chunks_x, width, height) = (self.chunks_x, self.width, self.height);
+        let scheduled = |c : usize, phase : usize| (c % chunks_x) % 2 == phase % 2 && (c / chunks_x) % 2 == phase / 2;
+        let pool = (self.threads > 1).then(|| pool(self.threads));
+        for phase in 0..4 {
+            let cells = Cells {
+                grid : &self.grid, velocity : &self.velocity, touched : &self.touched, materials : &self.materials,
+                rules : &self.rules, previous : &self.rule_grid, gravity : self.gravity, wells : &self.wells, width, height
+            };
+            let dirty = &self.dirty;
+            let awake = (0..self.chunks.len()).filter(|&c| dirty[c] && scheduled(c, phase)).count();
+            let run = |(c, state) : (usize, &mut ChunkState)| {
+                if !(dirty[c] && scheduled(c, phase)) { return; }
+                let (cx, cy) = (c % chunks_x, c / chunks_x);
+                let xs = (cx * CHUNK_SIZE)..((cx + 1) * CHUNK_SIZE).min(width);
+                let ys = (cy * CHUNK_SIZE)..((cy + 1) * CHUNK_SIZE).min(height);
+                let mut rng = StdRng::seed_from_u64(seed ^ (c as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                state.moves.clear();
+                state.stops.clear();
+                state.active = cells.collect(xs, ys, &mut rng, &mut state.moves, &mut state.stops);
+                resolve(&mut state.moves, &mut rng);
+            };
+            match &pool {
+                Some(pool) if awake > 1 => pool.install(|| self.chunks.par_iter_mut().enumerate().for_each(run)),
+                _ => self.chunks.iter_mut().enumerate().for_each(run)
+            }
+            // Apply serially in chunk order; chunks of one phase don't share destinations
+            for c in 0..self.chunks.len() {
+                if !(self.dirty[c] && scheduled(c, phase)) { continue; }
+                for &cell in &self.chunks[c].stops { self.velocity[cell] = [0.0; 2]; }
+                let moves = std::mem::take(&mut self.chunks[c].moves);
+                self.apply(&moves);
+                self.chunks[c].moves = moves;
+                if self.chunks[c].active { self.next_dirty[c] = true; }
+            }
+        }
+        std::mem::swap(&mut self.dirty, &mut self.next_dirty);
+        self.next_dirty.fill(false);
+        self.update_bodies();
+        self.update_heat();
+    }
+
+    /// Advances the simulation by one tick, collecting and resolving moves for the whole grid at
+    /// once on the calling thread; the reference for `step`
+    pub fn step_serial<R : Rng>(&mut self, rng : &mut R) {
+        self.touched.fill(false);
+        self.moved = 0;
+        self.snapshot_for_rules();
+        let cells = Cells {
+            grid : &self.grid, velocity : &self.velocity, touched : &self.touched, materials : &self.materials,
+            rules : &self.rules, previous : &self.rule_grid, gravity : self.gravity, wells : &self.wells,
+            width : self.width, height : self.height
+        };
+        let (mut moves, mut stops) = (std::mem::take(&mut self.moves), Vec::new());
+        moves.clear();
+        cells.collect(0..self.width, 0..self.height, rng, &mut moves, &mut stops);
+        resolve(&mut moves, rng);
+        for &cell in &stops { self.velocity[cell] = [0.0; 2]; }
+        self.apply(&moves);
+        self.moves = moves;
+        // Chunks weren't tracked, so let the next parallel step look at everything
+        self.dirty.fill(true);
+        self.next_dirty.fill(false);
+        self.update_bodies();
+        self.update_heat();
+    }
+
+    // Copies the grid for rules to read while it changes under them
+    fn snapshot_for_rules(&mut self) {
+        if self.rules.is_empty() { self.rule_grid.clear(); } else { self.rule_grid.clone_from(&self.grid); }
+    }
+
+    // Executes resolved moves. Cells already involved in an executed move are marked, so a particle
+    // that was displaced (e.g. water swapped out by sand) doesn't also carry out its own move from
+    // the old cell, and moves whose destination has since changed to something that can't be
+    // displaced are dropped. Reactions need both cells untouched, as they were when proposed.
+    fn apply(&mut self, moves : &[Move]) {
+        let width = self.width;
+        for m in moves {
+            let src = m.src.1 * width + m.src.0;
+            let dst = m.dst.1 * width + m.dst.0;
+            match m.action {
+                Action::Swap => {
+                    if self.touched[src] || !self.materials.can_displace(self.grid[src], self.grid[dst]) { continue; }
+                    self.grid.swap(src, dst);
+                    self.velocity.swap(src, dst);
+                    self.velocity[dst] = m.velocity;
+                    self.temperature.swap(src, dst);
+                    self.shade.swap(src, dst);
+                }
+                Action::React(into, other_into) => {
+                    if self.touched[src] || self.touched[dst] { continue; }
+                    self.grid[src] = into;
+                    self.grid[dst] = other_into;
+                    self.velocity[src] = [0.0; 2];
+                    self.velocity[dst] = [0.0; 2];
+                    // Products start at least as hot as their material, e.g. fire spreading into oil
+                    self.temperature[src] = self.temperature[src].max(self.materials[into].temperature);
+                    self.temperature[dst] = self.temperature[dst].max(self.materials[other_into].temperature);
+                }
+            }
+            self.touched[src] = true;
+            self.touched[dst] = true;
+            self.moved += 1;
+            self.wake_next(m.src.0, m.src.1);
+            self.wake_next(m.dst.0, m.dst.1);
+            self.wake_heat(m.src.0, m.src.1);
+            self.wake_heat(m.dst.0, m.dst.1);
+        }
+    }
+
+    // Marks the chunks around a changed cell to be updated next tick
+    fn wake_next(&mut self, x : usize, y : usize) {
+        mark_around(&mut self.next_dirty, self.chunks_x, self.width, self.height, x, y);
+    }
+}
+
+// Marks every chunk containing `(x, y)` or one of its neighbors
+pub(crate) fn mark_around(dirty : &mut [bool], chunks_x : usize, width : usize, height : usize, x : usize, y : usize) {
+    let xs = (x.saturating_sub(1) / CHUNK_SIZE)..=((x + 1).min(width - 1) / CHUNK_SIZE);
+    let ys = (y.saturating_sub(1) / CHUNK_SIZE)..=((y + 1).min(height - 1) / CHUNK_SIZE);
+    for cy in ys {
+        for cx in xs.clone() { dirty[cy * chunks_x + cx] = true; }
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+use rand::Rng;
+use glam::Vec2;
+use crate::{Particle, MaterialRegistry, Emitter, Body, Rule, GravityWell, GRAVITY};
+use crate::step::{Move, ChunkState, CHUNK_SIZE, mark_around};
+use crate::heat::HeatChunk;
+
+/// Largest width or height of a world read from a file or replay
+pub const MAX_SIZE : usize = 4096;
+
+/// Shape painted around each point of a stroke
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum Brush {
+    #[default]
+    Circle,
+    Square
+}
+
+/// Grid of particles, indexed as `(x, y)` with `y = 0` at the bottom and stored row by row
+#[derive(Clone)]
+pub struct SandWorld {
+    pub(crate) materials : Arc<MaterialRegistry>,
+    pub(crate) width : usize,
+    pub(crate) height : usize,
+    pub(crate) grid : Vec<Particle>,
+    /// Velocity of the particle in each cell, in cells per tick
+    pub(crate) velocity : Vec<[f32; 2]>,
+    pub(crate) temperature : Vec<f32>,
+    /// Color variation of the particle in each cell, picked when it's placed and carried along as it moves
+    pub(crate) shade : Vec<u8>,
+    /// Number of particles placed so far, which picks the next shade
+    pub(crate) placed : u32,
+    pub(crate) emitters : Vec<Emitter>,
+    pub(crate) bodies : Vec<Body>,
+    pub(crate) gravity : Vec2,
+    pub(crate) wells : Vec<GravityWell>,
+    /// Rule of each material that has one, indexed by particle
+    pub(crate) rules : Vec<Option<Arc<dyn Rule>>>,
+    /// Copy of the grid at the start of the tick, read by rules
+    pub(crate) rule_grid : Vec<Particle>,
+    pub(crate) moves : Vec<Move>,
+    /// Moves executed during the last tick
+    pub(crate) moved : usize,
+    pub(crate) touched : Vec<bool>,
+    /// Number of chunks per row
+    pub(crate) chunks_x : usize,
+    pub(crate) chunks : Vec<ChunkState>,
+    /// Chunks to update this tick, and those woken up for the next one
+    pub(crate) dirty : Vec<bool>,
+    pub(crate) next_dirty : Vec<bool>,
+    /// Chunks whose temperatures aren't settled, to update at the end of this tick
+    pub(crate) heat_dirty : Vec<bool>,
+    pub(crate) heat_chunks : Vec<HeatChunk>,
+    pub(crate) threads : usize
+}
+
+impl SandWorld {
+    /// Creates an empty world using the built-in materials
+    pub fn new(width : usize, height : usize) -> SandWorld {
+        SandWorld::with_materials(width, height, Arc::new(MaterialRegistry::default()))
+    }
+
+    pub fn with_materials(width : usize, height : usize, materials : Arc<MaterialRegistry>) -> SandWorld {
+        assert!(width > 0 && height > 0, "SandWorld dimensions must be non-zero");
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let mut world = SandWorld {
+            materials,
+            width,
+            height,
+            grid : vec![Particle::AIR; width * height],
+            velocity : vec![[0.0; 2]; width * height],
+            temperature : Vec::new(),
+            shade : vec![0; width * height],
+            placed : 0,
+            emitters : Vec::new(),
+            bodies : Vec::new(),
+            gravity : Vec2::new(0.0, -GRAVITY),
+            wells : Vec::new(),
+            rules : Vec::new(),
+            rule_grid : Vec::new(),
+            moves : Vec::new(),
+            moved : 0,
+            touched : vec![false; width * height],
+            chunks_x : 0,
+            chunks : Vec::new(),
+            dirty : Vec::new(),
+            next_dirty : Vec::new(),
+            heat_dirty : Vec::new(),
+            heat_chunks : Vec::new(),
+            threads
+        };
+        world.temperature = vec![world.materials[Particle::AIR].temperature; width * height];
+        world.reset_chunks();
+        world
+    }
+
+    // Recreates the chunk bookkeeping for the current dimensions, with every chunk awake
+    fn reset_chunks(&mut self) {
+        self.chunks_x = self.width.div_ceil(CHUNK_SIZE);
+        let count = self.chunks_x * self.height.div_ceil(CHUNK_SIZE);
+        self.chunks = (0..count).map(|_| ChunkState::default()).collect();
+        self.dirty = vec![true; count];
+        self.next_dirty = vec![false; count];
+        self.heat_dirty = vec![true; count];
+        self.heat_chunks = vec![HeatChunk::default(); count];
+    }
+
+    /// Sets how many threads `step` may use; 1 updates every chunk on the calling thread
+    pub fn set_threads(&mut self, threads : usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Number of chunks `step` will update next tick; settled regions are skipped
+    pub fn awake_chunks(&self) -> usize {
+        self.dirty.iter().filter(|&&d| d).count()
+    }
+
+    pub fn materials(&self) -> &Arc<MaterialRegistry> { &self.materials }
+
+    /// Makes the particles of `p` follow `rule` instead of their material's built-in behavior
+    pub fn set_rule(&mut self, p : Particle, rule : Arc<dyn Rule>) {
+        self.rules.resize(self.materials.len(), None);
+        self.rules[p.index() as usize] = Some(rule);
+        self.dirty.fill(true);
+    }
+
+    /// Restores the built-in behavior of `p`
+    pub fn remove_rule(&mut self, p : Particle) {
+        if let Some(rule) = self.rules.get_mut(p.index() as usize) { *rule = None; }
+        self.rules.truncate(self.rules.iter().rposition(Option::is_some).map_or(0, |last| last + 1));
+        self.dirty.fill(true);
+    }
+
+    pub fn rule(&self, p : Particle) -> Option<&Arc<dyn Rule>> {
+        self.rules.get(p.index() as usize).and_then(Option::as_ref)
+    }
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    /// All cells, row by row from the bottom
+    pub fn cells(&self) -> &[Particle] { &self.grid }
+
+    pub fn get(&self, x : usize, y : usize) -> Particle { self.grid[y * self.width + x] }
+    /// Velocity of the particle at `(x, y)`, in cells per tick
+    pub fn velocity(&self, x : usize, y : usize) -> [f32; 2] { self.velocity[y * self.width + x] }
+    /// Color variation of every cell, row by row from the bottom, used by `render::GridRenderer`
+    pub fn shades(&self) -> &[u8] { &self.shade }
+    pub fn set(&mut self, x : usize, y : usize, p : Particle) {
+        self.grid[y * self.width + x] = p;
+        self.velocity[y * self.width + x] = [0.0; 2];
+        self.temperature[y * self.width + x] = self.materials[p].temperature;
+        // Consecutive multiples of the golden ratio spread evenly, so neighbors painted together differ
+        self.placed = self.placed.wrapping_add(1);
+        self.shade[y * self.width + x] = (self.placed.wrapping_mul(0x9e37_79b9) >> 24) as u8;
+        self.wake_heat(x, y);
+        mark_around(&mut self.dirty, self.chunks_x, self.width, self.height, x, y);
+    }
+
+    /// Resets every cell to `Particle::AIR` and removes all bodies
+    pub fn clear(&mut self) {
+        self.grid.fill(Particle::AIR);
+        self.bodies.clear();
+        self.velocity.fill([0.0; 2]);
+        self.temperature.fill(self.materials[Particle::AIR].temperature);
+        self.heat_dirty.fill(true);
+        self.dirty.fill(true);
+    }
+
+    /// Changes the grid dimensions, keeping the cells that still fit anchored to the bottom-left corner.
+    /// Bodies that don't fit any more are left behind as plain cells.
+    pub fn resize(&mut self, width : usize, height : usize) {
+        assert!(width > 0 && height > 0, "SandWorld dimensions must be non-zero");
+        let mut grid = vec![Particle::AIR; width * height];
+        let mut temperature = vec![self.materials[Particle::AIR].temperature; width * height];
+        let mut shade = vec![0; width * height];
+        for y in 0..height.min(self.height) {
+            for x in 0..width.min(self.width) {
+                grid[y * width + x] = self.get(x, y);
+                temperature[y * width + x] = self.temperature(x, y);
+                shade[y * width + x] = self.shade[y * self.width + x];
+            }
+        }
+        self.width = width;
+        self.height = height;
+        self.grid = grid;
+        self.velocity = vec![[0.0; 2]; width * height];
+        self.temperature = temperature;
+        self.shade = shade;
+        self.touched = vec![false; width * height];
+        self.reset_chunks();
+        for body in std::mem::take(&mut self.bodies) { self.adopt_body(body); }
+    }
+
+    /// Fills every cell within `radius` of `(x, y)` with `p`, clipped to the grid
+    pub fn paint(&mut self, x : i32, y : i32, radius : i32, p : Particle) {
+        self.paint_brush(x, y, radius, Brush::Circle, p);
+    }
+
+    /// Fills the cells covered by `brush` of `radius` centered on `(x, y)` with `p`, clipped to the grid
+    pub fn paint_brush(&mut self, x : i32, y : i32, radius : i32, brush : Brush, p : Particle) {
+        // In i64 so no radius or position overflows
+        let (x, y, radius) = (x as i64, y as i64, radius as i64);
+        let (width, height) = (self.width as i64, self.height as i64);
+        for i in (x - radius).max(0)..=(x + radius).min(width - 1) {
+            for j in (y - radius).max(0)..=(y + radius).min(height - 1) {
+                if brush == Brush::Square || (i - x).pow(2) + (j - y).pow(2) <= radius.pow(2) {
+                    self.set(i as usize, j as usize, p);
+                }
+            }
+        }
+    }
+
+    /// Paints a stroke of brush stamps from `from` to `to`, so fast drags don't leave gaps
+    pub fn paint_line(&mut self, from : (i32, i32), to : (i32, i32), radius : i32, brush : Brush, p : Particle) {
+        let (from, to) = ((from.0 as i64, from.1 as i64), (to.0 as i64, to.1 as i64));
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).max(1);
+        for s in 0..=steps {
+            let x = from.0 + (to.0 - from.0) * s / steps;
+            let y = from.1 + (to.1 - from.1) * s / steps;
+            self.paint_brush(x as i32, y as i32, radius, brush, p);
+        }
+    }
+
+    /// Number of cells holding `p`
+    pub fn count(&self, p : Particle) -> usize {
+        self.grid.iter().filter(|&&c| c == p).count()
+    }
+
+    /// Number of cells holding each material, indexed by particle
+    pub fn counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.materials.len()];
+        for p in &self.grid { counts[p.index() as usize] += 1; }
+        counts
+    }
+
+    /// Number of particles that moved or reacted during the last tick
+    pub fn last_moves(&self) -> usize { self.moved }
+
+    /// Places `p` in a random column of the top row
+    pub fn spawn<R : Rng>(&mut self, rng : &mut R, p : Particle) {
+        let x = rng.gen_range(0..self.width);
+        self.set(x, self.height - 1, p);
+    }
+}
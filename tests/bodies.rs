@@ -0,0 +1,202 @@
+use glam::Vec2;
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle, Body, Simulation, Command, Replay};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+const STONE : Particle = Particle(3);
+const WOOD : Particle = Particle(4);
+
+fn run(world : &mut SandWorld, ticks : usize) {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..ticks { world.step(&mut rng); }
+}
+
+// Lowest and highest row holding `p`
+fn rows(world : &SandWorld, p : Particle) -> (usize, usize) {
+    let ys = (0..world.height()).filter(|&y| (0..world.width()).any(|x| world.get(x, y) == p));
+    ys.fold((usize::MAX, 0), |(lo, hi), y| (lo.min(y), hi.max(y)))
+}
+
+#[test]
+fn rectangle_and_polygon_shapes() {
+    let body = Body::rectangle(Vec2::new(4.5, 2.5), Vec2::new(0.5, 0.5), STONE).unwrap();
+    assert_eq!(body.area(), 8.0);
+    assert_eq!(body.position, Vec2::new(2.5, 1.5));
+    // Points inside the hull don't change it
+    let points = [Vec2::new(0.0, 0.0), Vec2::new(4.0, 0.0), Vec2::new(2.0, 1.0), Vec2::new(0.0, 2.0), Vec2::new(4.0, 2.0), Vec2::new(1.0, 1.0)];
+    let hull = Body::polygon(&points, STONE).unwrap();
+    assert_eq!(hull.area(), 8.0);
+    assert_eq!(hull.vertices().count(), 4);
+    assert!(Body::polygon(&[Vec2::ZERO, Vec2::X, Vec2::new(2.0, 0.0)], STONE).is_none());
+    assert!(Body::rectangle(Vec2::ZERO, Vec2::new(0.5, 0.5), STONE).is_none());
+}
+
+#[test]
+fn add_body_stamps_cells_and_pushes_particles_aside() {
+    let mut world = SandWorld::new(20, 20);
+    world.paint(10, 10, 1, WATER);
+    let water = world.count(WATER);
+    assert!(world.add_body(Body::rectangle(Vec2::new(7.5, 7.5), Vec2::new(12.5, 12.5), WOOD).unwrap()));
+    assert_eq!(world.count(WOOD), 25);
+    assert_eq!(world.count(WATER), water);
+    // Only solids make bodies, and they can't overlap other solids
+    assert!(!world.add_body(Body::rectangle(Vec2::new(0.5, 0.5), Vec2::new(3.5, 3.5), SAND).unwrap()));
+    assert!(!world.add_body(Body::rectangle(Vec2::new(10.5, 10.5), Vec2::new(15.5, 15.5), STONE).unwrap()));
+    assert_eq!(world.bodies().len(), 1);
+}
+
+#[test]
+fn destroyed_body_leaves_its_remaining_cells() {
+    let mut world = SandWorld::new(20, 20);
+    assert!(world.add_body(Body::rectangle(Vec2::new(5.5, 0.5), Vec2::new(9.5, 4.5), STONE).unwrap()));
+    assert_eq!(world.count(STONE), 16);
+    let cells : Vec<usize> = (0..400).filter(|&c| world.cells()[c] == STONE).take(9).collect();
+    for c in cells { world.set(c % 20, c / 20, Particle::AIR); }
+    run(&mut world, 1);
+    assert!(world.bodies().is_empty());
+    assert_eq!(world.count(STONE), 7);
+}
+
+#[test]
+fn body_falls_and_rests_on_the_floor() {
+    let mut world = SandWorld::new(30, 40);
+    world.add_body(Body::rectangle(Vec2::new(9.5, 29.5), Vec2::new(19.5, 34.5), STONE).unwrap());
+    run(&mut world, 100);
+    assert_eq!(rows(&world, STONE), (0, 4));
+    assert_eq!(world.count(STONE), 50);
+    let body = &world.bodies()[0];
+    assert_eq!(body.velocity, Vec2::ZERO);
+    assert!(body.angle.abs() < 1e-3);
+    // Resting bodies leave their chunks asleep
+    run(&mut world, 5);
+    assert_eq!(world.awake_chunks(), 0);
+}
+
+#[test]
+fn tilted_body_tips_over_onto_its_side() {
+    let mut world = SandWorld::new(40, 40);
+    let mut body = Body::rectangle(Vec2::new(17.5, 5.5), Vec2::new(21.5, 25.5), STONE).unwrap();
+    body.angle = 0.3;
+    world.add_body(body);
+    run(&mut world, 300);
+    // Lying down, the 4 x 20 rectangle is much wider than high
+    let (lo, hi) = rows(&world, STONE);
+    assert_eq!(lo, 0);
+    assert!(hi < 8, "still standing up to row {}", hi);
+}
+
+#[test]
+fn body_rests_on_sand_pile() {
+    let mut world = SandWorld::new(30, 40);
+    for x in 0..30 {
+        for y in 0..10 { world.set(x, y, SAND); }
+    }
+    world.add_body(Body::rectangle(Vec2::new(9.5, 14.5), Vec2::new(19.5, 18.5), STONE).unwrap());
+    run(&mut world, 100);
+    assert_eq!(rows(&world, STONE).0, 10);
+    assert_eq!(world.count(SAND), 300);
+}
+
+#[test]
+fn dropped_body_digs_into_sand() {
+    let mut world = SandWorld::new(30, 60);
+    for x in 0..30 {
+        for y in 0..20 { world.set(x, y, SAND); }
+    }
+    world.add_body(Body::rectangle(Vec2::new(11.5, 54.5), Vec2::new(17.5, 59.5), STONE).unwrap());
+    run(&mut world, 150);
+    let (lo, _) = rows(&world, STONE);
+    assert!(lo < 20, "resting on top at row {}", lo);
+    assert!(lo > 0);
+}
+
+#[test]
+fn wood_floats_and_stone_sinks() {
+    let mut world = SandWorld::new(60, 50);
+    for x in 0..60 {
+        for y in 0..30 { world.set(x, y, WATER); }
+    }
+    let water = world.count(WATER);
+    world.add_body(Body::rectangle(Vec2::new(5.5, 35.5), Vec2::new(15.5, 39.5), WOOD).unwrap());
+    world.add_body(Body::rectangle(Vec2::new(40.5, 35.5), Vec2::new(50.5, 39.5), STONE).unwrap());
+    run(&mut world, 400);
+    let (lo, hi) = rows(&world, WOOD);
+    assert!(lo < 30 && hi >= 28, "wood between rows {} and {}", lo, hi);
+    assert_eq!(rows(&world, STONE).0, 0);
+    assert_eq!(world.count(WATER), water);
+}
+
+#[test]
+fn body_does_not_destroy_particles_without_room() {
+    // A tilted block in a tank filled up to the top row, so the water has little room to move to
+    // as the block turns and sinks
+    let mut world = SandWorld::new(24, 24);
+    let mut body = Body::rectangle(Vec2::new(6.5, 12.5), Vec2::new(16.5, 16.5), STONE).unwrap();
+    body.angle = 0.9;
+    assert!(world.add_body(body));
+    for x in 0..24 {
+        for y in 0..23 {
+            if world.get(x, y) == Particle::AIR { world.set(x, y, WATER); }
+        }
+    }
+    let water = world.count(WATER);
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        world.step(&mut rng);
+        assert_eq!(world.count(WATER), water);
+    }
+    // Nor can a body be added where it would have to destroy particles
+    for x in 0..24 { world.set(x, 23, WATER); }
+    assert!(!world.add_body(Body::rectangle(Vec2::new(0.5, 20.5), Vec2::new(3.5, 23.5), WOOD).unwrap()));
+}
+
+#[test]
+fn bodies_stack() {
+    let mut world = SandWorld::new(30, 60);
+    world.add_body(Body::rectangle(Vec2::new(4.5, 0.5), Vec2::new(24.5, 4.5), STONE).unwrap());
+    world.add_body(Body::rectangle(Vec2::new(9.5, 30.5), Vec2::new(19.5, 34.5), WOOD).unwrap());
+    run(&mut world, 150);
+    assert_eq!(rows(&world, STONE), (0, 3));
+    assert_eq!(rows(&world, WOOD), (4, 7));
+}
+
+#[test]
+fn bodies_are_saved_and_replayed() {
+    let mut world = SandWorld::new(30, 40);
+    world.add_body(Body::rectangle(Vec2::new(9.5, 29.5), Vec2::new(19.5, 34.5), STONE).unwrap());
+    run(&mut world, 10);
+    let mut file = Vec::new();
+    world.save(&mut file).unwrap();
+    let mut loaded = SandWorld::load(&mut &file[..]).unwrap();
+    assert_eq!(loaded.bodies(), world.bodies());
+    run(&mut world, 50);
+    run(&mut loaded, 50);
+    assert_eq!(loaded.cells(), world.cells());
+
+    let mut simulation = Simulation::recorded(SandWorld::new(30, 40), 3);
+    let body = Body::polygon(&[Vec2::new(10.0, 30.0), Vec2::new(20.0, 31.0), Vec2::new(14.0, 38.0)], WOOD).unwrap();
+    simulation.apply(Command::AddBody(Box::new(body)));
+    for _ in 0..60 { simulation.tick(); }
+    let mut file = Vec::new();
+    simulation.replay().unwrap().save(&mut file).unwrap();
+    let replayed = Replay::load(&mut &file[..]).unwrap().play();
+    assert_eq!(replayed.world().cells(), simulation.world().cells());
+    assert_eq!(replayed.world().bodies(), simulation.world().bodies());
+}
+
+#[test]
+fn bodies_with_invalid_shapes_are_rejected() {
+    let mut world = SandWorld::new(30, 30);
+    assert!(world.add_body(Body::rectangle(Vec2::new(10.0, 10.0), Vec2::new(16.0, 14.0), STONE).unwrap()));
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    assert!(SandWorld::load(&mut &bytes[..]).is_ok());
+    // Area, a corner and the position of the 6x4 rectangle
+    for (from, to) in [(24.0f32, 0.0f32), (24.0, -24.0), (-3.0, -1e9), (13.0, 1e9)] {
+        let mut bytes = bytes.clone();
+        let at = bytes.windows(4).position(|w| w == from.to_le_bytes()).unwrap();
+        bytes[at..at + 4].copy_from_slice(&to.to_le_bytes());
+        assert!(SandWorld::load(&mut &bytes[..]).is_err(), "{} read as {}", from, to);
+    }
+}
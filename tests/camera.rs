@@ -0,0 +1,56 @@
+use glam::{Vec2, Vec3};
+use sand::render::{Camera, grid_to_ndc};
+
+#[test]
+fn fitted_camera_shows_the_whole_grid() {
+    let camera = Camera::fit(200.0, 100.0);
+    assert_eq!(camera.transform(200.0, 100.0), grid_to_ndc(200.0, 100.0));
+    assert_eq!(camera.to_grid(200.0, 100.0, Vec2::new(-1.0, -1.0)), Vec2::ZERO);
+    assert_eq!(camera.to_grid(200.0, 100.0, Vec2::new(1.0, 1.0)), Vec2::new(200.0, 100.0));
+}
+
+#[test]
+fn zoom_keeps_the_point_under_the_cursor() {
+    let mut camera = Camera::fit(200.0, 100.0);
+    let at = Vec2::new(150.0, 30.0);
+    let ndc = camera.transform(200.0, 100.0).transform_point3(Vec3::new(at.x, at.y, 0.0));
+    camera.zoom_at(at, 4.0, 200.0, 100.0);
+    assert_eq!(camera.zoom, 4.0);
+    let after = camera.to_grid(200.0, 100.0, Vec2::new(ndc.x, ndc.y));
+    assert!((after - at).length() < 1e-3, "{:?} moved to {:?}", at, after);
+    // Zooming back out recenters the whole grid
+    camera.zoom_at(at, 0.1, 200.0, 100.0);
+    assert_eq!(camera, Camera::fit(200.0, 100.0));
+}
+
+#[test]
+fn pan_stays_within_the_grid() {
+    let mut camera = Camera::fit(200.0, 100.0);
+    camera.zoom_at(Vec2::new(100.0, 50.0), 2.0, 200.0, 100.0);
+    camera.pan(Vec2::new(100.0, 50.0), Vec2::new(80.0, 50.0), 200.0, 100.0);
+    assert_eq!(camera.center, Vec2::new(120.0, 50.0));
+    camera.pan(Vec2::ZERO, Vec2::new(-1000.0, -1000.0), 200.0, 100.0);
+    assert_eq!(camera.center, Vec2::new(150.0, 75.0));
+    assert_eq!(camera.to_grid(200.0, 100.0, Vec2::new(1.0, 1.0)), Vec2::new(200.0, 100.0));
+}
+
+#[test]
+fn cells_stay_square_in_any_viewport() {
+    // A square window shows a wide grid with bars above and below
+    let mut camera = Camera { aspect : 1.0, ..Camera::fit(200.0, 100.0) };
+    let transform = camera.transform(200.0, 100.0);
+    assert_eq!(transform.transform_point3(Vec3::ZERO), Vec3::new(-1.0, -0.5, 0.0));
+    assert_eq!(transform.transform_point3(Vec3::new(200.0, 100.0, 0.0)), Vec3::new(1.0, 0.5, 0.0));
+    camera.pan(Vec2::ZERO, Vec2::new(0.0, 10.0), 200.0, 100.0);
+    assert_eq!(camera.center, Vec2::new(100.0, 50.0));
+
+    // A wide window shows it with bars on the sides, still centered while they're visible
+    camera.aspect = 4.0;
+    let corner = camera.to_grid(200.0, 100.0, Vec2::new(1.0, 1.0));
+    assert!((corner - Vec2::new(300.0, 100.0)).length() < 1e-3, "top-right corner at {:?}", corner);
+    camera.zoom_at(Vec2::new(190.0, 90.0), 1.5, 200.0, 100.0);
+    camera.pan(Vec2::ZERO, Vec2::new(-1000.0, -1000.0), 200.0, 100.0);
+    assert_eq!(camera.center.x, 100.0);
+    let top = camera.to_grid(200.0, 100.0, Vec2::new(0.0, 1.0)).y;
+    assert!((top - 100.0).abs() < 1e-3, "view reaches up to {}", top);
+}
@@ -0,0 +1,103 @@
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle, Emitter, EmitterKind, WorldFileError};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+
+#[test]
+fn spawner_matches_old_top_row_spawn() {
+    let mut a = SandWorld::new(30, 20);
+    let mut b = SandWorld::new(30, 20);
+    let spawner = Emitter::spawner(&a, SAND);
+    a.emitters_mut().push(spawner);
+    let (mut rng_a, mut rng_b) = (StdRng::seed_from_u64(1), StdRng::seed_from_u64(1));
+    for _ in 0..100 {
+        a.step(&mut rng_a);
+        a.emit(&mut rng_a);
+        b.step(&mut rng_b);
+        b.spawn(&mut rng_b, SAND);
+    }
+    assert_eq!(a.cells(), b.cells());
+}
+
+#[test]
+fn source_rate_and_spread() {
+    let mut world = SandWorld::new(20, 20);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 10, y : 10, particle : WATER, rate : 40.0, spread : 2 });
+    world.emit(&mut StdRng::seed_from_u64(2));
+    for x in 0..20 {
+        for y in 0..20 {
+            let expected = if y == 10 && (8..=12).contains(&x) { WATER } else { Particle::AIR };
+            assert_eq!(world.get(x, y), expected);
+        }
+    }
+}
+
+#[test]
+fn drain_removes_only_its_material() {
+    let mut world = SandWorld::new(10, 5);
+    for x in 0..10 { world.set(x, 0, if x % 2 == 0 { SAND } else { WATER }); }
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Drain, x : 5, y : 0, particle : WATER, rate : 100.0, spread : 10 });
+    world.emit(&mut StdRng::seed_from_u64(3));
+    assert_eq!(world.count(WATER), 0);
+    assert_eq!(world.count(SAND), 5);
+}
+
+#[test]
+fn emitters_are_saved_with_the_world() {
+    let mut world = SandWorld::new(8, 8);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Drain, x : -3, y : 0, particle : Particle::AIR, rate : 0.25, spread : 4 });
+    let spawner = Emitter::spawner(&world, WATER);
+    world.emitters_mut().push(spawner);
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    let loaded = SandWorld::load(&mut &bytes[..]).unwrap();
+    assert_eq!(loaded.emitters(), world.emitters());
+}
+
+#[test]
+fn huge_rates_affect_each_cell_once() {
+    let mut world = SandWorld::new(50, 5);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 10, y : 4, particle : SAND, rate : f32::INFINITY, spread : 3 });
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 30, y : 4, particle : WATER, rate : f32::NAN, spread : 3 });
+    world.emit(&mut StdRng::seed_from_u64(4));
+    assert_eq!(world.count(SAND), 7);
+    assert_eq!(world.count(WATER), 0);
+}
+
+#[test]
+fn non_finite_rates_are_rejected() {
+    let mut world = SandWorld::new(8, 8);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 3, y : 7, particle : SAND, rate : 1e30, spread : 2 });
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    assert!(SandWorld::load(&mut &bytes[..]).is_ok());
+    let at = bytes.windows(4).position(|w| w == 1e30f32.to_le_bytes()).unwrap();
+    bytes[at..at + 4].copy_from_slice(&f32::INFINITY.to_le_bytes());
+    assert!(matches!(SandWorld::load(&mut &bytes[..]), Err(WorldFileError::Format(_))));
+}
+
+#[test]
+fn spreads_reaching_past_i32_stay_in_the_grid() {
+    let mut world = SandWorld::new(10, 5);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 1, y : 4, particle : SAND, rate : 100.0, spread : i32::MAX });
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : i32::MIN, y : 0, particle : WATER, rate : 100.0, spread : i32::MAX });
+    world.emit(&mut StdRng::seed_from_u64(5));
+    assert_eq!(world.count(SAND), 10);
+    assert_eq!(world.count(WATER), 0);
+}
+
+#[test]
+fn negative_or_huge_spreads_are_rejected() {
+    let mut world = SandWorld::new(8, 8);
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 3, y : 7, particle : SAND, rate : 1.0, spread : 777 });
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    assert!(SandWorld::load(&mut &bytes[..]).is_ok());
+    let at = bytes.windows(4).position(|w| w == 777i32.to_le_bytes()).unwrap();
+    for spread in [-1, i32::MAX] {
+        let mut bytes = bytes.clone();
+        bytes[at..at + 4].copy_from_slice(&spread.to_le_bytes());
+        assert!(matches!(SandWorld::load(&mut &bytes[..]), Err(WorldFileError::Format(_))), "spread {}", spread);
+    }
+}
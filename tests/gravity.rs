@@ -0,0 +1,164 @@
+use glam::Vec2;
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle, GravityWell, Simulation, Command, Replay, WorldFileError};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+const STONE : Particle = Particle(3);
+
+fn run(world : &mut SandWorld, ticks : usize) {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..ticks { world.step(&mut rng); }
+}
+
+fn fill(world : &mut SandWorld, x : std::ops::Range<usize>, y : std::ops::Range<usize>, p : Particle) {
+    for j in y {
+        for i in x.clone() { world.set(i, j, p); }
+    }
+}
+
+// Cells holding `p`
+fn cells_of(world : &SandWorld, p : Particle) -> Vec<(usize, usize)> {
+    (0..world.height()).flat_map(|y| (0..world.width()).map(move |x| (x, y))).filter(|&(x, y)| world.get(x, y) == p).collect()
+}
+
+#[test]
+fn sand_piles_up_against_the_side_gravity_points_to() {
+    let mut world = SandWorld::new(30, 20);
+    let g = world.gravity().length();
+    world.set_gravity(Vec2::new(g, 0.0));
+    fill(&mut world, 2..8, 5..15, SAND);
+    run(&mut world, 300);
+    let sand = cells_of(&world, SAND);
+    assert_eq!(sand.len(), 60);
+    // Every grain rests on another one or on the right wall
+    for &(x, y) in &sand {
+        assert!(x + 1 == world.width() || world.get(x + 1, y) == SAND, "grain at ({}, {}) isn't resting", x, y);
+    }
+}
+
+#[test]
+fn sand_falls_up_with_gravity_reversed() {
+    let mut world = SandWorld::new(20, 30);
+    world.set_gravity(-world.gravity());
+    fill(&mut world, 5..15, 2..5, SAND);
+    run(&mut world, 300);
+    let sand = cells_of(&world, SAND);
+    assert_eq!(sand.len(), 30);
+    assert!(sand.iter().all(|&(_, y)| y >= world.height() - 5));
+    assert!(sand.iter().any(|&(_, y)| y == world.height() - 1));
+}
+
+#[test]
+fn nothing_falls_without_gravity() {
+    let mut world = SandWorld::new(30, 30);
+    world.set_gravity(Vec2::ZERO);
+    fill(&mut world, 5..10, 10..20, SAND);
+    fill(&mut world, 15..20, 10..20, WATER);
+    fill(&mut world, 22..24, 0..30, STONE);
+    let before = world.cells().to_vec();
+    run(&mut world, 100);
+    assert_eq!(world.cells(), &before[..]);
+}
+
+#[test]
+fn wells_add_to_gravity_within_their_radius() {
+    let mut world = SandWorld::new(40, 40);
+    let g = world.gravity();
+    world.wells_mut().push(GravityWell { x : 20, y : 20, radius : 5.0, strength : 0.5 });
+    assert_eq!(world.gravity_at(20, 20), g);
+    assert_eq!(world.gravity_at(30, 20), g);
+    assert_eq!(world.gravity_at(16, 20), g + Vec2::new(0.5, 0.0));
+    assert_eq!(world.gravity_at(20, 24), g + Vec2::new(0.0, -0.5));
+}
+
+#[test]
+fn well_pulls_sand_towards_its_center() {
+    let mut world = SandWorld::new(40, 40);
+    world.set_gravity(Vec2::ZERO);
+    world.wells_mut().push(GravityWell { x : 20, y : 20, radius : 12.0, strength : 0.5 });
+    fill(&mut world, 9..11, 18..23, SAND);
+    fill(&mut world, 29..31, 18..23, SAND);
+    // Out of the well's reach, so left alone
+    fill(&mut world, 0..3, 0..3, WATER);
+    let distance = |world : &SandWorld| {
+        let sand = cells_of(world, SAND);
+        sand.iter().map(|&(x, y)| Vec2::new(x as f32 - 20.0, y as f32 - 20.0).length()).sum::<f32>() / sand.len() as f32
+    };
+    let before = distance(&world);
+    run(&mut world, 200);
+    assert_eq!(cells_of(&world, SAND).len(), 20);
+    assert!(distance(&world) < before / 2.0, "{} vs {}", distance(&world), before);
+    assert_eq!(cells_of(&world, WATER).len(), 9);
+    assert!(cells_of(&world, WATER).iter().all(|&(x, y)| x < 3 && y < 3));
+}
+
+#[test]
+fn gravity_and_wells_are_saved() {
+    let mut world = SandWorld::new(10, 10);
+    world.set_gravity(Vec2::new(0.1, 0.2));
+    world.wells_mut().push(GravityWell { x : 3, y : -4, radius : 6.5, strength : -0.25 });
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    let loaded = SandWorld::load(&mut &bytes[..]).unwrap();
+    assert_eq!(loaded.gravity(), world.gravity());
+    assert_eq!(loaded.wells(), world.wells());
+}
+
+#[test]
+fn gravity_changes_are_replayed() {
+    let mut world = SandWorld::new(30, 30);
+    fill(&mut world, 5..25, 20..25, SAND);
+    let mut recorded = Simulation::recorded(world, 3);
+    let well = GravityWell { x : 15, y : 15, radius : 8.0, strength : 0.4 };
+    for t in 0..120 {
+        match t {
+            10 => recorded.apply(Command::SetGravity(Vec2::new(-0.1, 0.05))),
+            30 => recorded.apply(Command::AddWell(well)),
+            31 => recorded.apply(Command::AddWell(GravityWell { x : 0, y : 0, ..well })),
+            60 => recorded.apply(Command::RemoveWell(0)),
+            _ => ()
+        }
+        recorded.tick();
+    }
+    assert_eq!(recorded.world().wells(), &[GravityWell { x : 0, y : 0, ..well }]);
+    let mut bytes = Vec::new();
+    recorded.replay().unwrap().save(&mut bytes).unwrap();
+    let played = Replay::load(&mut &bytes[..]).unwrap().play();
+    assert_eq!(played.world().gravity(), Vec2::new(-0.1, 0.05));
+    assert_eq!(played.world().wells(), recorded.world().wells());
+    assert_eq!(played.world().cells(), recorded.world().cells());
+}
+
+// Replaces the first occurrence of `from` in `bytes` with `to`
+fn patch(bytes : &mut [u8], from : f32, to : f32) {
+    let at = bytes.windows(4).position(|w| w == from.to_le_bytes()).unwrap();
+    bytes[at..at + 4].copy_from_slice(&to.to_le_bytes());
+}
+
+#[test]
+fn non_finite_gravity_and_wells_are_rejected() {
+    let mut world = SandWorld::new(10, 10);
+    world.set_gravity(Vec2::new(0.125, 0.25));
+    world.wells_mut().push(GravityWell { x : 3, y : 4, radius : 6.5, strength : -0.375 });
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    for (from, to) in [(0.125, f32::NAN), (6.5, f32::INFINITY), (-0.375, f32::NEG_INFINITY)] {
+        let mut bytes = bytes.clone();
+        patch(&mut bytes, from, to);
+        assert!(matches!(SandWorld::load(&mut &bytes[..]), Err(WorldFileError::Format(_))), "{} read as {}", from, to);
+    }
+
+    let mut recorded = Simulation::recorded(SandWorld::new(10, 10), 1);
+    recorded.apply(Command::SetGravity(Vec2::new(0.625, 0.0)));
+    recorded.apply(Command::AddWell(GravityWell { x : 5, y : 5, radius : 2.5, strength : 0.875 }));
+    recorded.tick();
+    let mut bytes = Vec::new();
+    recorded.replay().unwrap().save(&mut bytes).unwrap();
+    assert!(Replay::load(&mut &bytes[..]).is_ok());
+    for (from, to) in [(0.625, f32::INFINITY), (2.5, f32::NAN), (0.875, f32::INFINITY)] {
+        let mut bytes = bytes.clone();
+        patch(&mut bytes, from, to);
+        assert!(matches!(Replay::load(&mut &bytes[..]), Err(WorldFileError::Format(_))), "{} read as {}", from, to);
+    }
+}
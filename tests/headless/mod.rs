@@ -0,0 +1,90 @@
+//! OpenGL context without a window or display, through the surfaceless platform of EGL, which Mesa
+//! provides with its software driver. Lets tests of the GPU code run on machines without a screen.
+
+use std::{ffi::{c_void, CString}, os::raw::c_char, ptr::null_mut, rc::Rc};
+use glium::backend::{Backend, Context};
+use libloading::Library;
+
+const PLATFORM_SURFACELESS_MESA : u32 = 0x31dd;
+const OPENGL_API : u32 = 0x30a2;
+const RENDERABLE_TYPE : i32 = 0x3040;
+const OPENGL_BIT : i32 = 0x0008;
+const SURFACE_TYPE : i32 = 0x3033;
+const PBUFFER_BIT : i32 = 0x0001;
+const CONTEXT_MAJOR_VERSION : i32 = 0x3098;
+const CONTEXT_MINOR_VERSION : i32 = 0x30fb;
+const CONTEXT_OPENGL_PROFILE_MASK : i32 = 0x30fd;
+const CONTEXT_OPENGL_CORE_PROFILE_BIT : i32 = 0x0001;
+const NONE : i32 = 0x3038;
+
+type GetProcAddress = unsafe extern "C" fn(*const c_char) -> *const c_void;
+type GetPlatformDisplay = unsafe extern "C" fn(u32, *mut c_void, *const isize) -> *mut c_void;
+type Initialize = unsafe extern "C" fn(*mut c_void, *mut i32, *mut i32) -> u32;
+type BindApi = unsafe extern "C" fn(u32) -> u32;
+type ChooseConfig = unsafe extern "C" fn(*mut c_void, *const i32, *mut *mut c_void, i32, *mut i32) -> u32;
+type CreateContext = unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void, *const i32) -> *mut c_void;
+type MakeCurrent = unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void, *mut c_void) -> u32;
+type GetCurrentContext = unsafe extern "C" fn() -> *mut c_void;
+
+struct Egl {
+    // Keeps the function pointers below valid
+    _library : Library,
+    display : *mut c_void,
+    context : *mut c_void,
+    get_proc_address : GetProcAddress,
+    make_current : MakeCurrent,
+    get_current_context : GetCurrentContext
+}
+
+unsafe impl Backend for Egl {
+    fn swap_buffers(&self) -> Result<(), glium::SwapBuffersError> { Ok(()) }
+
+    unsafe fn get_proc_address(&self, symbol : &str) -> *const c_void {
+        let symbol = CString::new(symbol).expect("Error converting OpenGL symbol name");
+        (self.get_proc_address)(symbol.as_ptr())
+    }
+
+    // Everything is drawn into textures, so the default framebuffer doesn't matter
+    fn get_framebuffer_dimensions(&self) -> (u32, u32) { (1, 1) }
+
+    fn is_current(&self) -> bool { unsafe { (self.get_current_context)() == self.context } }
+
+    unsafe fn make_current(&self) { (self.make_current)(self.display, null_mut(), null_mut(), self.context); }
+}
+
+/// A current OpenGL 3.3 core context without any surface, or why there is none
+pub fn context() -> Result<Rc<Context>, String> {
+    unsafe {
+        let library = Library::new("libEGL.so.1").map_err(|e| format!("Can't load libEGL: {}", e))?;
+        let symbol = |name : &[u8]| -> Result<*const c_void, String> {
+            library.get::<*const c_void>(name).map(|s| *s).map_err(|e| format!("Missing EGL function: {}", e))
+        };
+        let get_proc_address : GetProcAddress = std::mem::transmute(symbol(b"eglGetProcAddress\0")?);
+        let get_platform_display : GetPlatformDisplay = std::mem::transmute(symbol(b"eglGetPlatformDisplay\0")?);
+        let initialize : Initialize = std::mem::transmute(symbol(b"eglInitialize\0")?);
+        let bind_api : BindApi = std::mem::transmute(symbol(b"eglBindAPI\0")?);
+        let choose_config : ChooseConfig = std::mem::transmute(symbol(b"eglChooseConfig\0")?);
+        let create_context : CreateContext = std::mem::transmute(symbol(b"eglCreateContext\0")?);
+        let make_current : MakeCurrent = std::mem::transmute(symbol(b"eglMakeCurrent\0")?);
+        let get_current_context : GetCurrentContext = std::mem::transmute(symbol(b"eglGetCurrentContext\0")?);
+
+        let display = get_platform_display(PLATFORM_SURFACELESS_MESA, null_mut(), std::ptr::null());
+        if display.is_null() { return Err("EGL has no surfaceless platform".into()); }
+        if initialize(display, null_mut(), null_mut()) == 0 { return Err("Error initializing EGL".into()); }
+        if bind_api(OPENGL_API) == 0 { return Err("EGL doesn't support OpenGL".into()); }
+        let (mut config, mut configs) = (null_mut(), 0);
+        // Configs default to window surfaces, which the surfaceless platform doesn't have
+        let attributes = [RENDERABLE_TYPE, OPENGL_BIT, SURFACE_TYPE, PBUFFER_BIT, NONE];
+        choose_config(display, attributes.as_ptr(), &mut config, 1, &mut configs);
+        if configs == 0 { return Err("No EGL config for OpenGL".into()); }
+        let attributes = [
+            CONTEXT_MAJOR_VERSION, 3, CONTEXT_MINOR_VERSION, 3,
+            CONTEXT_OPENGL_PROFILE_MASK, CONTEXT_OPENGL_CORE_PROFILE_BIT, NONE
+        ];
+        let context = create_context(display, config, null_mut(), attributes.as_ptr());
+        if context.is_null() { return Err("Error creating an OpenGL 3.3 context".into()); }
+        if make_current(display, null_mut(), null_mut(), context) == 0 { return Err("Error making the context current".into()); }
+        let egl = Egl { _library : library, display, context, get_proc_address, make_current, get_current_context };
+        Context::new(egl, true, Default::default()).map_err(|e| format!("Incompatible OpenGL: {:?}", e))
+    }
+}
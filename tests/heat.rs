@@ -0,0 +1,107 @@
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle, Emitter, EmitterKind, CHUNK_SIZE};
+
+fn material(world : &SandWorld, name : &str) -> Particle {
+    world.materials().find(name).unwrap_or_else(|| panic!("Missing built-in material {}", name))
+}
+
+fn run(world : &mut SandWorld, ticks : usize, seed : u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..ticks { world.step(&mut rng); }
+}
+
+#[test]
+fn heat_spreads_and_is_conserved() {
+    let mut world = SandWorld::new(20, 20);
+    let stone = material(&world, "stone");
+    for x in 0..20 {
+        for y in 0..20 { world.set(x, y, stone); }
+    }
+    world.set_temperature(10, 10, 1000.0);
+    let total : f32 = world.temperatures().iter().sum();
+    run(&mut world, 50, 1);
+    assert!(world.temperature(10, 10) < 100.0);
+    assert!(world.temperature(12, 10) > 21.0);
+    let after : f32 = world.temperatures().iter().sum();
+    assert!((after - total).abs() < 1.0);
+}
+
+#[test]
+fn water_freezes_and_boils() {
+    let mut world = SandWorld::new(10, 10);
+    let (water, ice, steam) = (material(&world, "water"), material(&world, "ice"), material(&world, "steam"));
+    for x in 0..10 { world.set(x, 0, water); }
+    world.set_temperature(2, 0, -50.0);
+    world.set_temperature(7, 0, 500.0);
+    run(&mut world, 1, 2);
+    assert_eq!(world.count(ice), 1);
+    assert_eq!(world.count(steam), 1);
+    assert_eq!(world.count(water), 8);
+}
+
+#[test]
+fn ice_freezes_surrounding_water() {
+    let mut world = SandWorld::new(20, 10);
+    let (water, ice) = (material(&world, "water"), material(&world, "ice"));
+    for x in 0..20 {
+        for y in 0..3 { world.set(x, y, if (8..12).contains(&x) { water } else { ice }); }
+    }
+    run(&mut world, 200, 3);
+    assert_eq!(world.count(water), 0);
+}
+
+#[test]
+fn sand_melts_into_glass() {
+    let mut world = SandWorld::new(10, 10);
+    let (sand, glass) = (material(&world, "sand"), material(&world, "glass"));
+    for x in 0..10 { world.set(x, 0, sand); }
+    world.set_temperature(4, 0, 1600.0);
+    run(&mut world, 1, 4);
+    assert_eq!(world.get(4, 0), glass);
+    assert_eq!(world.count(sand), 9);
+}
+
+#[test]
+fn heat_is_independent_of_thread_count() {
+    let mut a = SandWorld::new(64, 200);
+    let lava = material(&a, "lava");
+    for x in 0..64 { a.set(x, 100, lava); }
+    let mut b = a.clone();
+    a.set_threads(1);
+    b.set_threads(4);
+    run(&mut a, 30, 5);
+    run(&mut b, 30, 5);
+    assert_eq!(a.temperatures(), b.temperatures());
+    assert_eq!(a.cells(), b.cells());
+}
+
+#[test]
+fn heat_crosses_chunks_and_is_conserved() {
+    let mut world = SandWorld::new(3 * CHUNK_SIZE, CHUNK_SIZE);
+    let stone = material(&world, "stone");
+    for x in 0..world.width() {
+        for y in 0..world.height() { world.set(x, y, stone); }
+    }
+    world.set_temperature(CHUNK_SIZE - 1, 10, 5000.0);
+    let total : f32 = world.temperatures().iter().sum();
+    run(&mut world, 300, 6);
+    assert!(world.temperature(CHUNK_SIZE + 5, 10) > 21.0);
+    let after : f32 = world.temperatures().iter().sum();
+    assert!((after - total).abs() < 1.0, "{} vs {}", after, total);
+}
+
+#[test]
+fn heat_sleeps_away_from_emitters() {
+    let mut world = SandWorld::new(8 * CHUNK_SIZE, 8 * CHUNK_SIZE);
+    let sand = material(&world, "sand");
+    let top = world.height() as i32 - 1;
+    world.emitters_mut().push(Emitter { kind : EmitterKind::Source, x : 5, y : top, particle : sand, rate : 1.0, spread : 2 });
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..100 {
+        world.step(&mut rng);
+        world.emit(&mut rng);
+    }
+    // Only the column the sand falls through and the pile it lands on are updated
+    assert!(world.awake_heat_chunks() > 0);
+    assert!(world.awake_heat_chunks() <= 2 * 8, "{} chunks awake", world.awake_heat_chunks());
+}
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use glam::Vec2;
+use sand::{SandWorld, Particle, Simulation, Command, History, Emitter, Brush, Replay, MaterialRegistry, Body};
+
+const SAND : Particle = Particle(1);
+const STONE : Particle = Particle(3);
+
+// Applies commands through `history` the way the falling-sand binary does, then ticks
+fn tick(simulation : &mut Simulation, history : &mut History, commands : Vec<Command>) {
+    for command in commands { history.apply(simulation, command); }
+    history.end_tick();
+    simulation.tick();
+}
+
+fn paint(x : i32, particle : Particle) -> Command {
+    Command::Paint { from : (x, 10), to : (x, 10), radius : 1, brush : Brush::Circle, particle }
+}
+
+#[test]
+fn stroke_is_undone_and_redone_as_one_step() {
+    let mut simulation = Simulation::new(SandWorld::new(30, 30), 1);
+    let mut history = History::new(1 << 20);
+    simulation.apply(Command::Pause(true));
+    tick(&mut simulation, &mut history, vec![Command::Pause(true)]);
+    assert_eq!(history.undo_steps(), 0);
+    for x in 5..15 { tick(&mut simulation, &mut history, vec![paint(x, STONE)]); }
+    assert_eq!(history.undo_steps(), 1);
+    let painted = simulation.world().cells().to_vec();
+
+    let undo = history.undo(simulation.world()).unwrap();
+    simulation.apply(undo);
+    assert_eq!(simulation.world().count(STONE), 0);
+    let redo = history.redo(simulation.world()).unwrap();
+    simulation.apply(redo);
+    assert_eq!(simulation.world().cells(), &painted[..]);
+    assert!(history.redo(simulation.world()).is_none());
+}
+
+#[test]
+fn separate_edits_are_separate_steps() {
+    let mut simulation = Simulation::new(SandWorld::new(30, 30), 2);
+    let mut history = History::new(1 << 20);
+    tick(&mut simulation, &mut history, vec![paint(5, STONE)]);
+    tick(&mut simulation, &mut history, vec![]);
+    tick(&mut simulation, &mut history, vec![paint(20, STONE)]);
+    let stone = simulation.world().count(STONE);
+    // Different kinds of edits in one tick are steps of their own
+    let spawner = Emitter::spawner(simulation.world(), SAND);
+    tick(&mut simulation, &mut history, vec![Command::AddEmitter(spawner), Command::Clear]);
+    assert_eq!(history.undo_steps(), 4);
+    for _ in 0..2 {
+        let undo = history.undo(simulation.world()).unwrap();
+        simulation.apply(undo);
+    }
+    assert!(simulation.world().emitters().is_empty());
+    assert_eq!(simulation.world().count(STONE), stone);
+    // A new edit drops what could have been redone
+    tick(&mut simulation, &mut history, vec![Command::Resize { width : 20, height : 20 }]);
+    assert_eq!((history.undo_steps(), history.redo_steps()), (3, 0));
+}
+
+#[test]
+fn history_stays_within_budget() {
+    let mut simulation = Simulation::new(SandWorld::new(64, 64), 3);
+    let mut keyframe = Vec::new();
+    simulation.world().save(&mut keyframe).unwrap();
+    let mut history = History::new(keyframe.len() * 5);
+    for x in 0..20 {
+        tick(&mut simulation, &mut history, vec![paint(x * 3, SAND)]);
+        tick(&mut simulation, &mut history, vec![]);
+    }
+    assert!(history.bytes() <= keyframe.len() * 5);
+    assert!(history.undo_steps() > 0 && history.undo_steps() < 20, "{} steps kept", history.undo_steps());
+    // The newest steps are the ones kept
+    let sand = simulation.world().count(SAND);
+    let undo = history.undo(simulation.world()).unwrap();
+    simulation.apply(undo);
+    assert!(simulation.world().count(SAND) < sand);
+}
+
+#[test]
+fn undo_is_recorded_in_replays() {
+    let mut simulation = Simulation::recorded(SandWorld::new(30, 30), 4);
+    let mut history = History::new(1 << 20);
+    for t in 0..60 {
+        let commands = if (10..20).contains(&t) { vec![paint(t, STONE)] } else { vec![] };
+        tick(&mut simulation, &mut history, commands);
+        if t == 30 {
+            let undo = history.undo(simulation.world()).unwrap();
+            simulation.apply(undo);
+        }
+    }
+    assert_eq!(simulation.world().count(STONE), 0);
+    let mut bytes = Vec::new();
+    simulation.replay().unwrap().save(&mut bytes).unwrap();
+    assert_eq!(Replay::load(&mut &bytes[..]).unwrap().play().world().cells(), simulation.world().cells());
+}
+
+#[test]
+fn unreadable_step_stays_on_its_stack() {
+    let mut simulation = Simulation::new(SandWorld::new(30, 30), 5);
+    let mut history = History::new(1 << 20);
+    simulation.apply(Command::Pause(true));
+    tick(&mut simulation, &mut history, vec![paint(5, STONE)]);
+    tick(&mut simulation, &mut history, vec![]);
+    tick(&mut simulation, &mut history, vec![Command::Resize { width : 20, height : 20 }]);
+    // The keyframe from before resizing holds stone, which these materials lack
+    let materials = Arc::new(MaterialRegistry::parse("[air]\nstate = gas\n[sand]\nstate = powder\n").unwrap());
+    assert!(history.undo(&SandWorld::with_materials(20, 20, materials)).is_none());
+    assert_eq!((history.undo_steps(), history.redo_steps()), (2, 0));
+    let undo = history.undo(simulation.world()).unwrap();
+    simulation.apply(undo);
+    assert_eq!((simulation.world().width(), history.undo_steps(), history.redo_steps()), (30, 1, 1));
+}
+
+#[test]
+fn undo_keeps_what_the_simulation_did_since() {
+    let mut simulation = Simulation::new(SandWorld::new(30, 30), 6);
+    let mut history = History::new(1 << 20);
+    tick(&mut simulation, &mut history, vec![Command::Paste { x : 20, y : 20, stamp : Box::new(sand_block()) }]);
+    for x in 2..8 { tick(&mut simulation, &mut history, vec![paint(x, STONE)]); }
+    for _ in 0..60 { tick(&mut simulation, &mut history, vec![]); }
+    let settled = simulation.world().cells().to_vec();
+    let undo = history.undo(simulation.world()).unwrap();
+    simulation.apply(undo);
+    // Only the painted cells change back; the sand stays where it fell
+    assert_eq!(simulation.world().count(STONE), 0);
+    for (c, (&now, &before)) in simulation.world().cells().iter().zip(&settled).enumerate() {
+        assert!(now == before || before == STONE, "cell {} changed from {:?} to {:?}", c, before, now);
+    }
+    assert_eq!(simulation.world().count(SAND), 16);
+    let redo = history.redo(simulation.world()).unwrap();
+    simulation.apply(redo);
+    assert_eq!(simulation.world().cells(), &settled[..]);
+}
+
+#[test]
+fn edits_keep_only_the_cells_they_change() {
+    let mut world = SandWorld::new(200, 200);
+    for x in 0..200 {
+        for y in 0..200 { world.set(x, y, if (x * 7 + y * 3) % 5 == 0 { STONE } else { Particle::AIR }); }
+    }
+    let mut simulation = Simulation::new(world, 7);
+    let mut history = History::new(1 << 20);
+    simulation.apply(Command::Pause(true));
+    tick(&mut simulation, &mut history, vec![paint(100, SAND)]);
+    assert!(history.bytes() < 1000, "{} bytes for one dot", history.bytes());
+    // Resizing keeps the whole world
+    tick(&mut simulation, &mut history, vec![Command::Resize { width : 100, height : 100 }]);
+    assert!(history.bytes() > 10_000, "{} bytes with a keyframe", history.bytes());
+}
+
+#[test]
+fn added_body_is_undone_and_redone() {
+    let mut simulation = Simulation::new(SandWorld::new(30, 30), 8);
+    let mut history = History::new(1 << 20);
+    simulation.apply(Command::Pause(true));
+    let body = Body::rectangle(Vec2::new(10.0, 10.0), Vec2::new(16.0, 14.0), STONE).unwrap();
+    tick(&mut simulation, &mut history, vec![Command::AddBody(Box::new(body))]);
+    let added = simulation.world().cells().to_vec();
+    assert_eq!(simulation.world().bodies().len(), 1);
+
+    let undo = history.undo(simulation.world()).unwrap();
+    simulation.apply(undo);
+    assert!(simulation.world().bodies().is_empty());
+    assert_eq!(simulation.world().count(STONE), 0);
+    let redo = history.redo(simulation.world()).unwrap();
+    simulation.apply(redo);
+    assert_eq!(simulation.world().bodies().len(), 1);
+    assert_eq!(simulation.world().cells(), &added[..]);
+}
+
+// A 4x4 block of sand
+fn sand_block() -> SandWorld {
+    let mut stamp = SandWorld::new(4, 4);
+    for x in 0..4 {
+        for y in 0..4 { stamp.set(x, y, SAND); }
+    }
+    stamp
+}
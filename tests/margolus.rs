@@ -0,0 +1,106 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sand::{SandWorld, Particle, Simulation};
+
+#[cfg(target_os = "linux")]
+mod headless;
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+const STONE : Particle = Particle(3);
+const SMOKE : Particle = Particle(8);
+
+fn run(world : &mut SandWorld, ticks : usize) {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..ticks { world.step_margolus(&mut rng); }
+}
+
+// A seeded mix of air, sand, water, stone, oil and smoke, with odd dimensions so blocks hang over the edges
+fn random_world(width : usize, height : usize, seed : u64) -> SandWorld {
+    let mut world = SandWorld::new(width, height);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mix = [SAND, SAND, SAND, WATER, WATER, WATER, STONE, SMOKE, Particle(5)];
+    for x in 0..width {
+        for y in 0..height {
+            if rng.gen::<f32>() < 0.5 { world.set(x, y, mix[rng.gen_range(0..mix.len())]); }
+        }
+    }
+    world
+}
+
+#[test]
+fn margolus_sand_falls_and_piles_up() {
+    let mut world = SandWorld::new(41, 31);
+    for x in 15..25 {
+        for y in 20..30 { world.set(x, y, SAND); }
+    }
+    world.set(20, 0, STONE);
+    run(&mut world, 300);
+    assert_eq!(world.count(SAND), 100);
+    assert_eq!(world.get(20, 0), STONE);
+    // Nothing is left hanging in the air, and the pile spread wider than it was dropped
+    for x in 0..41 {
+        for y in 1..31 {
+            if world.get(x, y) == SAND { assert_ne!(world.get(x, y - 1), Particle::AIR, "sand floating at ({}, {})", x, y); }
+        }
+    }
+    assert!((0..41).filter(|&x| world.get(x, 0) == SAND).count() > 10);
+}
+
+#[test]
+fn margolus_water_spreads_and_smoke_rises() {
+    let mut world = SandWorld::new(40, 30);
+    for y in 0..20 {
+        world.set(19, y, WATER);
+        world.set(20, y, WATER);
+    }
+    world.set(5, 0, SMOKE);
+    run(&mut world, 600);
+    assert_eq!(world.count(WATER), 40);
+    assert!((0..40).filter(|&x| world.get(x, 0) == WATER).count() >= 20);
+    assert!((0..40).any(|x| world.get(x, 29) == SMOKE));
+}
+
+#[test]
+fn margolus_runs_as_simulation_stepper() {
+    let world = random_world(33, 21, 2);
+    let mut simulation = Simulation::new(world.clone(), 3);
+    simulation.set_stepper(|world, rng| world.step_margolus(rng));
+    let (mut direct, mut rng) = (world, StdRng::seed_from_u64(3));
+    for _ in 0..50 {
+        simulation.tick();
+        direct.step_margolus(&mut rng);
+    }
+    assert_eq!(simulation.world().cells(), direct.cells());
+    assert_eq!(simulation.world().counts(), random_world(33, 21, 2).counts());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn gpu_matches_cpu_reference() {
+    use sand::gpu::GpuStepper;
+
+    // Machines without EGL's surfaceless platform, e.g. Mesa's software driver, can't run the GPU stepper
+    let facade = match headless::context() {
+        Ok(facade) => facade,
+        Err(e) => {
+            eprintln!("Skipping gpu_matches_cpu_reference: {}", e);
+            return;
+        }
+    };
+
+    let mut cpu = random_world(101, 77, 4);
+    let mut gpu = cpu.clone();
+    let mut stepper = GpuStepper::new(&facade);
+    let (mut cpu_rng, mut gpu_rng) = (StdRng::seed_from_u64(5), StdRng::seed_from_u64(5));
+    for t in 0..200 {
+        // Changing the world in between makes the stepper upload it again
+        if t == 100 {
+            cpu.paint(50, 60, 6, SAND);
+            gpu.paint(50, 60, 6, SAND);
+        }
+        cpu.step_margolus(&mut cpu_rng);
+        stepper.step(&facade, &mut gpu, &mut gpu_rng);
+        assert!(gpu.cells() == cpu.cells(), "backends differ after tick {}", t);
+        assert_eq!(gpu.last_moves(), cpu.last_moves());
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle, MaterialRegistry, State};
+
+const WITH_OIL : &str = "
+[air]
+color = 0 0 0
+state = gas
+
+[water]
+color = 0 0 1
+density = 10
+state = liquid
+moves = 0,-1 | -1,-1 1,-1 | -1,0 1,0
+
+[oil]  # lighter than water, so it should float
+color = 0.3 0.2 0.1
+density = 8
+state = liquid
+moves = 0,-1 | -1,-1 1,-1 | -1,0 1,0
+";
+
+#[test]
+fn builtin_registry_matches_test_indices() {
+    let materials = MaterialRegistry::default();
+    assert_eq!(materials.find("air"), Some(Particle::AIR));
+    assert_eq!(materials.find("sand"), Some(Particle(1)));
+    assert_eq!(materials.find("water"), Some(Particle(2)));
+    assert_eq!(materials[Particle(1)].state, State::Powder);
+    assert_eq!(materials[Particle(1)].moves, vec![vec![(0, -1)], vec![(-1, -1)], vec![(1, -1)]]);
+}
+
+#[test]
+fn parse_reports_line_of_error() {
+    let err = MaterialRegistry::parse("[sand]\nstate = powder\n").unwrap_err();
+    assert_eq!(err.line, 1);
+    let err = MaterialRegistry::parse("[air]\nstate = gas\nviscosity = 3\n").unwrap_err();
+    assert_eq!(err.line, 3);
+    let err = MaterialRegistry::parse("[air]\nstate = gas\n[dust]\nmoves = 0,-2\n").unwrap_err();
+    assert_eq!(err.line, 4);
+    let err = MaterialRegistry::parse("[air]\n[air]\n").unwrap_err();
+    assert_eq!(err.line, 2);
+}
+
+#[test]
+fn new_material_needs_no_code_changes() {
+    let materials = Arc::new(MaterialRegistry::parse(WITH_OIL).unwrap());
+    let (water, oil) = (materials.find("water").unwrap(), materials.find("oil").unwrap());
+    let mut world = SandWorld::with_materials(10, 20, materials);
+    for x in 0..10 {
+        for y in 0..3 { world.set(x, y, oil); }
+        for y in 3..6 { world.set(x, y, water); }
+    }
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..200 { world.step(&mut rng); }
+    assert_eq!(world.count(oil), 30);
+    assert_eq!(world.count(water), 30);
+    for x in 0..10 {
+        for y in 0..3 { assert_eq!(world.get(x, y), water); }
+        for y in 3..6 { assert_eq!(world.get(x, y), oil); }
+    }
+}
+
+#[test]
+fn load_maps_palette_by_name() {
+    let mut world = SandWorld::new(4, 1);
+    world.set(1, 0, Particle(1));
+    world.set(2, 0, Particle(2));
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    let materials = Arc::new(MaterialRegistry::parse("[air]\nstate = gas\n[water]\nstate = liquid\n[sand]\nstate = powder\n").unwrap());
+    let loaded = SandWorld::load_with_materials(&mut &bytes[..], materials.clone()).unwrap();
+    assert_eq!(loaded.get(1, 0), materials.find("sand").unwrap());
+    assert_eq!(loaded.get(2, 0), materials.find("water").unwrap());
+    let missing = Arc::new(MaterialRegistry::parse("[air]\nstate = gas\n[sand]\nstate = powder\n").unwrap());
+    assert!(SandWorld::load_with_materials(&mut &bytes[..], missing).is_err());
+}
+
+#[test]
+fn parse_thermal_properties() {
+    let materials = MaterialRegistry::parse("[air]\nstate = gas\n[ice]\ntemperature = -5\nconductivity = 0.5\nabove = water @ 0\n[water]\nstate = liquid\nbelow = ice @ 0\n").unwrap();
+    let (ice, water) = (materials.find("ice").unwrap(), materials.find("water").unwrap());
+    assert_eq!(materials[ice].temperature, -5.0);
+    assert_eq!(materials[ice].above.map(|c| (c.into, c.at)), Some((water, 0.0)));
+    assert_eq!(materials[water].below.map(|c| c.into), Some(ice));
+    assert_eq!(materials[water].temperature, 20.0);
+    let err = MaterialRegistry::parse("[air]\nconductivity = 2\n").unwrap_err();
+    assert_eq!(err.line, 2);
+    let err = MaterialRegistry::parse("[air]\nabove = steam\n").unwrap_err();
+    assert_eq!(err.line, 2);
+}
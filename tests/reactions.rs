@@ -0,0 +1,73 @@
+use rand::{rngs::StdRng, SeedableRng};
+use sand::{SandWorld, Particle};
+
+fn material(world : &SandWorld, name : &str) -> Particle {
+    world.materials().find(name).unwrap_or_else(|| panic!("Missing built-in material {}", name))
+}
+
+fn run(world : &mut SandWorld, ticks : usize, seed : u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..ticks { world.step(&mut rng); }
+}
+
+#[test]
+fn stone_stays_put_and_holds_sand() {
+    let mut world = SandWorld::new(20, 20);
+    let (stone, sand) = (material(&world, "stone"), material(&world, "sand"));
+    for x in 5..15 { world.set(x, 10, stone); }
+    world.set(10, 19, sand);
+    run(&mut world, 50, 1);
+    for x in 5..15 { assert_eq!(world.get(x, 10), stone); }
+    assert_eq!(world.get(10, 11), sand);
+}
+
+#[test]
+fn smoke_rises_and_clears() {
+    let mut world = SandWorld::new(20, 40);
+    let smoke = material(&world, "smoke");
+    world.paint(10, 2, 2, smoke);
+    run(&mut world, 30, 2);
+    let low = (0..20).flat_map(|x| (0..10).map(move |y| (x, y))).filter(|&(x, y)| world.get(x, y) == smoke).count();
+    assert_eq!(low, 0);
+    run(&mut world, 2000, 3);
+    assert_eq!(world.count(smoke), 0);
+}
+
+#[test]
+fn lava_and_water_make_stone_and_steam() {
+    let mut world = SandWorld::new(20, 20);
+    let (lava, water, stone) = (material(&world, "lava"), material(&world, "water"), material(&world, "stone"));
+    for x in 0..20 {
+        world.set(x, 0, water);
+        world.set(x, 1, lava);
+    }
+    run(&mut world, 5, 4);
+    assert!(world.count(stone) > 0);
+    assert!(world.count(material(&world, "steam")) > 0);
+}
+
+#[test]
+fn fire_burns_through_oil_into_smoke() {
+    let mut world = SandWorld::new(30, 30);
+    let (oil, fire, smoke) = (material(&world, "oil"), material(&world, "fire"), material(&world, "smoke"));
+    for x in 0..30 {
+        for y in 0..5 { world.set(x, y, oil); }
+    }
+    for x in 10..20 { world.set(x, 5, fire); }
+    run(&mut world, 400, 5);
+    assert!(world.count(oil) < 30 * 5 / 2);
+    assert!(world.count(smoke) > 0);
+    run(&mut world, 3000, 6);
+    assert_eq!(world.count(oil), 0);
+    assert_eq!(world.count(fire), 0);
+}
+
+#[test]
+fn fire_spreads_along_wood() {
+    let mut world = SandWorld::new(40, 10);
+    let (wood, fire) = (material(&world, "wood"), material(&world, "fire"));
+    for x in 0..40 { world.set(x, 3, wood); }
+    world.set(0, 4, fire);
+    run(&mut world, 3000, 7);
+    assert!(world.count(wood) < 40);
+}
@@ -0,0 +1,167 @@
+use std::{fs::File, io::BufReader};
+use sand::{SandWorld, Particle, Simulation, Replay, Command, Emitter, Brush};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+
+fn fixture(name : &str) -> BufReader<File> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    BufReader::new(File::open(path).expect("Missing fixture"))
+}
+
+fn world_with_spawner(width : usize, height : usize) -> SandWorld {
+    let mut world = SandWorld::new(width, height);
+    let spawner = Emitter::spawner(&world, SAND);
+    world.emitters_mut().push(spawner);
+    world
+}
+
+// Runs a session with a bit of every kind of command
+fn session(simulation : &mut Simulation) {
+    for t in 0..200 {
+        match t {
+            5 => simulation.apply(Command::Paint { from : (3, 30), to : (30, 30), radius : 1, brush : Brush::Circle, particle : WATER }),
+            40 => {
+                let spawner = simulation.world().emitters()[0];
+                simulation.apply(Command::EditEmitter(0, Emitter { particle : WATER, ..spawner }));
+            }
+            60 => simulation.apply(Command::Pause(true)),
+            70 => simulation.apply(Command::Resize { width : 50, height : 40 }),
+            80 => simulation.apply(Command::Pause(false)),
+            90 => {
+                let stamp = simulation.world().copy_region(0, 0, 20, 10).unwrap().rotated();
+                simulation.apply(Command::Paste { x : 30, y : 25, stamp : Box::new(stamp) });
+            }
+            100 => simulation.apply(Command::Erase { x : -5, y : 0, width : 15, height : 5 }),
+            120 => simulation.apply(Command::Load(Box::new(world_with_spawner(20, 20)))),
+            130 => simulation.apply(Command::Paint { from : (5, 15), to : (5, 15), radius : 2, brush : Brush::Square, particle : SAND }),
+            150 => simulation.apply(Command::Clear),
+            _ => ()
+        }
+        simulation.tick();
+    }
+}
+
+#[test]
+fn replay_reproduces_recorded_run() {
+    let mut recorded = Simulation::recorded(world_with_spawner(40, 40), 7);
+    session(&mut recorded);
+    let mut bytes = Vec::new();
+    recorded.replay().unwrap().save(&mut bytes).unwrap();
+    let replay = Replay::load(&mut &bytes[..]).unwrap();
+    assert_eq!(replay.ticks.len(), 200);
+    let played = replay.play();
+    assert_eq!((played.world().width(), played.world().height()), (20, 20));
+    assert_eq!(played.world().cells(), recorded.world().cells());
+    assert_eq!(played.world().emitters(), recorded.world().emitters());
+}
+
+#[test]
+fn recording_does_not_change_the_run() {
+    let mut recorded = Simulation::recorded(world_with_spawner(40, 40), 8);
+    let mut plain = Simulation::new(world_with_spawner(40, 40), 8);
+    session(&mut recorded);
+    session(&mut plain);
+    assert_eq!(recorded.world().cells(), plain.world().cells());
+    assert!(plain.replay().is_none());
+}
+
+#[test]
+fn paused_simulation_does_not_change() {
+    let mut simulation = Simulation::new(world_with_spawner(10, 10), 9);
+    for _ in 0..5 { simulation.tick(); }
+    simulation.apply(Command::Pause(true));
+    let cells = simulation.world().cells().to_vec();
+    for _ in 0..20 { simulation.tick(); }
+    assert_eq!(simulation.world().cells(), &cells[..]);
+}
+
+#[test]
+fn golden_replay_matches_saved_state() {
+    let replay = Replay::load(&mut fixture("pour.replay")).expect("Invalid replay fixture");
+    let expected = SandWorld::load(&mut fixture("pour.sand")).expect("Invalid world fixture");
+    assert_eq!(replay.play().world().cells(), expected.cells());
+}
+
+#[test]
+fn replay_rejects_bad_files() {
+    assert!(Replay::load(&mut &b"SAND\x01\x00"[..]).is_err());
+    let mut bytes = Vec::new();
+    Simulation::recorded(world_with_spawner(4, 4), 1).replay().unwrap().save(&mut bytes).unwrap();
+    assert!(Replay::load(&mut &bytes[..bytes.len() - 2]).is_err());
+    // Resizing to dimensions too large to allocate
+    let mut resized = Simulation::recorded(world_with_spawner(4, 4), 1);
+    resized.apply(Command::Resize { width : 8, height : 8 });
+    resized.tick();
+    let mut bytes = Vec::new();
+    resized.replay().unwrap().save(&mut bytes).unwrap();
+    let at = bytes.len() - 8;
+    bytes[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(Replay::load(&mut &bytes[..]).is_err());
+}
+
+#[test]
+fn replay_rejects_paint_strokes_it_cannot_draw() {
+    let mut painted = Simulation::recorded(SandWorld::new(10, 10), 1);
+    painted.apply(Command::Paint { from : (1, 2), to : (3, 4), radius : 5, brush : Brush::Circle, particle : SAND });
+    painted.tick();
+    let mut bytes = Vec::new();
+    painted.replay().unwrap().save(&mut bytes).unwrap();
+    assert!(Replay::load(&mut &bytes[..]).is_ok());
+    // The stroke's fields are the last ones written: from, to, radius, material and brush
+    let at = bytes.len() - 22;
+    for (field, value) in [(4, i32::MAX), (4, -1), (0, -100_000), (3, 50_000)] {
+        let mut bytes = bytes.clone();
+        bytes[at + field * 4..at + field * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        assert!(Replay::load(&mut &bytes[..]).is_err(), "field {} set to {}", field, value);
+    }
+}
+
+#[test]
+fn version_1_replays_turn_the_spawner_into_an_emitter() {
+    // Palette and starting world, as in a replay without ticks
+    let mut bytes = Vec::new();
+    Simulation::recorded(SandWorld::new(20, 20), 7).replay().unwrap().save(&mut bytes).unwrap();
+    let mut v1 = b"SRPL".to_vec();
+    v1.extend(1u16.to_le_bytes());
+    v1.extend(7u64.to_le_bytes());
+    v1.push(SAND.index());
+    v1.extend(&bytes[14..bytes.len() - 4]);
+    // A tick setting the spawner to water, one resizing, then ticks without commands
+    v1.extend(40u32.to_le_bytes());
+    v1.extend(1u32.to_le_bytes());
+    v1.extend([3, WATER.index()]);
+    v1.extend(1u32.to_le_bytes());
+    v1.push(2);
+    for v in [30u32, 25] { v1.extend(v.to_le_bytes()); }
+    for _ in 2..40 { v1.extend(0u32.to_le_bytes()); }
+    let replay = Replay::load(&mut &v1[..]).unwrap();
+    assert_eq!(replay.world.emitters(), &[Emitter::spawner(&replay.world, SAND)]);
+
+    let mut expected = Simulation::new(world_with_spawner(20, 20), 7);
+    expected.apply(Command::EditEmitter(0, Emitter::spawner(&SandWorld::new(20, 20), WATER)));
+    expected.tick();
+    expected.apply(Command::Resize { width : 30, height : 25 });
+    expected.apply(Command::EditEmitter(0, Emitter::spawner(&SandWorld::new(30, 25), WATER)));
+    for _ in 1..40 { expected.tick(); }
+    let played = replay.play();
+    assert_eq!(played.world().cells(), expected.world().cells());
+    assert_eq!(played.world().emitters(), expected.world().emitters());
+    assert!(played.world().count(WATER) > 0);
+}
+
+#[test]
+fn step_advances_one_tick_while_paused() {
+    let mut stepped = Simulation::recorded(world_with_spawner(10, 10), 10);
+    let mut running = Simulation::new(world_with_spawner(10, 10), 10);
+    stepped.apply(Command::Pause(true));
+    for _ in 0..3 {
+        stepped.apply(Command::Step);
+        stepped.tick();
+        running.tick();
+        assert_eq!(stepped.world().cells(), running.world().cells());
+    }
+    let mut bytes = Vec::new();
+    stepped.replay().unwrap().save(&mut bytes).unwrap();
+    assert_eq!(Replay::load(&mut &bytes[..]).unwrap().play().world().cells(), stepped.world().cells());
+}
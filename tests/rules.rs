@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use sand::{SandWorld, Particle, MaterialRegistry, Rule, Proposal, Neighborhood, Life, LangtonsAnt, Simulation, Command};
+
+const SAND : Particle = Particle(1);
+
+fn rules_world(width : usize, height : usize) -> SandWorld {
+    let materials = MaterialRegistry::parse(include_str!("../assets/rules.txt")).expect("Invalid rule materials");
+    SandWorld::with_materials(width, height, Arc::new(materials))
+}
+
+fn life_world(width : usize, height : usize) -> (SandWorld, Particle) {
+    let mut world = rules_world(width, height);
+    let alive = world.materials().find("life").unwrap();
+    let life : Arc<dyn Rule> = Arc::new(Life { alive });
+    world.set_rule(alive, life.clone());
+    world.set_rule(Particle::AIR, life);
+    (world, alive)
+}
+
+fn living(world : &SandWorld, alive : Particle) -> Vec<(usize, usize)> {
+    (0..world.height()).flat_map(|y| (0..world.width()).map(move |x| (x, y))).filter(|&(x, y)| world.get(x, y) == alive).collect()
+}
+
+#[test]
+fn life_blinker_oscillates() {
+    let (mut world, alive) = life_world(10, 10);
+    for x in 3..6 { world.set(x, 5, alive); }
+    let mut rng = StdRng::seed_from_u64(1);
+    world.step(&mut rng);
+    assert_eq!(living(&world, alive), vec![(4, 4), (4, 5), (4, 6)]);
+    world.step_serial(&mut rng);
+    assert_eq!(living(&world, alive), vec![(3, 5), (4, 5), (5, 5)]);
+}
+
+#[test]
+fn life_glider_crosses_chunks() {
+    let (mut world, alive) = life_world(64, 64);
+    let glider = [(1, 2), (2, 1), (0, 0), (1, 0), (2, 0)];
+    for (x, y) in glider { world.set(26 + x, 40 + y, alive); }
+    let mut rng = StdRng::seed_from_u64(2);
+    for _ in 0..32 { world.step(&mut rng); }
+    let mut expected : Vec<(usize, usize)> = glider.iter().map(|&(x, y)| (34 + x, 32 + y)).collect();
+    expected.sort_by_key(|&(x, y)| (y, x));
+    assert_eq!(living(&world, alive), expected);
+}
+
+#[test]
+fn life_in_parallel_matches_serial() {
+    let (mut world, alive) = life_world(80, 70);
+    let mut rng = StdRng::seed_from_u64(3);
+    for x in 0..80 {
+        for y in 0..70 {
+            if rng.gen::<f32>() < 0.3 { world.set(x, y, alive); }
+        }
+    }
+    let mut serial = world.clone();
+    world.set_threads(4);
+    for _ in 0..40 {
+        world.step(&mut rng);
+        serial.step_serial(&mut rng);
+    }
+    assert_eq!(world.cells(), serial.cells());
+}
+
+#[test]
+fn langtons_ant_walks_a_square() {
+    let mut world = rules_world(20, 20);
+    let ant = LangtonsAnt::from_materials(world.materials()).unwrap();
+    let (black, north, north_on_black, west) = (ant.black, ant.ants[0][0], ant.ants[1][0], ant.ants[0][3]);
+    let particles = ant.ants.concat();
+    let ant : Arc<dyn Rule> = Arc::new(ant);
+    for p in particles { world.set_rule(p, ant.clone()); }
+    world.set(10, 10, north);
+    let mut rng = StdRng::seed_from_u64(4);
+    // Right, right, right, right: back where it started, on the first cell it flipped
+    for _ in 0..4 { world.step(&mut rng); }
+    assert_eq!(world.get(10, 10), north_on_black);
+    for (x, y) in [(11, 10), (11, 9), (10, 9)] { assert_eq!(world.get(x, y), black); }
+    assert_eq!(world.count(black), 3);
+    // On black it turns left instead, and leaves the cell white again
+    world.step(&mut rng);
+    assert_eq!(world.get(10, 10), Particle::AIR);
+    assert_eq!(world.get(9, 10), west);
+}
+
+// Moves every particle straight up
+struct Rise(i32);
+
+impl Rule for Rise {
+    fn update(&self, cell : &Neighborhood, _ : &mut dyn RngCore) -> Option<Proposal> {
+        (cell.get(0, self.0) == Some(Particle::AIR)).then_some(Proposal::Move(0, self.0))
+    }
+}
+
+#[test]
+fn custom_rule_replaces_built_in_moves() {
+    let mut world = SandWorld::new(20, 20);
+    world.paint(10, 5, 3, SAND);
+    let sand = world.count(SAND);
+    world.set_rule(SAND, Arc::new(Rise(1)));
+    let mut simulation = Simulation::new(world, 5);
+    for _ in 0..30 { simulation.tick(); }
+    let world = simulation.world();
+    assert_eq!(world.count(SAND), sand);
+    assert!((0..20).all(|x| world.get(x, 0) == Particle::AIR));
+    assert!((0..20).any(|x| world.get(x, 19) == SAND));
+
+    // Offsets beyond the neighbors are ignored, and loaded worlds without rules keep the current ones
+    let mut world = SandWorld::new(20, 20);
+    world.set(3, 3, SAND);
+    world.set_rule(SAND, Arc::new(Rise(2)));
+    simulation.apply(Command::Load(Box::new(world.clone())));
+    for _ in 0..5 { simulation.tick(); }
+    assert_eq!(simulation.world().get(3, 3), SAND);
+    let mut plain = SandWorld::new(20, 20);
+    plain.set(3, 3, SAND);
+    simulation.apply(Command::Load(Box::new(plain)));
+    for _ in 0..5 { simulation.tick(); }
+    assert_eq!(simulation.world().get(3, 3), SAND);
+
+    // Without the rule, sand falls again
+    world.remove_rule(SAND);
+    assert!(world.rule(SAND).is_none());
+    let mut rng = StdRng::seed_from_u64(6);
+    for _ in 0..10 { world.step(&mut rng); }
+    assert_eq!(world.get(3, 0), SAND);
+}
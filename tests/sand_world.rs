@@ -0,0 +1,240 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sand::{SandWorld, Particle, Brush};
+
+// Indices of the built-in materials
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+
+fn random_world(rng : &mut StdRng) -> SandWorld {
+    let mut world = SandWorld::new(100, 100);
+    for x in 0..world.width() {
+        for y in 0..world.height() {
+            let p = match rng.gen_range(0..4) {
+                0 => SAND,
+                1 => WATER,
+                _ => Particle::AIR
+            };
+            world.set(x, y, p);
+        }
+    }
+    world
+}
+
+fn cells(world : &SandWorld) -> Vec<Particle> {
+    (0..world.width()).flat_map(|x| (0..world.height()).map(move |y| (x, y))).map(|(x, y)| world.get(x, y)).collect()
+}
+
+#[test]
+fn step_conserves_particles() {
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut world = random_world(&mut rng);
+    let sand = world.count(SAND);
+    let water = world.count(WATER);
+    for _ in 0..200 {
+        world.step(&mut rng);
+        assert_eq!(world.count(SAND), sand);
+        assert_eq!(world.count(WATER), water);
+    }
+}
+
+#[test]
+fn step_is_deterministic_for_seed() {
+    let mut a = random_world(&mut StdRng::seed_from_u64(2));
+    let mut b = random_world(&mut StdRng::seed_from_u64(2));
+    let mut rng_a = StdRng::seed_from_u64(3);
+    let mut rng_b = StdRng::seed_from_u64(3);
+    for _ in 0..50 {
+        a.step(&mut rng_a);
+        b.step(&mut rng_b);
+    }
+    assert!(cells(&a) == cells(&b));
+}
+
+#[test]
+fn sand_falls_to_floor() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut world = SandWorld::new(100, 100);
+    world.set(10, world.height() - 1, SAND);
+    // Accelerating, it takes far fewer ticks than the height to land, possibly scattering sideways
+    for _ in 0..40 { world.step(&mut rng); }
+    assert_eq!((0..world.width()).filter(|&x| world.get(x, 0) == SAND).count(), 1);
+    assert_eq!(world.count(SAND), 1);
+}
+
+#[test]
+fn dropped_sand_scatters_wider_than_poured_sand() {
+    // A 4x4 block of sand either resting on the floor or dropped from near the top
+    let floor_width = |drop_height : usize| {
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut world = SandWorld::new(200, 100);
+        for x in 98..102 {
+            for y in drop_height..(drop_height + 4) { world.set(x, y, SAND); }
+        }
+        for _ in 0..300 { world.step(&mut rng); }
+        (0..world.width()).filter(|&x| world.get(x, 0) == SAND).count()
+    };
+    assert!(floor_width(90) > floor_width(0));
+}
+
+#[test]
+fn sand_column_topples_to_the_left() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut world = SandWorld::new(100, 100);
+    // Sand tries the left diagonal first, so a column of 4 grains on the floor ends up as a row
+    for y in 0..4 { world.set(50, y, SAND); }
+    for _ in 0..20 { world.step(&mut rng); }
+    for x in 47..=50 { assert_eq!(world.get(x, 0), SAND); }
+    assert_eq!(world.count(SAND), 4);
+}
+
+#[test]
+fn water_spreads_flat() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let mut world = SandWorld::new(100, 100);
+    for y in 0..10 { world.set(50, y, WATER); }
+    for _ in 0..200 { world.step(&mut rng); }
+    for x in 0..world.width() {
+        for y in 1..world.height() { assert_eq!(world.get(x, y), Particle::AIR); }
+    }
+    assert_eq!(world.count(WATER), 10);
+}
+
+#[test]
+fn sand_sinks_below_water() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut world = SandWorld::new(100, 100);
+    for x in 0..world.width() {
+        world.set(x, 0, WATER);
+        world.set(x, 1, SAND);
+    }
+    for _ in 0..50 { world.step(&mut rng); }
+    for x in 0..world.width() {
+        assert_eq!(world.get(x, 0), SAND);
+        assert_eq!(world.get(x, 1), WATER);
+    }
+}
+
+#[test]
+fn paint_fills_circle_clipped_to_grid() {
+    let mut world = SandWorld::new(100, 100);
+    world.paint(50, 50, 2, SAND);
+    assert_eq!(world.count(SAND), 13);
+    world.paint(0, 0, 2, WATER);
+    assert_eq!(world.count(WATER), 6);
+    world.paint(50, 50, 2, Particle::AIR);
+    assert_eq!(world.count(SAND), 0);
+}
+
+#[test]
+fn square_brush_line() {
+    let mut world = SandWorld::new(100, 100);
+    world.paint_brush(50, 50, 2, Brush::Square, SAND);
+    assert_eq!(world.count(SAND), 25);
+    world.paint_line((10, 10), (20, 10), 1, Brush::Square, WATER);
+    assert_eq!(world.count(WATER), 13 * 3);
+}
+
+#[test]
+fn painting_far_outside_the_grid_does_not_overflow() {
+    let mut world = SandWorld::new(20, 20);
+    world.paint_brush(i32::MAX, 5, i32::MAX, Brush::Square, SAND);
+    assert_eq!(world.count(SAND), 400);
+    world.paint_line((-50_000, 5), (5, 5), 0, Brush::Circle, WATER);
+    assert_eq!(world.count(WATER), 6);
+}
+
+#[test]
+fn resize_keeps_bottom_left_cells() {
+    let mut world = SandWorld::new(20, 10);
+    world.set(0, 0, SAND);
+    world.set(5, 5, WATER);
+    world.set(19, 9, SAND);
+    world.resize(8, 30);
+    assert_eq!((world.width(), world.height()), (8, 30));
+    assert_eq!(world.get(0, 0), SAND);
+    assert_eq!(world.get(5, 5), WATER);
+    assert_eq!(world.count(SAND), 1);
+}
+
+#[test]
+fn non_square_world_settles_on_floor() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let mut world = SandWorld::new(7, 300);
+    for _ in 0..20 { world.spawn(&mut rng, SAND); world.step(&mut rng); }
+    for _ in 0..400 { world.step(&mut rng); }
+    assert_eq!(world.count(SAND), 20);
+    for x in 0..7 { assert_eq!(world.get(x, 0), SAND); }
+}
+
+#[test]
+fn thread_count_does_not_change_result() {
+    let mut a = random_world(&mut StdRng::seed_from_u64(9));
+    let mut b = random_world(&mut StdRng::seed_from_u64(9));
+    a.set_threads(1);
+    b.set_threads(4);
+    let mut rng_a = StdRng::seed_from_u64(10);
+    let mut rng_b = StdRng::seed_from_u64(10);
+    for _ in 0..100 {
+        a.step(&mut rng_a);
+        b.step(&mut rng_b);
+    }
+    assert!(cells(&a) == cells(&b));
+}
+
+#[test]
+fn serial_step_conserves_particles() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let mut world = random_world(&mut rng);
+    let sand = world.count(SAND);
+    let water = world.count(WATER);
+    for _ in 0..200 { world.step_serial(&mut rng); }
+    assert_eq!(world.count(SAND), sand);
+    assert_eq!(world.count(WATER), water);
+}
+
+#[test]
+fn settled_chunks_sleep_until_painted() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let mut world = SandWorld::new(128, 128);
+    for x in 0..world.width() {
+        for y in 0..8 { world.set(x, y, SAND); }
+    }
+    for _ in 0..3 { world.step(&mut rng); }
+    assert_eq!(world.awake_chunks(), 0);
+    world.paint(100, 100, 2, SAND);
+    assert!(world.awake_chunks() > 0 && world.awake_chunks() <= 4);
+    for _ in 0..world.height() { world.step(&mut rng); }
+    assert_eq!(world.count(SAND), world.width() * 8 + 13);
+    assert_eq!(world.awake_chunks(), 0);
+}
+
+#[test]
+fn counts_and_moves_per_tick() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let mut world = SandWorld::new(10, 10);
+    world.set(5, 9, SAND);
+    world.set(0, 0, SAND);
+    assert_eq!(world.counts()[..3], [98, 2, 0]);
+    world.step(&mut rng);
+    assert_eq!(world.last_moves(), 1);
+    for _ in 0..20 { world.step(&mut rng); }
+    assert_eq!(world.last_moves(), 0);
+    assert_eq!(world.counts().iter().sum::<usize>(), 100);
+}
+
+#[test]
+fn shades_vary_and_follow_particles() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let mut world = SandWorld::new(10, 10);
+    world.paint_brush(5, 5, 1, Brush::Square, SAND);
+    let painted : Vec<u8> = (4..7).map(|x| world.shades()[5 * 10 + x]).collect();
+    assert!(painted[0] != painted[1] && painted[1] != painted[2], "{:?}", painted);
+
+    let mut world = SandWorld::new(10, 10);
+    world.set(5, 9, SAND);
+    let shade = world.shades()[9 * 10 + 5];
+    for _ in 0..20 { world.step(&mut rng); }
+    let landed = world.cells().iter().position(|&p| p == SAND).unwrap();
+    assert_eq!(landed, 5);
+    assert_eq!(world.shades()[landed], shade);
+}
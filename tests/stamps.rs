@@ -0,0 +1,96 @@
+use sand::{SandWorld, Particle, StampLibrary};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+const STONE : Particle = Particle(3);
+
+// An L of stone with a grain of sand on the end of its foot:
+// S . .
+// S . .
+// S S W
+fn ell() -> SandWorld {
+    let mut world = SandWorld::new(3, 3);
+    for (x, y) in [(0, 0), (1, 0), (0, 1), (0, 2)] { world.set(x, y, STONE); }
+    world.set(2, 0, WATER);
+    world
+}
+
+fn rows(world : &SandWorld) -> Vec<Vec<u8>> {
+    (0..world.height()).rev().map(|y| (0..world.width()).map(|x| world.get(x, y).index()).collect()).collect()
+}
+
+#[test]
+fn copy_clips_to_the_grid() {
+    let mut world = SandWorld::new(10, 10);
+    world.paste(&ell(), 0, 0);
+    let copy = world.copy_region(-1, -1, 3, 4).unwrap();
+    assert_eq!((copy.width(), copy.height()), (2, 3));
+    assert_eq!(rows(&copy), vec![vec![3, 0], vec![3, 0], vec![3, 3]]);
+    assert!(world.copy_region(10, 0, 5, 5).is_none());
+    assert!(world.copy_region(-5, 0, 5, 5).is_none());
+}
+
+#[test]
+fn rotate_and_flip() {
+    let stamp = ell();
+    assert_eq!(rows(&stamp.rotated()), vec![vec![0, 0, 2], vec![0, 0, 3], vec![3, 3, 3]]);
+    assert_eq!(rows(&stamp.flipped()), vec![vec![0, 0, 3], vec![0, 0, 3], vec![2, 3, 3]]);
+    let turned = stamp.rotated().rotated().rotated().rotated();
+    assert_eq!(turned.cells(), stamp.cells());
+    assert_eq!(stamp.flipped().flipped().cells(), stamp.cells());
+}
+
+#[test]
+fn paste_keeps_cells_under_air() {
+    let mut world = SandWorld::new(10, 10);
+    world.paint(5, 5, 3, SAND);
+    let sand = world.count(SAND);
+    world.paste(&ell(), 6, 4);
+    // The air of the L keeps the sand it covers
+    assert_eq!((world.get(6, 4), world.get(7, 4), world.get(6, 6), world.get(8, 4)), (STONE, STONE, STONE, WATER));
+    assert_eq!(world.get(7, 5), SAND);
+    assert_eq!(world.count(SAND), sand - 4);
+    // Cells past the edge are left out
+    world.paste(&ell(), 8, 0);
+    assert_eq!((world.get(9, 0), world.get(8, 2)), (STONE, STONE));
+    assert_eq!((world.count(STONE), world.count(WATER)), (8, 1));
+
+    world.erase_region(-2, 3, 20, 3);
+    assert!((0..10).all(|x| (3..6).all(|y| world.get(x, y) == Particle::AIR)));
+    assert_eq!((world.get(6, 6), world.get(5, 7)), (STONE, SAND));
+}
+
+#[test]
+fn library_keeps_stamps_on_disk() {
+    let dir = std::env::temp_dir().join(format!("sand-stamps-{}", std::process::id()));
+    let materials = SandWorld::new(1, 1).materials().clone();
+    let mut library = StampLibrary::open(&dir, materials.clone()).unwrap();
+    assert!(library.stamps().is_empty());
+    library.save("funnel", &ell()).unwrap();
+    library.save("container", &ell().flipped()).unwrap();
+    library.save("funnel", &ell().rotated()).unwrap();
+    assert!(library.save("../escape", &ell()).is_err());
+    assert!(library.save("", &ell()).is_err());
+
+    let reopened = StampLibrary::open(&dir, materials.clone()).unwrap();
+    let names : Vec<&str> = reopened.stamps().iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["container", "funnel"]);
+    assert_eq!(reopened.stamps()[1].1.cells(), ell().rotated().cells());
+
+    library.remove(0).unwrap();
+    assert_eq!(StampLibrary::open(&dir, materials).unwrap().stamps().len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn failed_writes_are_reported() {
+    let dir = std::env::temp_dir().join(format!("sand-stamps-full-{}", std::process::id()));
+    let mut library = StampLibrary::open(&dir, SandWorld::new(1, 1).materials().clone()).unwrap();
+    // Every write to /dev/full fails, here only once the buffered file is flushed
+    std::fs::create_dir_all(&dir).unwrap();
+    std::os::unix::fs::symlink("/dev/full", dir.join("full.sand")).unwrap();
+    assert!(library.save("full", &ell()).is_err());
+    assert!(library.stamps().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,102 @@
+use std::{fs::File, io::BufReader};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use sand::{SandWorld, Particle, WorldFileError};
+
+const SAND : Particle = Particle(1);
+const WATER : Particle = Particle(2);
+
+fn random_world(width : usize, height : usize, seed : u64) -> SandWorld {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut world = SandWorld::new(width, height);
+    for x in 0..width {
+        for y in 0..height { world.set(x, y, Particle(rng.gen_range(0..3))); }
+    }
+    world
+}
+
+fn load_fixture(name : &str) -> SandWorld {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    SandWorld::import_png(BufReader::new(File::open(path).expect("Missing fixture"))).expect("Invalid fixture")
+}
+
+#[test]
+fn sand_file_round_trips() {
+    let world = random_world(37, 21, 1);
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    let loaded = SandWorld::load(&mut &bytes[..]).unwrap();
+    assert_eq!((loaded.width(), loaded.height()), (37, 21));
+    assert_eq!(loaded.cells(), world.cells());
+}
+
+#[test]
+fn sand_file_compresses_runs() {
+    let mut world = SandWorld::new(200, 200);
+    world.paint(100, 0, 50, SAND);
+    let mut bytes = Vec::new();
+    world.save(&mut bytes).unwrap();
+    assert!(bytes.len() < 200 * 200 / 10);
+}
+
+#[test]
+fn png_round_trips() {
+    let world = random_world(13, 29, 2);
+    let mut bytes = Vec::new();
+    world.export_png(&mut bytes).unwrap();
+    let loaded = SandWorld::import_png(&bytes[..]).unwrap();
+    assert_eq!((loaded.width(), loaded.height()), (13, 29));
+    assert_eq!(loaded.cells(), world.cells());
+}
+
+#[test]
+fn png_import_rejects_huge_headers() {
+    // Headers larger than MAX_SIZE, though small enough for the decoder's own limits, and a bit of image data
+    for (width, height) in [(5000, 8), (8, 5000)] {
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_chunk(png::chunk::IDAT, &[0x78, 0x9c, 0x03, 0x00]).unwrap();
+        drop(writer);
+        assert!(matches!(SandWorld::import_png(&bytes[..]), Err(WorldFileError::Format(_))), "{}x{}", width, height);
+    }
+}
+
+#[test]
+fn load_rejects_bad_files() {
+    assert!(matches!(SandWorld::load(&mut &b"NOPE\x01\x00"[..]), Err(WorldFileError::Format(_))));
+    let mut bytes = Vec::new();
+    random_world(8, 8, 3).save(&mut bytes).unwrap();
+    // Unsupported version
+    let mut versioned = bytes.clone();
+    versioned[4] = 0xff;
+    assert!(matches!(SandWorld::load(&mut &versioned[..]), Err(WorldFileError::Format(_))));
+    // Dimensions too large to allocate
+    for (width, height) in [(u32::MAX, u32::MAX), (5000, 8), (8, 5000)] {
+        let mut huge = bytes.clone();
+        huge[6..10].copy_from_slice(&width.to_le_bytes());
+        huge[10..14].copy_from_slice(&height.to_le_bytes());
+        assert!(matches!(SandWorld::load(&mut &huge[..]), Err(WorldFileError::Format(_))));
+    }
+    // Truncated cell data
+    bytes.truncate(bytes.len() - 3);
+    assert!(matches!(SandWorld::load(&mut &bytes[..]), Err(WorldFileError::Io(_))));
+}
+
+#[test]
+fn fixture_sand_sinks_through_water() {
+    let mut world = load_fixture("sand-over-water.png");
+    let sand = world.count(SAND);
+    let water = world.count(WATER);
+    assert_eq!((sand, water), (300, 320));
+    let mut rng = StdRng::seed_from_u64(4);
+    for _ in 0..1000 { world.step(&mut rng); }
+    assert_eq!(world.count(SAND), sand);
+    assert_eq!(world.count(WATER), water);
+    for x in 0..world.width() {
+        assert_eq!(world.get(x, 0), SAND);
+        for y in 1..world.height() {
+            if world.get(x, y) == SAND { assert_eq!(world.get(x, y - 1), SAND); }
+        }
+    }
+}